@@ -1,18 +1,25 @@
+#![allow(clippy::too_many_arguments)]
+
 use anchor_lang::prelude::*;
-use anchor_spl::token::{transfer, Transfer as SplTransfer};
+use anchor_lang::Discriminator;
+use anchor_spl::token_interface::{burn, mint_to, transfer_checked, Burn, MintTo, TransferChecked};
 use chainlink_solana as chainlink;
 
 pub mod state;
 use state::*;
 
 declare_id!("6zYPKjtGyPSZq6pP2U9ahNZAnaTtoVK9f1BMkEL2cix5");
-const LEVERAGE_DECIMALS: u128 = 10u128.pow(4);
-const MARKET_WEIGHT_DECIMALS: u128 = 10u128.pow(4);
-const FEE_DECIMALS: u128 = 10u128.pow(4);
+const LEVERAGE_NUM_DECIMALS: u32 = 4;
+const MARKET_WEIGHT_NUM_DECIMALS: u32 = 4;
+const MARGIN_NUM_DECIMALS: u32 = 4;
+const FEE_NUM_DECIMALS: u32 = 4;
+const TREASURY_WEIGHT_NUM_DECIMALS: u32 = 4;
+const FEE_DECIMALS: u128 = 10u128.pow(FEE_NUM_DECIMALS);
+const FUNDING_RATE_NUM_DECIMALS: u32 = 9;
+const REWARD_INDEX_NUM_DECIMALS: u32 = 18;
+const MAX_ORACLE_CONFIDENCE: u128 = 200; // 2% of price in FEE_DECIMALS
 const AMOUNT_NUM_DECIMALS: u8 = 9;
-const ONE_YEAR: u64 = 365 * 24 * 60 * 60;
-// const ONE_YEAR: u64 = 1 * 60 * 60; // one hour for testing
-const AMOUNT_DECIMALS: u128 = 10u128.pow(AMOUNT_NUM_DECIMALS as u32);
+const VOLUME_WINDOW: i64 = 30 * 24 * 60 * 60;
 
 #[program]
 pub mod krunch {
@@ -26,6 +33,12 @@ pub mod krunch {
         test_mode: bool,
         market_weight: u16,
         chainlink_program: Pubkey,
+        pyth_program: Pubkey,
+        liquidation_fee: u16,
+        liquidator_share: u16,
+        insurance_fee_share: u16,
+        referrer_fee_share: u16,
+        referee_fee_discount: u16,
     ) -> Result<()> {
         let exchange = &mut ctx.accounts.exchange;
         exchange.admin = ctx.accounts.admin.key.to_owned();
@@ -37,12 +50,79 @@ pub mod krunch {
         exchange.fees = 0;
         exchange.rebates = 0;
         exchange.rewards = 0;
+        exchange.reward_epoch = 0;
+        exchange.reward_epoch_start = Clock::get()?.unix_timestamp;
+        exchange.reward_epoch_pool = 0;
+        exchange.reward_epoch_distributed = 0;
+        exchange.reward_index = 0;
+        exchange.last_reward_update = exchange.reward_epoch_start;
         exchange.leverage = leverage;
         exchange.collateral_value = 0;
+        exchange.house_collateral_value = 0;
+        exchange.lp_collateral_value = 0;
+        exchange.settled_pnl = 0;
+        exchange.insurance_fees_pending = 0;
+        exchange.insurance_fund_value = 0;
+        exchange.socialized_loss = 0;
         exchange.reward_frequency = reward_frequency;
         exchange.reward_rate = reward_rate;
         exchange.test_mode = test_mode;
         exchange.chainlink_program = chainlink_program;
+        exchange.pyth_program = pyth_program;
+        exchange.liquidation_fee = liquidation_fee;
+        exchange.liquidator_share = liquidator_share;
+        exchange.insurance_fee_share = insurance_fee_share;
+        exchange.referrer_fee_share = referrer_fee_share;
+        exchange.referee_fee_discount = referee_fee_discount;
+        exchange.risk_admin = exchange.admin;
+        exchange.oracle_admin = exchange.admin;
+        exchange.fee_admin = exchange.admin;
+        exchange.pause_guardian = exchange.admin;
+        exchange.paused = false;
+        exchange.insurance_claims_pending = 0;
+        Ok(())
+    }
+
+    pub fn propose_admin(ctx: Context<ExchangeAdmin>, pending_admin: Pubkey) -> Result<()> {
+        let exchange = &mut ctx.accounts.exchange;
+        exchange.pending_admin = pending_admin;
+        Ok(())
+    }
+
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let exchange = &mut ctx.accounts.exchange;
+        exchange.admin = exchange.pending_admin;
+        exchange.pending_admin = Pubkey::default();
+        Ok(())
+    }
+
+    pub fn update_roles(
+        ctx: Context<ExchangeAdmin>,
+        risk_admin: Pubkey,
+        oracle_admin: Pubkey,
+        fee_admin: Pubkey,
+        pause_guardian: Pubkey,
+    ) -> Result<()> {
+        let exchange = &mut ctx.accounts.exchange;
+        exchange.risk_admin = risk_admin;
+        exchange.oracle_admin = oracle_admin;
+        exchange.fee_admin = fee_admin;
+        exchange.pause_guardian = pause_guardian;
+        Ok(())
+    }
+
+    pub fn set_test_mode(ctx: Context<ExchangeAdmin>, test_mode: bool) -> Result<()> {
+        let exchange = &mut ctx.accounts.exchange;
+        exchange.test_mode = test_mode;
+        Ok(())
+    }
+
+    // pausing stops trading, liquidations, withdrawals, pnl and yield settlement,
+    // lp flows, insurance transfers and rewards. deposits, cancelling orders and
+    // closing flat positions stay open since they only reduce risk
+    pub fn set_paused(ctx: Context<SetPaused>, paused: bool) -> Result<()> {
+        let exchange = &mut ctx.accounts.exchange;
+        exchange.paused = paused;
         Ok(())
     }
 
@@ -50,168 +130,942 @@ pub mod krunch {
         let user_account = &mut ctx.accounts.user_account;
         user_account.owner = ctx.accounts.owner.key.to_owned();
         user_account.collateral_value = 0;
+        if let Some(referrer) = &mut ctx.accounts.referrer {
+            user_account.referrer = referrer.owner;
+            referrer.referee_count = referrer
+                .referee_count
+                .checked_add(1)
+                .ok_or(KrunchErrors::MathOverflow)?;
+        }
+        Ok(())
+    }
+
+    pub fn create_referrer(ctx: Context<CreateReferrer>) -> Result<()> {
+        let referrer = &mut ctx.accounts.referrer;
+        referrer.owner = ctx.accounts.owner.key();
+        Ok(())
+    }
+
+    pub fn claim_referral_fees(ctx: Context<ClaimReferralFees>) -> Result<()> {
+        let referrer = &mut ctx.accounts.referrer;
+        if referrer.referral_fees <= 0 {
+            return err!(KrunchErrors::NoReferralFeesAvailable);
+        }
+        // referral fees settle like rebates
+        let user_account = &mut ctx.accounts.user_account;
+        checked_add_assign(&mut user_account.rebates, referrer.referral_fees)?;
+        referrer.referral_fees = 0;
         Ok(())
     }
 
     pub fn update_market(
         ctx: Context<UpdateMarket>,
         _market_index: u16,
+        initial_margin: u16,
+        maintenance_margin: u16,
+        market_weight: u16,
+        funding_period: i64,
+        max_funding_rate: i64,
+        max_long_open_interest: i64,
+        max_short_open_interest: i64,
+        max_position_size: i64,
+    ) -> Result<()> {
+        if maintenance_margin > initial_margin {
+            return err!(KrunchErrors::InvalidMarginRequirement);
+        }
+        if funding_period <= 0 {
+            return err!(KrunchErrors::InvalidFundingPeriod);
+        }
+        if max_funding_rate < 0 {
+            return err!(KrunchErrors::InvalidMaxFundingRate);
+        }
+        let market = &mut ctx.accounts.market;
+
+        // funding up to now accrues at the old parameters
+        let current_price = get_oracle_price(
+            market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            market.fixed_price,
+            market.max_price_age,
+        )?
+        .to_decimal();
+        accrue_funding(market, current_price, Clock::get()?.unix_timestamp)?;
+
+        market.initial_margin = initial_margin;
+        market.maintenance_margin = maintenance_margin;
+        market.market_weight = market_weight;
+        market.funding_period = funding_period;
+        market.max_funding_rate = max_funding_rate;
+        market.max_long_open_interest = max_long_open_interest;
+        market.max_short_open_interest = max_short_open_interest;
+        market.max_position_size = max_position_size;
+        Ok(())
+    }
+
+    pub fn update_market_fees(
+        ctx: Context<UpdateMarketFees>,
+        _market_index: u16,
         maker_fee: i16,
         taker_fee: i16,
-        leverage: u32,
-        market_weight: u16,
     ) -> Result<()> {
         let market = &mut ctx.accounts.market;
         market.taker_fee = taker_fee;
         market.maker_fee = maker_fee;
-        market.leverage = leverage;
-        market.market_weight = market_weight;
+        Ok(())
+    }
+
+    pub fn update_market_oracle(
+        ctx: Context<UpdateMarketOracle>,
+        _market_index: u16,
+        max_price_age: u32,
+        oracle_source: OracleSource,
+        fixed_price: i64,
+    ) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        market.max_price_age = max_price_age;
+        market.oracle_source = oracle_source;
+        market.fixed_price = fixed_price;
         Ok(())
     }
 
     pub fn update_exchange(
         ctx: Context<UpdateExchange>,
-        test_mode: bool,
-        reward_frequency: u64,
-        reward_rate: u64,
         leverage: u32,
         market_weight: u16,
+        liquidation_fee: u16,
+        liquidator_share: u16,
     ) -> Result<()> {
         let exchange = &mut ctx.accounts.exchange;
-        exchange.test_mode = test_mode;
-        exchange.reward_frequency = reward_frequency;
-        exchange.reward_rate = reward_rate;
         exchange.leverage = leverage;
         exchange.market_weight = market_weight;
+        exchange.liquidation_fee = liquidation_fee;
+        exchange.liquidator_share = liquidator_share;
+        Ok(())
+    }
+
+    pub fn update_exchange_fees(
+        ctx: Context<UpdateExchangeFees>,
+        reward_frequency: u64,
+        reward_rate: u64,
+        insurance_fee_share: u16,
+        referrer_fee_share: u16,
+        referee_fee_discount: u16,
+    ) -> Result<()> {
+        let exchange = &mut ctx.accounts.exchange;
+        // the running epoch accrues at the old frequency up to now
+        accrue_rewards(exchange, Clock::get()?.unix_timestamp)?;
+        exchange.reward_frequency = reward_frequency;
+        exchange.reward_rate = reward_rate;
+        exchange.insurance_fee_share = insurance_fee_share;
+        exchange.referrer_fee_share = referrer_fee_share;
+        exchange.referee_fee_discount = referee_fee_discount;
         Ok(())
     }
 
-    pub fn execute_trade(
-        ctx: Context<ExecuteTrade>,
+    pub fn execute_trade<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ExecuteTrade<'info>>,
         _market_index: u16,
         amount: i64,
+        limit_price: Option<i64>,
+        reduce_only: bool,
     ) -> Result<()> {
         let user_account = &mut ctx.accounts.user_account;
         let user_position = &mut ctx.accounts.user_position;
         let market = &mut ctx.accounts.market;
         let exchange = &mut ctx.accounts.exchange;
 
+        // reduce only trades must shrink an existing position without flipping it
+        if reduce_only
+            && (user_position.token_amount == 0
+                || user_position.token_amount.signum() == amount.signum()
+                || amount.abs() > user_position.token_amount.abs())
+        {
+            return err!(KrunchErrors::ReduceOnlyViolation);
+        }
+
         // get price
-        let round = chainlink::latest_round_data(
+        let current_price = get_oracle_price(
+            market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
             ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
+            exchange,
+            market.fixed_price,
+            market.max_price_age,
+        )?
+        .to_decimal();
+
+        // settle funding before the position changes
+        let now = Clock::get()?.unix_timestamp;
+        accrue_funding(market, current_price, now)?;
+        settle_funding(user_account, user_position, market, exchange)?;
+        let mut positions = load_open_positions(
+            ctx.remaining_accounts,
+            user_account,
+            exchange,
+            &ctx.accounts.chainlink_program,
+            Some(user_position),
+            None,
+            None,
+        )?;
+        settle_open_positions(&mut positions, user_account, exchange)?;
+
+        // limit prices are quoted with AMOUNT_NUM_DECIMALS
+        if let Some(limit_price) = limit_price {
+            let limit_price = to_amount(limit_price);
+            let price_difference = current_price.checked_sub(limit_price)?.value;
+            if (amount > 0 && price_difference > 0) || (amount < 0 && price_difference < 0) {
+                return err!(KrunchErrors::SlippageExceeded);
+            }
+        }
+
+        // trades against the exchange always take liquidity
+        let (_, taker_fee) = get_fee_rates(
+            market,
+            user_account,
+            ctx.accounts.fee_tiers.as_deref(),
+            ctx.accounts.fee_override.as_deref(),
+        );
+        let mut fee = calculate_fee(amount, current_price, taker_fee)?;
+        if let Some(referrer) = ctx.accounts.referrer.as_deref_mut() {
+            fee = apply_referral(fee, market, exchange, referrer)?;
+        }
+        update_volume(user_account, amount, current_price, now)?;
+        charge_fee(user_account, user_position, market, exchange, fee)?;
+
+        let exposure_before = Exposure::new(market, user_position);
+        update_position(
+            user_account,
+            user_position,
+            market,
+            exchange,
+            amount,
+            current_price,
+        )?;
+        exposure_before.validate(market, user_position)?;
+
+        let exchange_total = calculate_exchange_balance_available(exchange)?;
+        if exchange_total.value < 0 {
+            return err!(KrunchErrors::ExchangeMarginInsufficient);
+        }
+
+        let market_total = calculate_market_total(exchange, market)?;
+        if market_total.value < 0 {
+            return err!(KrunchErrors::MarketMarginInsufficient);
+        }
+
+        let user_total = calculate_account_health(
+            user_account,
+            Some((user_position, market, current_price)),
+            None,
+            &positions,
+            false,
         )?;
-        let price_decimals = chainlink::decimals(
+        if user_total.value < 0 {
+            return err!(KrunchErrors::UserMarginInsufficient);
+        }
+        exit_open_positions(&positions)?;
+        Ok(())
+    }
+
+    pub fn liquidate_user<'info>(
+        ctx: Context<'_, '_, 'info, 'info, LiquidateUser<'info>>,
+        _market_index: u16,
+        amount: i64,
+    ) -> Result<()> {
+        let liquidator_account = &mut ctx.accounts.liquidator_account;
+        let user_account = &mut ctx.accounts.user_account;
+        let user_position = &mut ctx.accounts.user_position;
+        let market = &mut ctx.accounts.market;
+        let exchange = &mut ctx.accounts.exchange;
+
+        if user_position.token_amount == 0 || amount < 0 {
+            return err!(KrunchErrors::InvalidLiquidationAmount);
+        }
+
+        // get price
+        let current_price = get_oracle_price(
+            market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
             ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
+            exchange,
+            market.fixed_price,
+            market.max_price_age,
+        )?
+        .to_decimal();
+
+        // settle funding and mark every open position to the current price before
+        // checking health, profits elsewhere can keep the account out of liquidation
+        accrue_funding(market, current_price, Clock::get()?.unix_timestamp)?;
+        settle_funding(user_account, user_position, market, exchange)?;
+        update_margin_used(user_account, user_position, market, exchange, current_price)?;
+        let mut positions = load_open_positions(
+            ctx.remaining_accounts,
+            user_account,
+            exchange,
+            &ctx.accounts.chainlink_program,
+            Some(user_position),
+            None,
+            None,
+        )?;
+        settle_open_positions(&mut positions, user_account, exchange)?;
+        let maintenance_total = calculate_account_health(
+            user_account,
+            Some((user_position, market, current_price)),
+            None,
+            &positions,
+            true,
         )?;
+        if maintenance_total.value >= 0 {
+            return err!(KrunchErrors::UserNotLiquidatable);
+        }
 
-        let mut current_price = round.answer;
-        if exchange.test_mode {
-            let clock = Clock::get()?;
-            let current_unix_timestamp = clock.unix_timestamp;
-            let last_digit = current_unix_timestamp % 10;
-            current_price = (current_price as f64 * (1.0 + (last_digit as f64) / 100.0)) as i128;
+        // close the whole position unless a smaller amount was requested
+        let mut close_amount = user_position.token_amount.abs();
+        if amount > 0 && amount < close_amount {
+            close_amount = amount;
         }
+        let trade_amount = if user_position.token_amount > 0 {
+            -close_amount
+        } else {
+            close_amount
+        };
+        update_position(
+            user_account,
+            user_position,
+            market,
+            exchange,
+            trade_amount,
+            current_price,
+        )?;
+
+        // liquidation fee is split between the liquidator and the exchange
+        let close_basis = token_value(close_amount, current_price, Rounding::Up)?;
+        let (fee, liquidator_fee) = calculate_liquidation_fee(exchange, close_basis)?;
+
+        // the whole fee is charged like a trading fee, so insurance takes its share,
+        // and the exchange pays the liquidator's share out as a rebate
+        charge_fee(user_account, user_position, market, exchange, fee)?;
+        checked_sub_assign(&mut exchange.rebates, liquidator_fee)?;
+        checked_sub_assign(&mut market.rebates, liquidator_fee)?;
+        checked_add_assign(&mut liquidator_account.rebates, liquidator_fee)?;
+
+        cover_bad_debt(user_account, Some(market), exchange)?;
+        exit_open_positions(&positions)?;
+        Ok(())
+    }
+
+    pub fn update_funding(ctx: Context<UpdateFunding>, _market_index: u16) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let exchange = &ctx.accounts.exchange;
 
-        let fbasis = (amount as i128 * current_price as i128) / 10i128.pow(price_decimals.into());
-        msg!("basis is {}", fbasis);
+        // get price
+        let current_price = get_oracle_price(
+            market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            exchange,
+            market.fixed_price,
+            market.max_price_age,
+        )?
+        .to_decimal();
 
-        let mut fee_rate: i64 = market.taker_fee.into();
+        // accrue at the old rate, then reprice from the open interest skew.
+        // trades fill at the oracle price so the mark premium is the skew itself
+        accrue_funding(market, current_price, Clock::get()?.unix_timestamp)?;
+        let long_open_interest = i128::from(market.long_open_interest);
+        let short_open_interest = i128::from(market.short_open_interest);
+        let max_funding_rate = i128::from(market.max_funding_rate);
+        let open_interest = long_open_interest + short_open_interest;
+        let skew = long_open_interest - short_open_interest;
+        let mut funding_rate = 0;
+        if open_interest > 0 {
+            funding_rate = (max_funding_rate * skew) / open_interest;
+        }
+        market.funding_rate =
+            i64::try_from(funding_rate.max(-max_funding_rate).min(max_funding_rate))
+                .map_err(|_| error!(KrunchErrors::MathOverflow))?;
+        msg!("funding rate is {}", market.funding_rate);
+        Ok(())
+    }
 
-        let fee_token_delta = market.token_amount + amount * -1; // market amounts are stored opposite user positions so flip the sign
-        if fee_token_delta.abs() < market.token_amount.abs()
-            && amount.abs() <= market.token_amount.abs()
+    pub fn update_fee_tiers(ctx: Context<UpdateFeeTiers>, tiers: Vec<FeeTier>) -> Result<()> {
+        if tiers.len() > MAX_FEE_TIERS
+            || tiers
+                .windows(2)
+                .any(|pair| pair[0].volume_threshold >= pair[1].volume_threshold)
         {
-            // maker
-            fee_rate = market.maker_fee.into();
+            return err!(KrunchErrors::InvalidFeeTiers);
+        }
+        let fee_tiers = &mut ctx.accounts.fee_tiers;
+        fee_tiers.tiers = tiers;
+        Ok(())
+    }
+
+    pub fn set_user_fee_override(
+        ctx: Context<SetUserFeeOverride>,
+        owner: Pubkey,
+        maker_fee: i16,
+        taker_fee: i16,
+    ) -> Result<()> {
+        let fee_override = &mut ctx.accounts.fee_override;
+        fee_override.owner = owner;
+        fee_override.maker_fee = maker_fee;
+        fee_override.taker_fee = taker_fee;
+        Ok(())
+    }
+
+    pub fn remove_user_fee_override(
+        _ctx: Context<RemoveUserFeeOverride>,
+        _owner: Pubkey,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn add_order_book(
+        ctx: Context<AddOrderBook>,
+        market_index: u16,
+        price_band: u16,
+        min_order_size: i64,
+        max_orders_per_user: u8,
+    ) -> Result<()> {
+        if price_band == 0 || min_order_size <= 0 || max_orders_per_user == 0 {
+            return err!(KrunchErrors::InvalidOrderBookParameters);
         }
-        let fee = ((fbasis.abs() * fee_rate as i128) / FEE_DECIMALS as i128) as i64;
+        let order_book = &mut ctx.accounts.order_book;
+        order_book.market_index = market_index;
+        order_book.next_order_id = 0;
+        order_book.bids = Vec::new();
+        order_book.asks = Vec::new();
+        order_book.price_band = price_band;
+        order_book.min_order_size = min_order_size;
+        order_book.max_orders_per_user = max_orders_per_user;
+        Ok(())
+    }
 
-        // update fees
-        if fee < 0 {
-            exchange.rebates += fee;
-            market.rebates += fee;
-            user_account.rebates += fee * -1;
-            user_position.rebates += fee * -1;
-        } else {
-            exchange.fees += fee;
-            market.fees += fee;
-            user_account.fees += fee * -1;
-            user_position.fees += fee * -1;
+    pub fn update_order_book(
+        ctx: Context<UpdateOrderBook>,
+        _market_index: u16,
+        price_band: u16,
+        min_order_size: i64,
+        max_orders_per_user: u8,
+    ) -> Result<()> {
+        if price_band == 0 || min_order_size <= 0 || max_orders_per_user == 0 {
+            return err!(KrunchErrors::InvalidOrderBookParameters);
         }
+        let order_book = &mut ctx.accounts.order_book;
+        order_book.price_band = price_band;
+        order_book.min_order_size = min_order_size;
+        order_book.max_orders_per_user = max_orders_per_user;
+        Ok(())
+    }
 
-        // update balances
-        let basis_before = user_position.basis;
-        let token_amount_before = user_position.token_amount;
+    pub fn place_order<'info>(
+        ctx: Context<'_, '_, 'info, 'info, PlaceOrder<'info>>,
+        market_index: u16,
+        amount: i64,
+        price: i64,
+        expiry: i64,
+    ) -> Result<()> {
+        let order_book = &mut ctx.accounts.order_book;
+        let user_account = &mut ctx.accounts.user_account;
+        let user_position = &mut ctx.accounts.user_position;
+        let market = &mut ctx.accounts.market;
+        let exchange = &mut ctx.accounts.exchange;
+        let owner = ctx.accounts.owner.key();
+        let now = Clock::get()?.unix_timestamp;
 
-        let mut token_delta = 0;
-        if (user_position.token_amount < 0 && amount > 0)
-            || (user_position.token_amount > 0 && amount < 0)
-        {
-            token_delta = user_position.token_amount.abs().min(amount.abs());
+        // every order expires so abandoned orders cannot hold the book
+        if amount.abs() < order_book.min_order_size || price <= 0 || expiry <= now {
+            return err!(KrunchErrors::InvalidOrder);
         }
-        market.token_amount -= amount;
-        user_position.token_amount += amount;
 
-        // update collateral value
-        let f_margin_basis = (user_position.token_amount as i128 * current_price as i128)
-            / 10i128.pow(price_decimals.into());
+        // get price
+        let current_price = get_oracle_price(
+            market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            exchange,
+            market.fixed_price,
+            market.max_price_age,
+        )?
+        .to_decimal();
 
-        let margin_used = f_margin_basis.abs() as i64;
-        let f_delta = user_position.margin_used.abs() - margin_used;
+        // settle funding before the position changes
+        accrue_funding(market, current_price, now)?;
+        settle_funding(user_account, user_position, market, exchange)?;
+        let mut positions = load_open_positions(
+            ctx.remaining_accounts,
+            user_account,
+            exchange,
+            &ctx.accounts.chainlink_program,
+            Some(user_position),
+            None,
+            None,
+        )?;
+        settle_open_positions(&mut positions, user_account, exchange)?;
 
-        user_position.margin_used = margin_used * -1;
-        user_account.margin_used += f_delta;
-        market.margin_used += f_delta;
-        exchange.margin_used += f_delta;
+        let exposure_before = Exposure::new(market, user_position);
+        let fee_tiers = ctx.accounts.fee_tiers.as_deref();
+        let (_, taker_fee_rate) = get_fee_rates(
+            market,
+            user_account,
+            fee_tiers,
+            ctx.accounts.fee_override.as_deref(),
+        );
 
-        if token_delta != 0 {
-            let avg_price = basis_before.abs() as f64 / token_amount_before.abs() as f64;
-            let abasis = (avg_price * token_delta as f64) as i64;
+        // fill crossing orders at the resting price, makers follow the taker's
+        // open positions as (user_account, user_position, fee_override) triples
+        // in book order, each followed by the maker's own open positions in the
+        // same layout as the taker's, the fee override address is passed even
+        // when it does not exist
+        let is_bid = amount > 0;
+        let mut remaining = amount.abs();
+        let price_band = order_book.price_band;
+        let min_order_size = order_book.min_order_size;
+        let mut maker_offset = positions.account_count();
+        while remaining > 0 {
+            let resting_orders = if is_bid {
+                &mut order_book.asks
+            } else {
+                &mut order_book.bids
+            };
+            let resting = match resting_orders.first() {
+                Some(resting) => *resting,
+                None => break,
+            };
+            if resting.is_expired(now) || resting.owner == owner {
+                // drop expired orders and never trade against yourself
+                resting_orders.remove(0);
+                continue;
+            }
+            if (is_bid && resting.price > price) || (!is_bid && resting.price < price) {
+                break;
+            }
+            let resting_price = to_amount(resting.price);
+            if !is_within_price_band(resting_price, current_price, price_band)? {
+                // off market orders are dropped rather than filled
+                resting_orders.remove(0);
+                continue;
+            }
 
-            let tbasis = (token_delta.abs() as i128 * current_price as i128)
-                / 10i128.pow(price_decimals.into());
+            let accounts = match ctx.remaining_accounts.get(maker_offset..maker_offset + 3) {
+                Some(accounts) => accounts,
+                None => return err!(KrunchErrors::MissingMakerAccounts),
+            };
+            let (maker_account_address, _) = Pubkey::find_program_address(
+                &[b"user_account".as_ref(), resting.owner.as_ref()],
+                &crate::ID,
+            );
+            let (maker_position_address, _) = Pubkey::find_program_address(
+                &[
+                    b"user_position".as_ref(),
+                    resting.owner.as_ref(),
+                    market_index.to_le_bytes().as_ref(),
+                ],
+                &crate::ID,
+            );
+            let (maker_fee_override_address, _) = Pubkey::find_program_address(
+                &[b"fee_override".as_ref(), resting.owner.as_ref()],
+                &crate::ID,
+            );
+            if accounts[0].key() != maker_account_address
+                || accounts[1].key() != maker_position_address
+                || accounts[2].key() != maker_fee_override_address
+            {
+                return err!(KrunchErrors::MissingMakerAccounts);
+            }
+            let maker_fee_override: Option<Account<UserFeeOverride>> =
+                if accounts[2].data_is_empty() {
+                    None
+                } else {
+                    Some(Account::try_from(&accounts[2])?)
+                };
+            let mut maker_account: Account<UserAccount> = Account::try_from(&accounts[0])?;
+            let mut maker_position: Account<UserPosition> = Account::try_from(&accounts[1])?;
 
-            let pnl = abasis - tbasis as i64;
-            let basis_adjustment = abasis * -1;
+            let fill = remaining.min(resting.size);
 
-            user_position.basis -= basis_adjustment as i64;
-            user_position.pnl += pnl as i64;
+            // the maker side is applied to copies and only kept if the maker's
+            // account still meets initial margin, otherwise the resting order is cancelled
+            let mut next_maker_account = (*maker_account).clone();
+            let mut next_maker_position = (*maker_position).clone();
+            let mut next_market = (**market).clone();
+            let mut next_exchange = (**exchange).clone();
+            let maker_amount = if is_bid { -fill } else { fill };
+            let maker_exposure_before = Exposure::new(&next_market, &next_maker_position);
+            settle_funding(
+                &mut next_maker_account,
+                &mut next_maker_position,
+                &mut next_market,
+                &mut next_exchange,
+            )?;
+            update_position(
+                &mut next_maker_account,
+                &mut next_maker_position,
+                &mut next_market,
+                &mut next_exchange,
+                maker_amount,
+                resting_price,
+            )?;
+            let (maker_fee_rate, _) = get_fee_rates(
+                &next_market,
+                &next_maker_account,
+                fee_tiers,
+                maker_fee_override.as_deref(),
+            );
+            let maker_fee = calculate_fee(fill, resting_price, maker_fee_rate)?;
+            update_volume(&mut next_maker_account, fill, resting_price, now)?;
+            charge_fee(
+                &mut next_maker_account,
+                &mut next_maker_position,
+                &mut next_market,
+                &mut next_exchange,
+                maker_fee,
+            )?;
+            let mut maker_positions = load_open_positions(
+                &ctx.remaining_accounts[maker_offset + 3..],
+                &next_maker_account,
+                &next_exchange,
+                &ctx.accounts.chainlink_program,
+                Some(&next_maker_position),
+                None,
+                None,
+            )?;
+            maker_offset += 3 + maker_positions.account_count();
 
-            user_account.basis -= basis_adjustment as i64;
-            user_account.pnl += pnl as i64;
+            // the maker's other positions are only marked for the check and never
+            // written back, the taker may hold the same markets
+            let mut health_account = next_maker_account.clone();
+            let mut health_exchange = next_exchange.clone();
+            settle_open_positions(
+                &mut maker_positions,
+                &mut health_account,
+                &mut health_exchange,
+            )?;
+            let maker_total = calculate_account_health(
+                &health_account,
+                Some((&next_maker_position, &next_market, current_price)),
+                None,
+                &maker_positions,
+                false,
+            )?;
+            if maker_total.value < 0
+                || maker_exposure_before
+                    .validate(&next_market, &next_maker_position)
+                    .is_err()
+            {
+                resting_orders.remove(0);
+                continue;
+            }
+            maker_account.set_inner(next_maker_account);
+            maker_position.set_inner(next_maker_position);
+            market.set_inner(next_market);
+            exchange.set_inner(next_exchange);
+            maker_account.exit(&crate::ID)?;
+            maker_position.exit(&crate::ID)?;
 
-            market.basis += basis_adjustment as i64;
-            market.pnl -= pnl as i64;
+            // taker side
+            update_position(
+                user_account,
+                user_position,
+                market,
+                exchange,
+                -maker_amount,
+                resting_price,
+            )?;
+            let mut taker_fee = calculate_fee(fill, resting_price, taker_fee_rate)?;
+            if let Some(referrer) = ctx.accounts.referrer.as_deref_mut() {
+                taker_fee = apply_referral(taker_fee, market, exchange, referrer)?;
+            }
+            update_volume(user_account, fill, resting_price, now)?;
+            charge_fee(user_account, user_position, market, exchange, taker_fee)?;
 
-            exchange.basis += basis_adjustment as i64;
-            exchange.pnl -= pnl as i64;
+            // dust left behind by a partial fill is not kept on the book
+            if resting.size - fill < min_order_size {
+                resting_orders.remove(0);
+            } else {
+                resting_orders[0].size -= fill;
+            }
+            remaining -= fill;
         }
 
-        // update token basis
-        let position_increase = amount.abs() - token_delta.abs();
-        let basis_increase =
-            (position_increase as i128 * current_price as i128) / 10i128.pow(price_decimals.into());
+        // rest whatever did not cross, dust is dropped
+        if remaining >= min_order_size {
+            if order_book.order_count(&owner) >= order_book.max_orders_per_user.into() {
+                return err!(KrunchErrors::MaxOrdersExceeded);
+            }
+            let order = Order {
+                order_id: order_book.next_order_id,
+                owner,
+                price,
+                size: remaining,
+                expiry,
+            };
+            order_book.next_order_id = order_book
+                .next_order_id
+                .checked_add(1)
+                .ok_or(KrunchErrors::MathOverflow)?;
+            order_book.insert(is_bid, order)?;
+            msg!("order {} placed", order.order_id);
+        }
 
-        user_position.basis -= basis_increase as i64;
-        user_account.basis -= basis_increase as i64;
-        market.basis += basis_increase as i64;
-        exchange.basis += basis_increase as i64;
+        exposure_before.validate(market, user_position)?;
 
-        let exchange_total = calculate_exchange_balance_available(&exchange);
-        if exchange_total < 0 {
-            return err!(KrunchErrors::ExchangeMarginInsufficient);
+        let user_total = calculate_account_health(
+            user_account,
+            Some((user_position, market, current_price)),
+            None,
+            &positions,
+            false,
+        )?;
+        if user_total.value < 0 {
+            return err!(KrunchErrors::UserMarginInsufficient);
         }
+        exit_open_positions(&positions)?;
+        Ok(())
+    }
 
-        let market_total = calculate_market_total(&exchange, &market);
-        if market_total < 0 {
-            return err!(KrunchErrors::MarketMarginInsufficient);
+    pub fn cancel_order(
+        ctx: Context<CancelOrder>,
+        _market_index: u16,
+        order_id: u64,
+    ) -> Result<()> {
+        let order_book = &mut ctx.accounts.order_book;
+        order_book.remove(order_id, ctx.accounts.owner.key)?;
+        Ok(())
+    }
+
+    pub fn settle_pnl<'info>(ctx: Context<'_, '_, 'info, 'info, SettlePnl<'info>>) -> Result<()> {
+        // get price
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
+
+        let user_account = &mut ctx.accounts.user_account;
+        let user_collateral = &mut ctx.accounts.user_collateral;
+        let exchange_treasury_position = &mut ctx.accounts.exchange_treasury_position;
+        let exchange = &mut ctx.accounts.exchange;
+        let mut positions = load_open_positions(
+            ctx.remaining_accounts,
+            user_account,
+            exchange,
+            &ctx.accounts.chainlink_program,
+            None,
+            None,
+            Some(user_collateral),
+        )?;
+        settle_open_positions(&mut positions, user_account, exchange)?;
+        exit_open_positions(&positions)?;
+        user_collateral.owner = ctx.accounts.owner.key();
+        user_collateral.mint = ctx.accounts.mint.key();
+        settle_user_pnl(
+            user_account,
+            user_collateral,
+            exchange,
+            exchange_treasury_position,
+            ctx.accounts.escrow_account.amount,
+            price,
+        )
+    }
+
+    pub fn update_collateral(ctx: Context<UpdateCollateral>) -> Result<()> {
+        // get price
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
+
+        // anyone can mark a balance to the oracle, liquidators do so before liquidating
+        mark_collateral(
+            &mut ctx.accounts.user_collateral,
+            &mut ctx.accounts.user_account,
+            &mut ctx.accounts.exchange,
+            &ctx.accounts.exchange_treasury_position,
+            price,
+        )
+    }
+
+    pub fn fund_insurance(ctx: Context<FundInsurance>) -> Result<()> {
+        // get price
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
+
+        // only fees the house has actually collected can be moved out of escrow
+        // and only out of the part of the escrow that is not owed to users,
+        // lps or the house's own deposits
+        let exchange = &ctx.accounts.exchange;
+        let exchange_treasury_position = &ctx.accounts.exchange_treasury_position;
+        let decimals = exchange_treasury_position.decimals.into();
+        let house_tokens = free_escrow_tokens(
+            ctx.accounts.escrow_account.amount,
+            exchange_treasury_position,
+        );
+        let amount = exchange
+            .insurance_fees_pending
+            .min(exchange.settled_pnl)
+            .max(0);
+        let token_amount = to_amount(amount)
+            .checked_div(price, decimals, Rounding::Down)?
+            .to_u64(decimals, Rounding::Down)?
+            .min(house_tokens);
+        if token_amount == 0 {
+            return Ok(());
         }
 
-        let user_total = calculate_user_total(&user_account, market.leverage.into());
-        if user_total < 0 {
-            return err!(KrunchErrors::UserMarginInsufficient);
+        // token transfer
+        let vault_amount = ctx.accounts.insurance_vault.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.escrow_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.insurance_vault.to_account_info(),
+            authority: ctx.accounts.exchange.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let bump = ctx.bumps.exchange;
+        let seeds = &[b"exchange".as_ref(), &[bump]];
+        let signer_seeds = &[&seeds[..]];
+
+        transfer_checked(
+            CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds),
+            token_amount,
+            ctx.accounts.mint.decimals,
+        )?;
+
+        // the house pays for what was sent, the fund is worth what arrived
+        ctx.accounts.insurance_vault.reload()?;
+        let received = ctx
+            .accounts
+            .insurance_vault
+            .amount
+            .checked_sub(vault_amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        let amount = from_amount(
+            Decimal::new(token_amount.into(), decimals).checked_mul(
+                price,
+                AMOUNT_NUM_DECIMALS.into(),
+                Rounding::Down,
+            )?,
+            Rounding::Down,
+        )?;
+        let fund_amount = from_amount(
+            Decimal::new(received.into(), decimals).checked_mul(
+                price,
+                AMOUNT_NUM_DECIMALS.into(),
+                Rounding::Down,
+            )?,
+            Rounding::Down,
+        )?;
+        let exchange = &mut ctx.accounts.exchange;
+        checked_sub_assign(&mut exchange.insurance_fees_pending, amount)?;
+        checked_sub_assign(&mut exchange.settled_pnl, amount)?;
+        checked_add_assign(&mut exchange.insurance_fund_value, fund_amount)?;
+        Ok(())
+    }
+
+    pub fn pay_insurance_claim(ctx: Context<PayInsuranceClaim>) -> Result<()> {
+        // get price
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
+
+        // bad debt the fund has covered is paid back into escrow out of the vault
+        let exchange = &ctx.accounts.exchange;
+        let decimals = ctx.accounts.exchange_treasury_position.decimals.into();
+        let token_amount = to_amount(exchange.insurance_claims_pending.max(0))
+            .checked_div(price, decimals, Rounding::Up)?
+            .to_u64(decimals, Rounding::Up)?
+            .min(ctx.accounts.insurance_vault.amount);
+        if token_amount == 0 {
+            return Ok(());
+        }
+
+        // token transfer
+        let escrow_amount = ctx.accounts.escrow_account.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.insurance_vault.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.escrow_account.to_account_info(),
+            authority: ctx.accounts.exchange.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let bump = ctx.bumps.exchange;
+        let seeds = &[b"exchange".as_ref(), &[bump]];
+        let signer_seeds = &[&seeds[..]];
+
+        transfer_checked(
+            CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds),
+            token_amount,
+            ctx.accounts.mint.decimals,
+        )?;
+
+        // the claim is reduced by what was sent, the house holds what arrived
+        ctx.accounts.escrow_account.reload()?;
+        let received = ctx
+            .accounts
+            .escrow_account
+            .amount
+            .checked_sub(escrow_amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        let amount = from_amount(
+            Decimal::new(token_amount.into(), decimals).checked_mul(
+                price,
+                AMOUNT_NUM_DECIMALS.into(),
+                Rounding::Down,
+            )?,
+            Rounding::Down,
+        )?;
+        let settled_amount = from_amount(
+            Decimal::new(received.into(), decimals).checked_mul(
+                price,
+                AMOUNT_NUM_DECIMALS.into(),
+                Rounding::Down,
+            )?,
+            Rounding::Down,
+        )?;
+        let exchange = &mut ctx.accounts.exchange;
+        exchange.insurance_claims_pending = exchange
+            .insurance_claims_pending
+            .checked_sub(amount)
+            .ok_or(KrunchErrors::MathOverflow)?
+            .max(0);
+        checked_add_assign(&mut exchange.settled_pnl, settled_amount)?;
+        Ok(())
+    }
+
+    pub fn close_user_position(ctx: Context<CloseUserPosition>, _market_index: u16) -> Result<()> {
+        // resting orders fill against this position, so they have to go first
+        let order_book = &ctx.accounts.order_book;
+        if !order_book.data_is_empty() {
+            let order_book = OrderBook::try_deserialize(&mut &order_book.data.borrow()[..])?;
+            if order_book.order_count(ctx.accounts.owner.key) > 0 {
+                return err!(KrunchErrors::RestingOrdersOpen);
+            }
         }
         Ok(())
     }
@@ -223,6 +1077,9 @@ pub mod krunch {
         treasury_weight: u16,
         decimals: u8,
         feed_address: Pubkey,
+        max_price_age: u32,
+        oracle_source: OracleSource,
+        fixed_price: i64,
     ) -> Result<()> {
         let position = &mut ctx.accounts.exchange_treasury_position;
         position.token_mint = token_mint;
@@ -230,6 +1087,14 @@ pub mod krunch {
         position.treasury_weight = treasury_weight;
         position.decimals = decimals;
         position.feed_address = feed_address;
+        position.max_price_age = max_price_age;
+        position.oracle_source = oracle_source;
+        position.fixed_price = fixed_price;
+        position.token_amount = 0;
+        position.house_token_amount = 0;
+        position.house_collateral_value = 0;
+        position.lp_token_amount = 0;
+        position.lp_collateral_value = 0;
         Ok(())
     }
 
@@ -238,14 +1103,28 @@ pub mod krunch {
         _token_mint: Pubkey,
         active: bool,
         treasury_weight: u16,
-        decimals: u8,
-        feed_address: Pubkey,
     ) -> Result<()> {
         let position = &mut ctx.accounts.exchange_treasury_position;
         position.active = active;
         position.treasury_weight = treasury_weight;
+        Ok(())
+    }
+
+    pub fn update_exchange_position_oracle(
+        ctx: Context<UpdateExchangeTreasuryOracle>,
+        _token_mint: Pubkey,
+        decimals: u8,
+        feed_address: Pubkey,
+        max_price_age: u32,
+        oracle_source: OracleSource,
+        fixed_price: i64,
+    ) -> Result<()> {
+        let position = &mut ctx.accounts.exchange_treasury_position;
         position.decimals = decimals;
         position.feed_address = feed_address;
+        position.max_price_age = max_price_age;
+        position.oracle_source = oracle_source;
+        position.fixed_price = fixed_price;
         Ok(())
     }
 
@@ -254,123 +1133,687 @@ pub mod krunch {
         market_index: u16,
         taker_fee: i16,
         maker_fee: i16,
-        leverage: u32,
+        initial_margin: u16,
+        maintenance_margin: u16,
         market_weight: u16,
         feed_address: Pubkey,
+        funding_period: i64,
+        max_funding_rate: i64,
+        max_price_age: u32,
+        oracle_source: OracleSource,
+        fixed_price: i64,
+        max_long_open_interest: i64,
+        max_short_open_interest: i64,
+        max_position_size: i64,
     ) -> Result<()> {
+        if maintenance_margin > initial_margin {
+            return err!(KrunchErrors::InvalidMarginRequirement);
+        }
+        if funding_period <= 0 {
+            return err!(KrunchErrors::InvalidFundingPeriod);
+        }
+        if max_funding_rate < 0 {
+            return err!(KrunchErrors::InvalidMaxFundingRate);
+        }
+        let clock = Clock::get()?;
         let market = &mut ctx.accounts.market;
         market.market_index = market_index;
         market.token_amount = 0;
         market.maker_fee = maker_fee;
         market.taker_fee = taker_fee;
-        market.leverage = leverage;
+        market.initial_margin = initial_margin;
+        market.maintenance_margin = maintenance_margin;
         market.market_weight = market_weight;
         market.feed_address = feed_address;
+        market.funding_period = funding_period;
+        market.max_funding_rate = max_funding_rate;
+        market.max_price_age = max_price_age;
+        market.oracle_source = oracle_source;
+        market.fixed_price = fixed_price;
+        market.max_long_open_interest = max_long_open_interest;
+        market.max_short_open_interest = max_short_open_interest;
+        market.max_position_size = max_position_size;
+        market.funding_rate = 0;
+        market.cumulative_funding = 0;
+        market.last_funding_time = clock.unix_timestamp;
+        market.short_basis = 0;
+        ctx.accounts.exchange.number_of_markets = ctx
+            .accounts
+            .exchange
+            .number_of_markets
+            .checked_add(1)
+            .ok_or(KrunchErrors::MathOverflow)?;
         Ok(())
     }
 
     pub fn add_user_position(ctx: Context<AddUserPosition>, market_index: u16) -> Result<()> {
         let user_position = &mut ctx.accounts.user_position;
+        user_position.owner = ctx.accounts.owner.key.to_owned();
         user_position.market_index = market_index;
         user_position.token_amount = 0;
         Ok(())
     }
 
-    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
-        let user_account = &mut ctx.accounts.user_account;
+    // migrations move accounts created in the v0 layout to the current one.
+    // the exchange goes first and is left paused, then markets, treasury
+    // positions and yield markets, then user accounts and their positions are
+    // migrated before the pause guardian resumes trading
+    pub fn migrate_exchange(
+        ctx: Context<MigrateExchange>,
+        pyth_program: Pubkey,
+        liquidation_fee: u16,
+        liquidator_share: u16,
+        insurance_fee_share: u16,
+        referrer_fee_share: u16,
+        referee_fee_discount: u16,
+    ) -> Result<()> {
+        let legacy: ExchangeV0 = read_v0_account(
+            &ctx.accounts.exchange,
+            Exchange::DISCRIMINATOR,
+            EXCHANGE_V0_SPACE,
+        )?;
+        if legacy.admin != ctx.accounts.admin.key() {
+            return err!(anchor_lang::error::ErrorCode::ConstraintRaw);
+        }
+        let now = Clock::get()?.unix_timestamp;
+        let exchange = Exchange {
+            admin: legacy.admin,
+            margin_used: legacy.margin_used,
+            // markets are counted again as each one is migrated
+            number_of_markets: 0,
+            market_weight: legacy.market_weight,
+            basis: legacy.basis,
+            pnl: legacy.pnl,
+            fees: legacy.fees,
+            // user collateral is counted again as each user account migrates
+            collateral_value: 0,
+            leverage: legacy.leverage,
+            rebates: legacy.rebates,
+            rewards: legacy.rewards,
+            last_reward_update: now,
+            reward_frequency: legacy.reward_frequency,
+            reward_rate: legacy.reward_rate,
+            test_mode: legacy.test_mode,
+            chainlink_program: legacy.chainlink_program,
+            pyth_program,
+            liquidation_fee,
+            liquidator_share,
+            settled_pnl: 0,
+            insurance_fee_share,
+            insurance_fees_pending: 0,
+            insurance_fund_value: 0,
+            socialized_loss: 0,
+            house_collateral_value: 0,
+            lp_collateral_value: 0,
+            referrer_fee_share,
+            referee_fee_discount,
+            reward_epoch: 0,
+            reward_epoch_start: now,
+            reward_epoch_pool: 0,
+            reward_epoch_distributed: 0,
+            reward_index: 0,
+            pending_admin: Pubkey::default(),
+            risk_admin: legacy.admin,
+            oracle_admin: legacy.admin,
+            fee_admin: legacy.admin,
+            pause_guardian: legacy.admin,
+            paused: true,
+            insurance_claims_pending: 0,
+        };
+        write_migrated_account(
+            &exchange,
+            &ctx.accounts.exchange,
+            &ctx.accounts.admin,
+            &ctx.accounts.system_program,
+        )
+    }
+
+    pub fn migrate_market(
+        ctx: Context<MigrateMarket>,
+        market_index: u16,
+        initial_margin: u16,
+        maintenance_margin: u16,
+        funding_period: i64,
+        max_funding_rate: i64,
+        max_price_age: u32,
+        oracle_source: OracleSource,
+        fixed_price: i64,
+        max_long_open_interest: i64,
+        max_short_open_interest: i64,
+        max_position_size: i64,
+    ) -> Result<()> {
+        if maintenance_margin > initial_margin {
+            return err!(KrunchErrors::InvalidMarginRequirement);
+        }
+        if funding_period <= 0 {
+            return err!(KrunchErrors::InvalidFundingPeriod);
+        }
+        if max_funding_rate < 0 {
+            return err!(KrunchErrors::InvalidMaxFundingRate);
+        }
+        let legacy: MarketV0 =
+            read_v0_account(&ctx.accounts.market, Market::DISCRIMINATOR, MARKET_V0_SPACE)?;
+        // open interest and short basis are rebuilt as the positions migrate
+        let market = Market {
+            market_index,
+            market_weight: legacy.market_weight,
+            token_amount: legacy.token_amount,
+            basis: legacy.basis,
+            pnl: legacy.pnl,
+            fees: legacy.fees,
+            taker_fee: legacy.taker_fee,
+            maker_fee: legacy.maker_fee,
+            initial_margin,
+            maintenance_margin,
+            margin_used: legacy.margin_used,
+            feed_address: legacy.feed_address,
+            rebates: legacy.rebates,
+            long_open_interest: 0,
+            short_open_interest: 0,
+            funding_rate: 0,
+            max_funding_rate,
+            funding_period,
+            cumulative_funding: 0,
+            last_funding_time: Clock::get()?.unix_timestamp,
+            max_price_age,
+            oracle_source,
+            fixed_price,
+            max_long_open_interest,
+            max_short_open_interest,
+            max_position_size,
+            short_basis: 0,
+        };
+        write_migrated_account(
+            &market,
+            &ctx.accounts.market,
+            &ctx.accounts.admin,
+            &ctx.accounts.system_program,
+        )?;
         let exchange = &mut ctx.accounts.exchange;
-        execute_claim(user_account, exchange, true)?;
+        exchange.number_of_markets = exchange
+            .number_of_markets
+            .checked_add(1)
+            .ok_or(KrunchErrors::MathOverflow)?;
         Ok(())
     }
 
-    pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
-        // get price
-        let round = chainlink::latest_round_data(
-            ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
+    pub fn migrate_exchange_position(
+        ctx: Context<MigrateExchangeTreasuryPosition>,
+        _token_mint: Pubkey,
+        max_price_age: u32,
+        oracle_source: OracleSource,
+        fixed_price: i64,
+    ) -> Result<()> {
+        let legacy: ExchangeTreasuryPositionV0 = read_v0_account(
+            &ctx.accounts.exchange_treasury_position,
+            ExchangeTreasuryPosition::DISCRIMINATOR,
+            EXCHANGE_TREASURY_POSITION_V0_SPACE,
+        )?;
+        // v0 deposits were tracked by value only, so no tokens are owed yet
+        let exchange_treasury_position = ExchangeTreasuryPosition {
+            token_mint: legacy.token_mint,
+            active: legacy.active,
+            treasury_weight: legacy.treasury_weight,
+            decimals: legacy.decimals,
+            feed_address: legacy.feed_address,
+            max_price_age,
+            oracle_source,
+            fixed_price,
+            token_amount: 0,
+            house_token_amount: 0,
+            house_collateral_value: 0,
+            lp_token_amount: 0,
+            lp_collateral_value: 0,
+        };
+        write_migrated_account(
+            &exchange_treasury_position,
+            &ctx.accounts.exchange_treasury_position,
+            &ctx.accounts.admin,
+            &ctx.accounts.system_program,
+        )
+    }
+
+    pub fn migrate_yield_market(
+        ctx: Context<MigrateYieldMarket>,
+        market_index: u16,
+        max_price_age: u32,
+        oracle_source: OracleSource,
+        fixed_price: i64,
+        fee: i16,
+        initial_margin: u16,
+        maintenance_margin: u16,
+        funding_period: i64,
+        max_funding_rate: i64,
+        funding_curve: FundingCurve,
+        kink_utilization: i64,
+        kink_funding_rate: i64,
+    ) -> Result<()> {
+        if maintenance_margin > initial_margin {
+            return err!(KrunchErrors::InvalidMarginRequirement);
+        }
+        let legacy: YieldMarketV0 = read_v0_account(
+            &ctx.accounts.yield_market,
+            YieldMarket::DISCRIMINATOR,
+            YIELD_MARKET_V0_SPACE,
+        )?;
+        let mut yield_market = YieldMarket {
+            market_index,
+            long_token_amount: legacy.long_token_amount,
+            short_token_amount: legacy.short_token_amount,
+            long_basis: legacy.long_basis,
+            short_basis: legacy.short_basis,
+            long_funding: legacy.long_funding,
+            short_funding: legacy.short_funding,
+            short_fees: legacy.short_fees,
+            long_fees: legacy.long_fees,
+            last_claim_date: legacy.last_claim_date,
+            feed_address: legacy.chainlink_feed,
+            max_price_age,
+            oracle_source,
+            fixed_price,
+            fee,
+            initial_margin,
+            maintenance_margin,
+            funding_period: 0,
+            max_funding_rate: 0,
+            funding_curve: FundingCurve::Linear,
+            kink_utilization: 0,
+            kink_funding_rate: 0,
+        };
+        set_yield_funding(
+            &mut yield_market,
+            funding_period,
+            max_funding_rate,
+            funding_curve,
+            kink_utilization,
+            kink_funding_rate,
+        )?;
+        write_migrated_account(
+            &yield_market,
+            &ctx.accounts.yield_market,
+            &ctx.accounts.admin,
+            &ctx.accounts.system_program,
+        )
+    }
+
+    pub fn migrate_user_yield_position(
+        ctx: Context<MigrateUserYieldPosition>,
+        _market_index: u16,
+    ) -> Result<()> {
+        let legacy: UserYieldPositionV0 = read_v0_account(
+            &ctx.accounts.user_yield_position,
+            UserYieldPosition::DISCRIMINATOR,
+            USER_YIELD_POSITION_V0_SPACE,
         )?;
-        let price_decimals = chainlink::decimals(
+        let yield_market = &ctx.accounts.yield_market;
+        let current_price = get_oracle_price(
+            yield_market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
             ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
+            &ctx.accounts.exchange,
+            yield_market.fixed_price,
+            yield_market.max_price_age,
+        )?
+        .to_decimal();
+        // v0 funding was never claimed, the margin is charged on the account
+        // at the current price
+        let mut user_yield_position = UserYieldPosition {
+            owner: legacy.owner,
+            market_index: legacy.market_index,
+            long_token_amount: legacy.long_token_amount,
+            short_token_amount: legacy.short_token_amount,
+            long_basis: legacy.long_basis,
+            short_basis: legacy.short_basis,
+            long_funding: legacy.long_funding,
+            short_funding: legacy.short_funding,
+            short_fees: legacy.short_fees,
+            long_fees: legacy.long_fees,
+            last_claim_date: legacy.last_claim_date,
+            initial_margin_required: 0,
+            maintenance_margin_required: 0,
+            settled_funding: 0,
+        };
+        let user_account = &mut ctx.accounts.user_account;
+        update_yield_margin(
+            user_account,
+            &mut user_yield_position,
+            yield_market,
+            current_price,
+        )?;
+        update_open_yield_positions(user_account, false, &user_yield_position)?;
+        write_migrated_account(
+            &user_yield_position,
+            &ctx.accounts.user_yield_position,
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+        )
+    }
+
+    pub fn migrate_user_account(ctx: Context<MigrateUserAccount>) -> Result<()> {
+        let legacy: UserAccountV0 = read_v0_account(
+            &ctx.accounts.user_account,
+            UserAccount::DISCRIMINATOR,
+            USER_ACCOUNT_V0_SPACE,
         )?;
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
 
-        let decimals = &ctx.accounts.exchange_treasury_position.decimals;
-        let conversion = AMOUNT_NUM_DECIMALS - decimals;
+        // margin requirements and the position count build up as positions migrate
+        let mut user_account = UserAccount {
+            owner: legacy.owner,
+            collateral_value: 0,
+            margin_used: legacy.margin_used,
+            basis: legacy.basis,
+            pnl: legacy.pnl,
+            fees: legacy.fees,
+            rebates: legacy.rebates,
+            rewards: legacy.rewards,
+            reward_index: ctx.accounts.exchange.reward_index,
+            initial_margin_required: 0,
+            maintenance_margin_required: 0,
+            weighted_collateral_value: 0,
+            volume_30d: 0,
+            last_volume_update: Clock::get()?.unix_timestamp,
+            referrer: Pubkey::default(),
+            open_positions: 0,
+            open_collaterals: 0,
+            open_yield_positions: 0,
+        };
 
-        let total = (amount as u128 * round.answer as u128) / 10u128.pow(price_decimals.into());
-        let collateral_amount = total;
+        // v0 collateral was pooled across mints by value, it becomes a balance
+        // in the mint passed for as many tokens as the escrow still holds
+        // unclaimed, any value left over is kept as pnl
+        let exchange_treasury_position = &mut ctx.accounts.exchange_treasury_position;
+        let token_amount = to_amount(legacy.collateral_value.max(0))
+            .checked_div(
+                price,
+                exchange_treasury_position.decimals.into(),
+                Rounding::Down,
+            )?
+            .to_u64(exchange_treasury_position.decimals.into(), Rounding::Down)?
+            .min(free_escrow_tokens(
+                ctx.accounts.escrow_account.amount,
+                exchange_treasury_position,
+            ));
+        let user_collateral = &mut ctx.accounts.user_collateral;
+        user_collateral.owner = legacy.owner;
+        user_collateral.mint = ctx.accounts.mint.key();
+        set_collateral_tokens(
+            &mut user_account,
+            user_collateral,
+            exchange_treasury_position,
+            token_amount,
+        )?;
+        mark_collateral(
+            user_collateral,
+            &mut user_account,
+            &mut ctx.accounts.exchange,
+            exchange_treasury_position,
+            price,
+        )?;
+        checked_add_assign(
+            &mut user_account.pnl,
+            legacy
+                .collateral_value
+                .checked_sub(user_collateral.collateral_value)
+                .ok_or(KrunchErrors::MathOverflow)?,
+        )?;
+        write_migrated_account(
+            &user_account,
+            &ctx.accounts.user_account,
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+        )
+    }
 
-        // update collateral value
+    pub fn migrate_user_position(
+        ctx: Context<MigrateUserPosition>,
+        _market_index: u16,
+    ) -> Result<()> {
+        let legacy: UserPositionV0 = read_v0_account(
+            &ctx.accounts.user_position,
+            UserPosition::DISCRIMINATOR,
+            USER_POSITION_V0_SPACE,
+        )?;
+        let market = &mut ctx.accounts.market;
+        let current_price = get_oracle_price(
+            market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            market.fixed_price,
+            market.max_price_age,
+        )?
+        .to_decimal();
         let user_account = &mut ctx.accounts.user_account;
-        let exchange = &mut ctx.accounts.exchange;
+        if legacy.token_amount > 0 {
+            checked_add_assign(&mut market.long_open_interest, legacy.token_amount)?;
+        } else if legacy.token_amount < 0 {
+            checked_sub_assign(&mut market.short_open_interest, legacy.token_amount)?;
+            checked_add_assign(&mut market.short_basis, legacy.basis)?;
+        }
+        if legacy.token_amount != 0 {
+            user_account.open_positions = user_account
+                .open_positions
+                .checked_add(1)
+                .ok_or(KrunchErrors::MathOverflow)?;
+        }
+        // funding starts from the market's current index, margin is required
+        // at the current price
+        let mut user_position = UserPosition {
+            owner: legacy.owner,
+            market_index: legacy.market_index,
+            token_amount: legacy.token_amount,
+            basis: legacy.basis,
+            pnl: legacy.pnl,
+            fees: legacy.fees,
+            margin_used: legacy.margin_used,
+            rebates: legacy.rebates,
+            initial_margin_required: 0,
+            maintenance_margin_required: 0,
+            funding: 0,
+            last_cumulative_funding: market.cumulative_funding,
+        };
+        user_position.initial_margin_required =
+            calculate_margin_required(&user_position, market.initial_margin, current_price)?;
+        user_position.maintenance_margin_required =
+            calculate_margin_required(&user_position, market.maintenance_margin, current_price)?;
+        checked_add_assign(
+            &mut user_account.initial_margin_required,
+            user_position.initial_margin_required,
+        )?;
+        checked_add_assign(
+            &mut user_account.maintenance_margin_required,
+            user_position.maintenance_margin_required,
+        )?;
+        write_migrated_account(
+            &user_position,
+            &ctx.accounts.user_position,
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+        )
+    }
 
-        execute_claim(user_account, exchange, false)?;
+    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+        let user_account = &mut ctx.accounts.user_account;
+        let exchange = &mut ctx.accounts.exchange;
+        execute_claim(user_account, exchange, true)?;
+        Ok(())
+    }
 
-        user_account.collateral_value += collateral_amount as i64;
-        exchange.collateral_value += collateral_amount as i64;
+    pub fn deposit<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Deposit<'info>>,
+        amount: u64,
+    ) -> Result<()> {
+        // get price
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
 
-        // do token transfer
-        let token_amount = (amount as u128) / 10u128.pow(conversion.into());
+        // inactive collateral is being wound down and only accepts withdrawals
+        if !ctx.accounts.exchange_treasury_position.active {
+            return err!(KrunchErrors::TreasuryPositionInactive);
+        }
 
-        let destination = &ctx.accounts.escrow_account;
-        let source = &ctx.accounts.user_token_account;
-        let token_program = &ctx.accounts.token_program;
-        let authority = &ctx.accounts.owner;
+        let decimals = ctx.accounts.exchange_treasury_position.decimals.into();
+        let token_amount = Decimal::new(amount.into(), AMOUNT_NUM_DECIMALS.into())
+            .to_u64(decimals, Rounding::Up)?;
 
-        let cpi_accounts = SplTransfer {
-            from: source.to_account_info().clone(),
-            to: destination.to_account_info().clone(),
-            authority: authority.to_account_info().clone(),
+        // do token transfer
+        let escrow_amount = ctx.accounts.escrow_account.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.user_token_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.escrow_account.to_account_info(),
+            authority: ctx.accounts.owner.to_account_info(),
         };
-        let cpi_program = token_program.to_account_info();
-        transfer(
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        transfer_checked(
             CpiContext::new(cpi_program, cpi_accounts),
-            token_amount.try_into().unwrap(),
+            token_amount,
+            ctx.accounts.mint.decimals,
+        )?;
+
+        // transfer fee mints deliver less than was sent, only credit what arrived
+        ctx.accounts.escrow_account.reload()?;
+        let token_amount = ctx
+            .accounts
+            .escrow_account
+            .amount
+            .checked_sub(escrow_amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+
+        // update collateral value
+        let user_account = &mut ctx.accounts.user_account;
+        let user_collateral = &mut ctx.accounts.user_collateral;
+        let exchange_treasury_position = &mut ctx.accounts.exchange_treasury_position;
+        let exchange = &mut ctx.accounts.exchange;
+
+        execute_claim(user_account, exchange, false)?;
+        let mut positions = load_open_positions(
+            ctx.remaining_accounts,
+            user_account,
+            exchange,
+            &ctx.accounts.chainlink_program,
+            None,
+            None,
+            Some(user_collateral),
         )?;
+        settle_open_positions(&mut positions, user_account, exchange)?;
+        exit_open_positions(&positions)?;
 
+        user_collateral.owner = ctx.accounts.owner.key();
+        user_collateral.mint = ctx.accounts.mint.key();
+        let collateral_tokens = user_collateral
+            .token_amount
+            .checked_add(token_amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        set_collateral_tokens(
+            user_account,
+            user_collateral,
+            exchange_treasury_position,
+            collateral_tokens,
+        )?;
+        mark_collateral(
+            user_collateral,
+            user_account,
+            exchange,
+            exchange_treasury_position,
+            price,
+        )?;
         Ok(())
     }
 
-    pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+    pub fn withdraw<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Withdraw<'info>>,
+        amount: u64,
+    ) -> Result<()> {
         // get price
-        let round = chainlink::latest_round_data(
-            ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
-        )?;
-        let price_decimals = chainlink::decimals(
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
             ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
-        )?;
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
 
         let user_account = &mut ctx.accounts.user_account;
+        let user_collateral = &mut ctx.accounts.user_collateral;
+        let exchange_treasury_position = &mut ctx.accounts.exchange_treasury_position;
         let exchange = &mut ctx.accounts.exchange;
-        user_account.collateral_value -= amount as i64;
-        exchange.collateral_value -= amount as i64;
+        let mut positions = load_open_positions(
+            ctx.remaining_accounts,
+            user_account,
+            exchange,
+            &ctx.accounts.chainlink_program,
+            None,
+            None,
+            Some(user_collateral),
+        )?;
+        settle_open_positions(&mut positions, user_account, exchange)?;
+
+        // collateral can only be withdrawn from the balance held in this mint
+        let decimals = exchange_treasury_position.decimals.into();
+        let value: i64 = amount
+            .try_into()
+            .map_err(|_| error!(KrunchErrors::MathOverflow))?;
+        let token_amount = to_amount(value)
+            .checked_div(price, decimals, Rounding::Down)?
+            .to_u64(decimals, Rounding::Down)?;
+        let collateral_tokens = user_collateral
+            .token_amount
+            .checked_sub(token_amount)
+            .ok_or(KrunchErrors::CollateralBalanceInsufficient)?;
+        set_collateral_tokens(
+            user_account,
+            user_collateral,
+            exchange_treasury_position,
+            collateral_tokens,
+        )?;
+        mark_collateral(
+            user_collateral,
+            user_account,
+            exchange,
+            exchange_treasury_position,
+            price,
+        )?;
 
         // validate enough funds are available
-        let exchange_total = calculate_exchange_balance_available(&exchange);
-        if exchange_total < 0 {
+        let exchange_total = calculate_exchange_balance_available(exchange)?;
+        if exchange_total.value < 0 {
             return err!(KrunchErrors::ExchangeMarginInsufficient);
         }
 
-        let user_total = calculate_user_total(&user_account, exchange.leverage.into());
-        if user_total < 0 {
+        let user_total = calculate_account_health(user_account, None, None, &positions, false)?;
+        if user_total.value < 0 {
             return err!(KrunchErrors::UserMarginInsufficient);
         }
+        exit_open_positions(&positions)?;
 
         // token transfer
-        let decimals = &ctx.accounts.exchange_treasury_position.decimals;
-        let conversion = AMOUNT_NUM_DECIMALS - decimals;
-        let total = (amount as u128) / 10u128.pow(conversion.into());
-        let token_amount =
-            (total as u128 * 10u128.pow(price_decimals.into())) / round.answer as u128;
         let source = &ctx.accounts.escrow_account;
         let destination = &ctx.accounts.user_token_account;
         let token_program = &ctx.accounts.token_program;
         let authority = &ctx.accounts.exchange;
 
-        let cpi_accounts = SplTransfer {
+        let cpi_accounts = TransferChecked {
             from: source.to_account_info().clone(),
+            mint: ctx.accounts.mint.to_account_info(),
             to: destination.to_account_info().clone(),
             authority: authority.to_account_info().clone(),
         };
@@ -379,281 +1822,2823 @@ pub mod krunch {
         let seeds = &[b"exchange".as_ref(), &[bump]];
         let signer_seeds = &[&seeds[..]];
 
-        transfer(
+        transfer_checked(
             CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds),
-            token_amount.try_into().unwrap(),
+            token_amount,
+            ctx.accounts.mint.decimals,
         )?;
 
         Ok(())
     }
 
-    pub fn get_price(ctx: Context<GetPrice>) -> Result<DataFeed> {
-        let round = chainlink::latest_round_data(
+    pub fn exchange_deposit(ctx: Context<ExchangeTransaction>, amount: u64) -> Result<()> {
+        // get price
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
             ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
-        )?;
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
 
-        let description = chainlink::description(
-            ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
+        if !ctx.accounts.exchange_treasury_position.active {
+            return err!(KrunchErrors::TreasuryPositionInactive);
+        }
+
+        let decimals = ctx.accounts.exchange_treasury_position.decimals.into();
+        let token_amount = Decimal::new(amount.into(), AMOUNT_NUM_DECIMALS.into())
+            .to_u64(decimals, Rounding::Up)?;
+
+        // do token transfer
+        let escrow_amount = ctx.accounts.escrow_account.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.user_token_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.escrow_account.to_account_info(),
+            authority: ctx.accounts.owner.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        transfer_checked(
+            CpiContext::new(cpi_program, cpi_accounts),
+            token_amount,
+            ctx.accounts.mint.decimals,
         )?;
+        ctx.accounts.escrow_account.reload()?;
+        let token_amount = ctx
+            .accounts
+            .escrow_account
+            .amount
+            .checked_sub(escrow_amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
 
-        let decimals = chainlink::decimals(
+        // house collateral is kept apart from user balances
+        let exchange_treasury_position = &mut ctx.accounts.exchange_treasury_position;
+        exchange_treasury_position.house_token_amount = exchange_treasury_position
+            .house_token_amount
+            .checked_add(token_amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        mark_house_collateral(
+            &mut ctx.accounts.exchange,
+            exchange_treasury_position,
+            price,
+        )
+    }
+
+    pub fn exchange_withdraw(ctx: Context<ExchangeTransaction>, amount: u64) -> Result<()> {
+        // deposits share this context and stay open while paused
+        if ctx.accounts.exchange.paused {
+            return err!(KrunchErrors::ExchangePaused);
+        }
+
+        // get price
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
             ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
+
+        let exchange_treasury_position = &mut ctx.accounts.exchange_treasury_position;
+        let exchange = &mut ctx.accounts.exchange;
+
+        // the house can only take back what it deposited and never user tokens
+        let decimals = exchange_treasury_position.decimals.into();
+        let value: i64 = amount
+            .try_into()
+            .map_err(|_| error!(KrunchErrors::MathOverflow))?;
+        let token_amount = to_amount(value)
+            .checked_div(price, decimals, Rounding::Down)?
+            .to_u64(decimals, Rounding::Down)?;
+        let house_tokens = free_escrow_tokens(
+            ctx.accounts.escrow_account.amount,
+            exchange_treasury_position,
+        )
+        .saturating_add(exchange_treasury_position.house_token_amount);
+        if token_amount > exchange_treasury_position.house_token_amount
+            || token_amount > house_tokens
+        {
+            return err!(KrunchErrors::CollateralBalanceInsufficient);
+        }
+        exchange_treasury_position.house_token_amount = exchange_treasury_position
+            .house_token_amount
+            .checked_sub(token_amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        mark_house_collateral(exchange, exchange_treasury_position, price)?;
+
+        // open interest must stay backed after the withdrawal
+        let exchange_total = calculate_exchange_balance_available(exchange)?;
+        if exchange_total.value < 0 {
+            return err!(KrunchErrors::ExchangeMarginInsufficient);
+        }
+
+        // token transfer
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.escrow_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.user_token_account.to_account_info(),
+            authority: ctx.accounts.exchange.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let bump = ctx.bumps.exchange;
+        let seeds = &[b"exchange".as_ref(), &[bump]];
+        let signer_seeds = &[&seeds[..]];
+
+        transfer_checked(
+            CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds),
+            token_amount,
+            ctx.accounts.mint.decimals,
         )?;
-        // write the latest price to the program output
-        let decimal_print = Decimal::new(round.answer, u32::from(decimals));
-        msg!("{} price is {}", description, decimal_print);
-        Ok(DataFeed {
-            round: round.answer,
-            description,
-            decimals: decimals.into(),
-        })
+        Ok(())
     }
 
-    pub fn add_yield_market(
-        ctx: Context<AddYieldMarket>,
-        market_index: u16,
-        chainlink_feed: Pubkey,
+    pub fn initialize_lp_vault(
+        ctx: Context<InitializeLpVault>,
+        cooldown_period: i64,
     ) -> Result<()> {
-        let clock = Clock::get()?;
-        let current_unix_timestamp = clock.unix_timestamp;
+        let lp_vault = &mut ctx.accounts.lp_vault;
+        lp_vault.share_mint = ctx.accounts.share_mint.key();
+        lp_vault.cooldown_period = cooldown_period;
+        lp_vault.withdrawn_pnl = 0;
+        Ok(())
+    }
 
-        let market = &mut ctx.accounts.yield_market;
-        market.market_index = market_index;
-        market.long_basis = 0;
-        market.short_basis = 0;
-        market.long_token_amount = 0;
-        market.short_token_amount = 0;
-        market.long_funding = 0;
-        market.short_funding = 0;
-        market.short_fees = 0;
-        market.long_fees = 0;
-        market.last_claim_date = current_unix_timestamp;
-        market.chainlink_feed = chainlink_feed;
+    pub fn update_lp_vault(ctx: Context<UpdateLpVault>, cooldown_period: i64) -> Result<()> {
+        let lp_vault = &mut ctx.accounts.lp_vault;
+        lp_vault.cooldown_period = cooldown_period;
         Ok(())
     }
 
-    pub fn update_yield(
-        ctx: Context<UpdateYield>,
-        market_index: u16,
-        long_token_amount: i64,
-        short_token_amount: i64,
+    pub fn lp_deposit<'info>(
+        ctx: Context<'_, '_, 'info, 'info, LpDeposit<'info>>,
+        amount: u64,
     ) -> Result<()> {
-        let clock = Clock::get()?;
-        let current_unix_timestamp = clock.unix_timestamp;
-        let yield_market = &mut ctx.accounts.yield_market;
-        let user_yield_position = &mut ctx.accounts.user_yield_position;
-        let exchange = &ctx.accounts.exchange;
+        // get price
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
 
-        if long_token_amount + user_yield_position.long_token_amount < 0 {
-            return err!(KrunchErrors::YieldAmountInsufficient);
-        }
-        if long_token_amount + yield_market.long_token_amount < 0 {
-            return err!(KrunchErrors::YieldAmountInsufficient);
-        }
-        if short_token_amount + user_yield_position.short_token_amount < 0 {
-            return err!(KrunchErrors::YieldAmountInsufficient);
-        }
-        if short_token_amount + yield_market.short_token_amount < 0 {
-            return err!(KrunchErrors::YieldAmountInsufficient);
+        if !ctx.accounts.exchange_treasury_position.active {
+            return err!(KrunchErrors::TreasuryPositionInactive);
         }
 
-        // get price
-        let round = chainlink::latest_round_data(
-            ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
+        let decimals = ctx.accounts.exchange_treasury_position.decimals.into();
+        let token_amount = Decimal::new(amount.into(), AMOUNT_NUM_DECIMALS.into())
+            .to_u64(decimals, Rounding::Up)?;
+
+        // do token transfer
+        let escrow_amount = ctx.accounts.escrow_account.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.user_token_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.escrow_account.to_account_info(),
+            authority: ctx.accounts.owner.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        transfer_checked(
+            CpiContext::new(cpi_program, cpi_accounts),
+            token_amount,
+            ctx.accounts.mint.decimals,
         )?;
-        let price_decimals = chainlink::decimals(
-            ctx.accounts.chainlink_program.to_account_info(),
-            ctx.accounts.chainlink_feed.to_account_info(),
+        ctx.accounts.escrow_account.reload()?;
+        let token_amount = ctx
+            .accounts
+            .escrow_account
+            .amount
+            .checked_sub(escrow_amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+
+        // shares are priced at the vault value before the deposit
+        let house_pnl = calculate_house_unrealized_pnl(
+            ctx.remaining_accounts,
+            &ctx.accounts.exchange,
+            &ctx.accounts.chainlink_program,
         )?;
+        let exchange = &mut ctx.accounts.exchange;
+        let exchange_treasury_position = &mut ctx.accounts.exchange_treasury_position;
+        mark_lp_collateral(exchange, exchange_treasury_position, price)?;
+        let vault_value = calculate_lp_vault_value(exchange, &ctx.accounts.lp_vault, house_pnl)?;
+        let lp_collateral_value = exchange.lp_collateral_value;
+        exchange_treasury_position.lp_token_amount = exchange_treasury_position
+            .lp_token_amount
+            .checked_add(token_amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        mark_lp_collateral(exchange, exchange_treasury_position, price)?;
+        let deposit_value = to_amount(
+            exchange
+                .lp_collateral_value
+                .checked_sub(lp_collateral_value)
+                .ok_or(KrunchErrors::MathOverflow)?,
+        );
 
-        let mut current_price = round.answer;
-        if exchange.test_mode {
-            let clock = Clock::get()?;
-            let current_unix_timestamp = clock.unix_timestamp;
-            let last_digit = current_unix_timestamp % 10;
-            current_price = (current_price as f64 * (1.0 + (last_digit as f64) / 100.0)) as i128;
-        }
-
-        let user_long_basis =
-            (current_price as i128 * long_token_amount as i128) / 10i128.pow(price_decimals.into());
-        let user_short_basis = (current_price as i128 * short_token_amount as i128)
-            / 10i128.pow(price_decimals.into());
-
-        let market_long_basis =
-            (current_price as i128 * long_token_amount as i128) / 10i128.pow(price_decimals.into());
-        let market_short_basis = (current_price as i128 * short_token_amount as i128)
-            / 10i128.pow(price_decimals.into());
-
-        // calculate funding
-        let long_current_value = (current_price as i128 * yield_market.long_token_amount as i128)
-            / 10i128.pow(price_decimals.into());
-        let short_current_value = (current_price as i128 * yield_market.short_token_amount as i128)
-            / 10i128.pow(price_decimals.into());
-        let old_long_basis = yield_market.long_basis + yield_market.long_funding;
-        let old_short_basis = yield_market.short_basis + yield_market.short_funding;
-        let long_pnl = long_current_value - old_long_basis as i128;
-        let short_pnl = old_short_basis as i128 - short_current_value;
-
-        let amount;
-        let max_amount;
-        let elapsed_time: i64 = current_unix_timestamp - yield_market.last_claim_date;
-        let mut long_user_yield_amount = 0;
-        let mut long_yield_amount = 0;
-        let mut short_user_yield_amount = 0;
-        let mut short_yield_amount = 0;
-
-        if long_pnl > short_pnl {
-            amount = long_pnl - short_pnl;
-            if (amount as i64) > old_short_basis {
-                max_amount = old_short_basis.into();
-            } else {
-                max_amount = amount;
-            }
-            if yield_market.long_token_amount > 0 && user_yield_position.long_token_amount > 0 {
-                long_yield_amount =
-                    get_ratio(max_amount as i128, elapsed_time as i128, ONE_YEAR as i128);
-                long_user_yield_amount = get_ratio(
-                    long_yield_amount as i128,
-                    user_yield_position.long_token_amount as i128,
-                    yield_market.long_token_amount as i128,
-                );
-                short_yield_amount = -1 * long_yield_amount;
-                short_user_yield_amount = -1 * long_user_yield_amount;
-            }
+        // new capital must not absorb losses that belong to existing lps
+        let share_supply = ctx.accounts.share_mint.supply;
+        if vault_value.value < 0 || (share_supply > 0 && vault_value.value == 0) {
+            return err!(KrunchErrors::LpVaultInsolvent);
+        }
+        let shares = if share_supply == 0 {
+            deposit_value.to_u64(AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?
         } else {
-            amount = short_pnl - long_pnl;
-            if (amount as i64) > old_long_basis {
-                max_amount = old_long_basis.into();
-            } else {
-                max_amount = amount;
-            }
-            if yield_market.short_token_amount > 0 && user_yield_position.short_token_amount > 0 {
-                short_yield_amount =
-                    get_ratio(max_amount as i128, elapsed_time as i128, ONE_YEAR as i128);
-                short_user_yield_amount = get_ratio(
-                    short_yield_amount as i128,
-                    user_yield_position.short_token_amount as i128,
-                    yield_market.short_token_amount as i128,
-                );
-                long_yield_amount = -1 * short_yield_amount;
-                long_user_yield_amount = -1 * short_user_yield_amount;
-            }
+            let share_supply = Decimal::new(share_supply.into(), AMOUNT_NUM_DECIMALS.into());
+            deposit_value
+                .checked_mul(
+                    share_supply,
+                    deposit_value.decimals + share_supply.decimals,
+                    Rounding::Down,
+                )?
+                .checked_div(vault_value, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?
+                .to_u64(AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?
+        };
+        if shares == 0 {
+            return err!(KrunchErrors::InvalidLpAmount);
         }
-        user_yield_position.long_funding += long_user_yield_amount as i64;
-        user_yield_position.short_funding += short_user_yield_amount as i64;
-        user_yield_position.market_index = market_index;
-        user_yield_position.long_token_amount += long_token_amount;
-        user_yield_position.short_token_amount += short_token_amount;
-        user_yield_position.long_fees = 0;
-        user_yield_position.short_fees = 0;
-        user_yield_position.long_basis += user_long_basis as i64;
-        user_yield_position.short_basis += user_short_basis as i64;
-        user_yield_position.last_claim_date = current_unix_timestamp;
-
-        yield_market.long_funding += long_yield_amount as i64;
-        yield_market.short_funding += short_yield_amount as i64;
-        yield_market.long_fees = 0;
-        yield_market.short_fees = 0;
-        yield_market.long_token_amount += long_token_amount;
-        yield_market.short_token_amount += short_token_amount;
-        yield_market.long_basis += market_long_basis as i64;
-        yield_market.short_basis += market_short_basis as i64;
-        yield_market.last_claim_date = current_unix_timestamp;
-        Ok(())
-    }
 
-    pub fn add_yield(ctx: Context<AddYield>, market_index: u16) -> Result<()> {
-        let user_yield_position = &mut ctx.accounts.user_yield_position;
-        user_yield_position.market_index = market_index;
-        user_yield_position.owner = ctx.accounts.owner.key.to_owned();
+        let bump = ctx.bumps.exchange;
+        let seeds = &[b"exchange".as_ref(), &[bump]];
+        let signer_seeds = &[&seeds[..]];
+
+        // value the vault earned before it had any lps is locked away as shares
+        // nobody can redeem, so the first depositor buys in at the same price as
+        // everyone after them
+        if share_supply == 0 && vault_value.value > 0 {
+            let locked_shares = vault_value.to_u64(AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?;
+            let cpi_accounts = MintTo {
+                mint: ctx.accounts.share_mint.to_account_info(),
+                to: ctx.accounts.share_escrow.to_account_info(),
+                authority: ctx.accounts.exchange.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.share_token_program.to_account_info();
+            mint_to(
+                CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds),
+                locked_shares,
+            )?;
+            msg!("locked {} shares", locked_shares);
+        }
+
+        let cpi_accounts = MintTo {
+            mint: ctx.accounts.share_mint.to_account_info(),
+            to: ctx.accounts.user_share_account.to_account_info(),
+            authority: ctx.accounts.exchange.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.share_token_program.to_account_info();
+        mint_to(
+            CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds),
+            shares,
+        )?;
+        msg!("minted {} shares", shares);
         Ok(())
     }
-}
 
-fn calculate_exchange_balance_available(exchange: &Exchange) -> i128 {
-    let exchange_total = calculate_exchange_total(&exchange);
-    return exchange_total * exchange.market_weight as i128 / MARKET_WEIGHT_DECIMALS as i128
-        + exchange.margin_used as i128;
-}
+    pub fn request_lp_withdrawal(ctx: Context<RequestLpWithdrawal>, shares: u64) -> Result<()> {
+        if shares == 0 {
+            return err!(KrunchErrors::InvalidLpAmount);
+        }
 
-fn calculate_exchange_total(exchange: &Exchange) -> i128 {
-    let exchange_hard_amount = exchange.collateral_value;
-    let exchange_total =
-        exchange_hard_amount as i128 * exchange.leverage as i128 / LEVERAGE_DECIMALS as i128;
-    return exchange_total;
-}
+        // shares stay locked, and exposed to the vault, until the cooldown ends
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.user_share_account.to_account_info(),
+            mint: ctx.accounts.share_mint.to_account_info(),
+            to: ctx.accounts.share_escrow.to_account_info(),
+            authority: ctx.accounts.owner.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.share_token_program.to_account_info();
+        transfer_checked(
+            CpiContext::new(cpi_program, cpi_accounts),
+            shares,
+            ctx.accounts.share_mint.decimals,
+        )?;
 
-fn exchange_rewards_available(exchange: &Exchange) -> i128 {
-    let exchange_total = exchange.pnl + exchange.rewards + exchange.fees + exchange.rebates;
-    if exchange_total < 0 {
-        return 0;
-    } else {
-        return (exchange_total as i128 * exchange.reward_rate as i128) / AMOUNT_DECIMALS as i128;
+        let lp_position = &mut ctx.accounts.lp_position;
+        lp_position.owner = ctx.accounts.owner.key();
+        lp_position.cooldown_shares = lp_position
+            .cooldown_shares
+            .checked_add(shares)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        lp_position.cooldown_start = Clock::get()?.unix_timestamp;
+        Ok(())
     }
-}
 
-fn calculate_market_total(exchange: &Exchange, market: &Market) -> i128 {
-    let exchange_total = calculate_exchange_total(&exchange);
-    let max_market_collateral =
-        (exchange_total as i128 * market.market_weight as i128) / MARKET_WEIGHT_DECIMALS as i128;
-    let market_total = max_market_collateral as i128 + market.margin_used as i128;
-    return market_total;
-}
-fn get_ratio(num1: i128, num: i128, denom: i128) -> i128 {
-    if denom == 0 {
-        return 0;
-    }
-    return ((num1 * num * AMOUNT_DECIMALS as i128) / denom) / AMOUNT_DECIMALS as i128;
-    // return 1;
-}
+    pub fn lp_withdraw<'info>(ctx: Context<'_, '_, 'info, 'info, LpWithdraw<'info>>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let shares = ctx.accounts.lp_position.cooldown_shares;
+        if shares == 0 {
+            return err!(KrunchErrors::InvalidLpAmount);
+        }
+        if now < ctx.accounts.lp_position.cooldown_start + ctx.accounts.lp_vault.cooldown_period {
+            return err!(KrunchErrors::LpCooldownActive);
+        }
 
-fn calculate_user_total(user_account: &UserAccount, leverage: i128) -> i128 {
-    let user_hard_amount = user_account.pnl
-        + user_account.fees
-        + user_account.rebates
-        + user_account.rewards
-        + user_account.collateral_value;
-    let user_total = user_hard_amount as i128 * leverage as i128 / LEVERAGE_DECIMALS as i128
-        + user_account.margin_used as i128;
-    return user_total;
-}
+        // get price
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
 
-fn execute_claim(
-    user_account: &mut UserAccount,
-    exchange: &mut Exchange,
-    throw_error: bool,
-) -> Result<i128> {
-    let clock = Clock::get()?;
-    let current_unix_timestamp = clock.unix_timestamp;
-    if user_account.last_rewards_claim + exchange.reward_frequency as i64 > current_unix_timestamp {
-        if throw_error {
-            return err!(KrunchErrors::RewardsClaimUnavailable);
-        } else {
-            return Ok(0);
+        let house_pnl = calculate_house_unrealized_pnl(
+            ctx.remaining_accounts,
+            &ctx.accounts.exchange,
+            &ctx.accounts.chainlink_program,
+        )?;
+        let exchange = &mut ctx.accounts.exchange;
+        let lp_vault = &mut ctx.accounts.lp_vault;
+        let exchange_treasury_position = &mut ctx.accounts.exchange_treasury_position;
+        mark_lp_collateral(exchange, exchange_treasury_position, price)?;
+
+        // shares are redeemed at the vault value when the cooldown ends
+        let vault_value = calculate_lp_vault_value(exchange, lp_vault, house_pnl)?;
+        let share_supply = Decimal::new(
+            ctx.accounts.share_mint.supply.into(),
+            AMOUNT_NUM_DECIMALS.into(),
+        );
+        let mut value = to_amount(0);
+        if vault_value.value > 0 {
+            let shares = Decimal::new(shares.into(), AMOUNT_NUM_DECIMALS.into());
+            value = shares
+                .checked_mul(
+                    vault_value,
+                    shares.decimals + vault_value.decimals,
+                    Rounding::Down,
+                )?
+                .checked_div(share_supply, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?;
         }
-    }
-    let user_total = calculate_user_total(&user_account, exchange.leverage.into())
-        - user_account.rewards as i128; // don't double count rewards
-    let exchange_total = calculate_exchange_total(&exchange);
-    let exchange_rewards = exchange_rewards_available(&exchange);
-    // get % or rewards available
-    let amount = (exchange_rewards * user_total) / exchange_total;
-    if amount < 0 {
-        if throw_error {
-            return err!(KrunchErrors::NoRewardsAvailable);
-        } else {
-            return Ok(0);
+        let decimals = exchange_treasury_position.decimals.into();
+        let token_amount = value
+            .checked_div(price, decimals, Rounding::Down)?
+            .to_u64(decimals, Rounding::Down)?;
+
+        // lps are paid from their own deposits first, anything beyond that is house
+        // pnl which can only come out of what the house has actually collected
+        let lp_tokens = token_amount.min(exchange_treasury_position.lp_token_amount);
+        let lp_collateral_value = exchange.lp_collateral_value;
+        exchange_treasury_position.lp_token_amount = exchange_treasury_position
+            .lp_token_amount
+            .checked_sub(lp_tokens)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        mark_lp_collateral(exchange, exchange_treasury_position, price)?;
+        let paid_value = from_amount(
+            Decimal::new(token_amount.into(), decimals).checked_mul(
+                price,
+                AMOUNT_NUM_DECIMALS.into(),
+                Rounding::Down,
+            )?,
+            Rounding::Down,
+        )?;
+        let withdrawn_pnl = lp_collateral_value
+            .checked_sub(exchange.lp_collateral_value)
+            .and_then(|lp_paid| paid_value.checked_sub(lp_paid))
+            .ok_or(KrunchErrors::MathOverflow)?
+            .max(0);
+        let available_tokens = free_escrow_tokens(
+            ctx.accounts.escrow_account.amount,
+            exchange_treasury_position,
+        );
+        if token_amount > available_tokens || withdrawn_pnl > exchange.settled_pnl.max(0) {
+            return err!(KrunchErrors::LpVaultLiquidityInsufficient);
         }
-    }
-    exchange.rewards -= amount as i64;
-    user_account.rewards += amount as i64;
-    exchange.last_rewards_claim = current_unix_timestamp;
-    user_account.last_rewards_claim = current_unix_timestamp;
-    return Ok(amount);
+        checked_add_assign(&mut lp_vault.withdrawn_pnl, withdrawn_pnl)?;
+        checked_sub_assign(&mut exchange.settled_pnl, withdrawn_pnl)?;
+
+        // open interest must stay backed after the withdrawal
+        let exchange_total = calculate_exchange_balance_available(exchange)?;
+        if exchange_total.value < 0 {
+            return err!(KrunchErrors::ExchangeMarginInsufficient);
+        }
+        ctx.accounts.lp_position.cooldown_shares = 0;
+
+        let bump = ctx.bumps.exchange;
+        let seeds = &[b"exchange".as_ref(), &[bump]];
+        let signer_seeds = &[&seeds[..]];
+
+        let cpi_accounts = Burn {
+            mint: ctx.accounts.share_mint.to_account_info(),
+            from: ctx.accounts.share_escrow.to_account_info(),
+            authority: ctx.accounts.exchange.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.share_token_program.to_account_info();
+        burn(
+            CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds),
+            shares,
+        )?;
+
+        // token transfer
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.escrow_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            to: ctx.accounts.user_token_account.to_account_info(),
+            authority: ctx.accounts.exchange.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        transfer_checked(
+            CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds),
+            token_amount,
+            ctx.accounts.mint.decimals,
+        )?;
+        msg!("redeemed {} shares for {}", shares, paid_value);
+        Ok(())
+    }
+
+    pub fn initialize_reward_token(
+        ctx: Context<InitializeRewardToken>,
+        vesting_period: i64,
+        emission_budget: u64,
+    ) -> Result<()> {
+        let reward_token = &mut ctx.accounts.reward_token;
+        reward_token.reward_mint = ctx.accounts.reward_mint.key();
+        reward_token.vesting_period = vesting_period;
+        reward_token.emission_budget = emission_budget;
+        reward_token.emitted = 0;
+        Ok(())
+    }
+
+    pub fn update_reward_token(
+        ctx: Context<UpdateRewardToken>,
+        vesting_period: i64,
+        emission_budget: u64,
+    ) -> Result<()> {
+        let reward_token = &mut ctx.accounts.reward_token;
+        // the budget can only shrink down to what has already been emitted
+        if emission_budget < reward_token.emitted {
+            return err!(KrunchErrors::RewardBudgetExhausted);
+        }
+        reward_token.vesting_period = vesting_period;
+        reward_token.emission_budget = emission_budget;
+        Ok(())
+    }
+
+    pub fn vest_rewards<'info>(
+        ctx: Context<'_, '_, 'info, 'info, VestRewards<'info>>,
+    ) -> Result<()> {
+        let user_account = &mut ctx.accounts.user_account;
+        let exchange = &mut ctx.accounts.exchange;
+        execute_claim(user_account, exchange, false)?;
+        let mut positions = load_open_positions(
+            ctx.remaining_accounts,
+            user_account,
+            exchange,
+            &ctx.accounts.chainlink_program,
+            None,
+            None,
+            None,
+        )?;
+        settle_open_positions(&mut positions, user_account, exchange)?;
+        if user_account.rewards <= 0 {
+            return err!(KrunchErrors::NoRewardsAvailable);
+        }
+
+        // rewards stop being a claim on the house and vest as reward tokens instead,
+        // anything still locked vests together with them over a fresh period. the
+        // pool already counted them as paid out, so they do not go back into it
+        let reward_token = &mut ctx.accounts.reward_token;
+        let rewards =
+            u64::try_from(user_account.rewards).map_err(|_| error!(KrunchErrors::MathOverflow))?;
+        let amount = rewards.min(
+            reward_token
+                .emission_budget
+                .saturating_sub(reward_token.emitted),
+        );
+        if amount == 0 {
+            return err!(KrunchErrors::RewardBudgetExhausted);
+        }
+        let now = Clock::get()?.unix_timestamp;
+        let reward_vesting = &mut ctx.accounts.reward_vesting;
+        reward_vesting.owner = ctx.accounts.owner.key();
+        release_vested(reward_vesting, now)?;
+        reward_vesting.locked_amount = reward_vesting
+            .locked_amount
+            .checked_add(amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        reward_vesting.vesting_start = now;
+        reward_vesting.vesting_end = now + reward_token.vesting_period;
+        reward_token.emitted = reward_token
+            .emitted
+            .checked_add(amount)
+            .ok_or(KrunchErrors::MathOverflow)?;
+        checked_sub_assign(
+            &mut user_account.rewards,
+            i64::try_from(amount).map_err(|_| error!(KrunchErrors::MathOverflow))?,
+        )?;
+
+        let user_total = calculate_account_health(user_account, None, None, &positions, false)?;
+        if user_total.value < 0 {
+            return err!(KrunchErrors::UserMarginInsufficient);
+        }
+        exit_open_positions(&positions)?;
+        Ok(())
+    }
+
+    pub fn claim_vested(ctx: Context<ClaimVested>) -> Result<()> {
+        let reward_vesting = &mut ctx.accounts.reward_vesting;
+        release_vested(reward_vesting, Clock::get()?.unix_timestamp)?;
+        let amount = reward_vesting.vested_amount;
+        if amount == 0 {
+            return err!(KrunchErrors::NoVestedRewards);
+        }
+        reward_vesting.vested_amount = 0;
+
+        let cpi_accounts = MintTo {
+            mint: ctx.accounts.reward_mint.to_account_info(),
+            to: ctx.accounts.user_reward_account.to_account_info(),
+            authority: ctx.accounts.exchange.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.reward_token_program.to_account_info();
+        let bump = ctx.bumps.exchange;
+        let seeds = &[b"exchange".as_ref(), &[bump]];
+        let signer_seeds = &[&seeds[..]];
+        mint_to(
+            CpiContext::new_with_signer(cpi_program, cpi_accounts, signer_seeds),
+            amount,
+        )?;
+        msg!("claimed {} vested rewards", amount);
+        Ok(())
+    }
+
+    pub fn get_price(ctx: Context<GetPrice>) -> Result<DataFeed> {
+        let round = chainlink::latest_round_data(
+            ctx.accounts.chainlink_program.to_account_info(),
+            ctx.accounts.chainlink_feed.to_account_info(),
+        )?;
+
+        let description = chainlink::description(
+            ctx.accounts.chainlink_program.to_account_info(),
+            ctx.accounts.chainlink_feed.to_account_info(),
+        )?;
+
+        let decimals = chainlink::decimals(
+            ctx.accounts.chainlink_program.to_account_info(),
+            ctx.accounts.chainlink_feed.to_account_info(),
+        )?;
+        // write the latest price to the program output
+        let decimal_print = Decimal::new(round.answer, u32::from(decimals));
+        msg!("{} price is {}", description, decimal_print);
+        Ok(DataFeed {
+            round: round.answer,
+            description,
+            decimals: decimals.into(),
+        })
+    }
+
+    pub fn add_mock_price(
+        ctx: Context<AddMockPrice>,
+        _mock_index: u16,
+        price: i64,
+        decimals: u8,
+    ) -> Result<()> {
+        let clock = Clock::get()?;
+        let mock_price = &mut ctx.accounts.mock_price;
+        mock_price.price = price;
+        mock_price.decimals = decimals;
+        mock_price.publish_time = clock.unix_timestamp;
+        Ok(())
+    }
+
+    pub fn update_mock_price(
+        ctx: Context<UpdateMockPrice>,
+        _mock_index: u16,
+        price: i64,
+        decimals: u8,
+        publish_time: i64,
+    ) -> Result<()> {
+        let mock_price = &mut ctx.accounts.mock_price;
+        mock_price.price = price;
+        mock_price.decimals = decimals;
+        mock_price.publish_time = publish_time;
+        Ok(())
+    }
+
+    pub fn add_yield_market(
+        ctx: Context<AddYieldMarket>,
+        market_index: u16,
+        feed_address: Pubkey,
+        max_price_age: u32,
+        oracle_source: OracleSource,
+        fixed_price: i64,
+        fee: i16,
+        initial_margin: u16,
+        maintenance_margin: u16,
+        funding_period: i64,
+        max_funding_rate: i64,
+        funding_curve: FundingCurve,
+        kink_utilization: i64,
+        kink_funding_rate: i64,
+    ) -> Result<()> {
+        if maintenance_margin > initial_margin {
+            return err!(KrunchErrors::InvalidMarginRequirement);
+        }
+        let clock = Clock::get()?;
+        let current_unix_timestamp = clock.unix_timestamp;
+
+        let market = &mut ctx.accounts.yield_market;
+        market.market_index = market_index;
+        market.long_basis = 0;
+        market.short_basis = 0;
+        market.long_token_amount = 0;
+        market.short_token_amount = 0;
+        market.long_funding = 0;
+        market.short_funding = 0;
+        market.short_fees = 0;
+        market.long_fees = 0;
+        market.last_claim_date = current_unix_timestamp;
+        market.feed_address = feed_address;
+        market.max_price_age = max_price_age;
+        market.oracle_source = oracle_source;
+        market.fixed_price = fixed_price;
+        market.fee = fee;
+        market.initial_margin = initial_margin;
+        market.maintenance_margin = maintenance_margin;
+        set_yield_funding(
+            market,
+            funding_period,
+            max_funding_rate,
+            funding_curve,
+            kink_utilization,
+            kink_funding_rate,
+        )
+    }
+
+    pub fn update_yield_market(
+        ctx: Context<UpdateYieldMarket>,
+        _market_index: u16,
+        funding_period: i64,
+        max_funding_rate: i64,
+        funding_curve: FundingCurve,
+        kink_utilization: i64,
+        kink_funding_rate: i64,
+    ) -> Result<()> {
+        // funding up to now accrues at the old curve
+        let yield_market = &mut ctx.accounts.yield_market;
+        let current_price = get_oracle_price(
+            yield_market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            &ctx.accounts.exchange,
+            yield_market.fixed_price,
+            yield_market.max_price_age,
+        )?
+        .to_decimal();
+        accrue_yield_funding(
+            yield_market,
+            None,
+            current_price,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        set_yield_funding(
+            yield_market,
+            funding_period,
+            max_funding_rate,
+            funding_curve,
+            kink_utilization,
+            kink_funding_rate,
+        )
+    }
+
+    pub fn update_yield<'info>(
+        ctx: Context<'_, '_, 'info, 'info, UpdateYield<'info>>,
+        market_index: u16,
+        long_token_amount: i64,
+        short_token_amount: i64,
+    ) -> Result<()> {
+        let clock = Clock::get()?;
+        let current_unix_timestamp = clock.unix_timestamp;
+        let yield_market = &mut ctx.accounts.yield_market;
+        let user_yield_position = &mut ctx.accounts.user_yield_position;
+        let user_account = &mut ctx.accounts.user_account;
+        let exchange = &mut ctx.accounts.exchange;
+
+        if long_token_amount
+            .checked_add(user_yield_position.long_token_amount)
+            .ok_or(KrunchErrors::MathOverflow)?
+            < 0
+        {
+            return err!(KrunchErrors::YieldAmountInsufficient);
+        }
+        if long_token_amount
+            .checked_add(yield_market.long_token_amount)
+            .ok_or(KrunchErrors::MathOverflow)?
+            < 0
+        {
+            return err!(KrunchErrors::YieldAmountInsufficient);
+        }
+        if short_token_amount
+            .checked_add(user_yield_position.short_token_amount)
+            .ok_or(KrunchErrors::MathOverflow)?
+            < 0
+        {
+            return err!(KrunchErrors::YieldAmountInsufficient);
+        }
+        if short_token_amount
+            .checked_add(yield_market.short_token_amount)
+            .ok_or(KrunchErrors::MathOverflow)?
+            < 0
+        {
+            return err!(KrunchErrors::YieldAmountInsufficient);
+        }
+
+        // get price
+        let current_price = get_oracle_price(
+            yield_market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            exchange,
+            yield_market.fixed_price,
+            yield_market.max_price_age,
+        )?
+        .to_decimal();
+
+        let long_basis = from_amount(
+            token_value(long_token_amount, current_price, Rounding::Down)?,
+            Rounding::Down,
+        )?;
+        let short_basis = from_amount(
+            token_value(short_token_amount, current_price, Rounding::Down)?,
+            Rounding::Down,
+        )?;
+
+        accrue_yield_funding(
+            yield_market,
+            Some(user_yield_position),
+            current_price,
+            current_unix_timestamp,
+        )?;
+        let was_open = is_yield_position_open(user_yield_position);
+        user_yield_position.market_index = market_index;
+        checked_add_assign(
+            &mut user_yield_position.long_token_amount,
+            long_token_amount,
+        )?;
+        checked_add_assign(
+            &mut user_yield_position.short_token_amount,
+            short_token_amount,
+        )?;
+        checked_add_assign(&mut user_yield_position.long_basis, long_basis)?;
+        checked_add_assign(&mut user_yield_position.short_basis, short_basis)?;
+
+        checked_add_assign(&mut yield_market.long_token_amount, long_token_amount)?;
+        checked_add_assign(&mut yield_market.short_token_amount, short_token_amount)?;
+        checked_add_assign(&mut yield_market.long_basis, long_basis)?;
+        checked_add_assign(&mut yield_market.short_basis, short_basis)?;
+        update_open_yield_positions(user_account, was_open, user_yield_position)?;
+
+        // entries pay the market fee, exits are free
+        let long_fee = calculate_fee(long_token_amount.max(0), current_price, yield_market.fee)?;
+        let short_fee = calculate_fee(short_token_amount.max(0), current_price, yield_market.fee)?;
+        checked_sub_assign(&mut user_yield_position.long_fees, long_fee)?;
+        checked_sub_assign(&mut user_yield_position.short_fees, short_fee)?;
+        checked_add_assign(&mut yield_market.long_fees, long_fee)?;
+        checked_add_assign(&mut yield_market.short_fees, short_fee)?;
+        collect_fee(user_account, exchange, long_fee)?;
+        collect_fee(user_account, exchange, short_fee)?;
+
+        update_yield_margin(
+            user_account,
+            user_yield_position,
+            yield_market,
+            current_price,
+        )?;
+        let mut positions = load_open_positions(
+            ctx.remaining_accounts,
+            user_account,
+            exchange,
+            &ctx.accounts.chainlink_program,
+            None,
+            Some(user_yield_position),
+            None,
+        )?;
+        settle_open_positions(&mut positions, user_account, exchange)?;
+        let user_total = calculate_account_health(
+            user_account,
+            None,
+            Some((user_yield_position, yield_market, current_price)),
+            &positions,
+            false,
+        )?;
+        if user_total.value < 0 {
+            return err!(KrunchErrors::UserMarginInsufficient);
+        }
+        exit_open_positions(&positions)?;
+        Ok(())
+    }
+
+    pub fn claim_yield(ctx: Context<ClaimYield>, _market_index: u16) -> Result<()> {
+        // get prices
+        let exchange = &ctx.accounts.exchange;
+        let yield_market = &ctx.accounts.yield_market;
+        let current_price = get_oracle_price(
+            yield_market.oracle_source,
+            ctx.accounts.yield_price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            exchange,
+            yield_market.fixed_price,
+            yield_market.max_price_age,
+        )?
+        .to_decimal();
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
+
+        let yield_market = &mut ctx.accounts.yield_market;
+        let user_yield_position = &mut ctx.accounts.user_yield_position;
+        let user_account = &mut ctx.accounts.user_account;
+        let exchange = &mut ctx.accounts.exchange;
+        accrue_yield_funding(
+            yield_market,
+            Some(user_yield_position),
+            current_price,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        // funding accrued since the last claim is realized and settled into collateral
+        let funding = user_yield_position
+            .long_funding
+            .checked_add(user_yield_position.short_funding)
+            .and_then(|funding| funding.checked_sub(user_yield_position.settled_funding))
+            .ok_or(KrunchErrors::MathOverflow)?;
+        checked_add_assign(&mut user_yield_position.settled_funding, funding)?;
+        checked_add_assign(&mut user_account.pnl, funding)?;
+        update_yield_margin(
+            user_account,
+            user_yield_position,
+            yield_market,
+            current_price,
+        )?;
+        let user_collateral = &mut ctx.accounts.user_collateral;
+        user_collateral.owner = ctx.accounts.owner.key();
+        user_collateral.mint = ctx.accounts.mint.key();
+        settle_user_pnl(
+            user_account,
+            user_collateral,
+            exchange,
+            &mut ctx.accounts.exchange_treasury_position,
+            ctx.accounts.escrow_account.amount,
+            price,
+        )
+    }
+
+    pub fn close_yield_position(
+        ctx: Context<CloseYieldPosition>,
+        _market_index: u16,
+    ) -> Result<()> {
+        // get prices
+        let exchange = &ctx.accounts.exchange;
+        let yield_market = &ctx.accounts.yield_market;
+        let current_price = get_oracle_price(
+            yield_market.oracle_source,
+            ctx.accounts.yield_price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            exchange,
+            yield_market.fixed_price,
+            yield_market.max_price_age,
+        )?
+        .to_decimal();
+        let price = get_oracle_price(
+            ctx.accounts.exchange_treasury_position.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            exchange,
+            ctx.accounts.exchange_treasury_position.fixed_price,
+            ctx.accounts.exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
+
+        let yield_market = &mut ctx.accounts.yield_market;
+        let user_yield_position = &mut ctx.accounts.user_yield_position;
+        let user_account = &mut ctx.accounts.user_account;
+        let exchange = &mut ctx.accounts.exchange;
+        accrue_yield_funding(
+            yield_market,
+            Some(user_yield_position),
+            current_price,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        close_yield(
+            user_account,
+            user_yield_position,
+            yield_market,
+            current_price,
+        )?;
+        let user_collateral = &mut ctx.accounts.user_collateral;
+        user_collateral.owner = ctx.accounts.owner.key();
+        user_collateral.mint = ctx.accounts.mint.key();
+        settle_user_pnl(
+            user_account,
+            user_collateral,
+            exchange,
+            &mut ctx.accounts.exchange_treasury_position,
+            ctx.accounts.escrow_account.amount,
+            price,
+        )
+    }
+
+    pub fn liquidate_yield<'info>(
+        ctx: Context<'_, '_, 'info, 'info, LiquidateYield<'info>>,
+        _market_index: u16,
+    ) -> Result<()> {
+        let liquidator_account = &mut ctx.accounts.liquidator_account;
+        let user_account = &mut ctx.accounts.user_account;
+        let user_yield_position = &mut ctx.accounts.user_yield_position;
+        let yield_market = &mut ctx.accounts.yield_market;
+        let exchange = &mut ctx.accounts.exchange;
+
+        if !is_yield_position_open(user_yield_position) {
+            return err!(KrunchErrors::InvalidLiquidationAmount);
+        }
+
+        // get price
+        let current_price = get_oracle_price(
+            yield_market.oracle_source,
+            ctx.accounts.price_feed.to_account_info(),
+            ctx.accounts.chainlink_program.to_account_info(),
+            exchange,
+            yield_market.fixed_price,
+            yield_market.max_price_age,
+        )?
+        .to_decimal();
+
+        // accrue funding and mark everything the account holds before checking health
+        accrue_yield_funding(
+            yield_market,
+            Some(user_yield_position),
+            current_price,
+            Clock::get()?.unix_timestamp,
+        )?;
+        let mut positions = load_open_positions(
+            ctx.remaining_accounts,
+            user_account,
+            exchange,
+            &ctx.accounts.chainlink_program,
+            None,
+            Some(user_yield_position),
+            None,
+        )?;
+        settle_open_positions(&mut positions, user_account, exchange)?;
+        let maintenance_total = calculate_account_health(
+            user_account,
+            None,
+            Some((user_yield_position, yield_market, current_price)),
+            &positions,
+            true,
+        )?;
+        if maintenance_total.value >= 0 {
+            return err!(KrunchErrors::UserNotLiquidatable);
+        }
+
+        // the whole position is closed, the fee is charged on both sides' value
+        let close_basis = token_value(
+            user_yield_position
+                .long_token_amount
+                .checked_add(user_yield_position.short_token_amount)
+                .ok_or(KrunchErrors::MathOverflow)?,
+            current_price,
+            Rounding::Up,
+        )?;
+        close_yield(
+            user_account,
+            user_yield_position,
+            yield_market,
+            current_price,
+        )?;
+        let (fee, liquidator_fee) = calculate_liquidation_fee(exchange, close_basis)?;
+        collect_fee(user_account, exchange, fee)?;
+        checked_sub_assign(&mut exchange.rebates, liquidator_fee)?;
+        checked_add_assign(&mut liquidator_account.rebates, liquidator_fee)?;
+
+        cover_bad_debt(user_account, None, exchange)?;
+        exit_open_positions(&positions)?;
+        Ok(())
+    }
+
+    pub fn add_yield(ctx: Context<AddYield>, market_index: u16) -> Result<()> {
+        let user_yield_position = &mut ctx.accounts.user_yield_position;
+        user_yield_position.market_index = market_index;
+        user_yield_position.owner = ctx.accounts.owner.key.to_owned();
+        Ok(())
+    }
+}
+
+fn get_oracle_price<'info>(
+    oracle_source: OracleSource,
+    price_feed: AccountInfo<'info>,
+    chainlink_program: AccountInfo<'info>,
+    exchange: &Exchange,
+    fixed_price: i64,
+    max_price_age: u32,
+) -> Result<OraclePrice> {
+    let clock = Clock::get()?;
+    let oracle_price = match oracle_source {
+        OracleSource::Chainlink => {
+            let round =
+                chainlink::latest_round_data(chainlink_program.clone(), price_feed.clone())?;
+            let decimals = chainlink::decimals(chainlink_program, price_feed)?;
+            OraclePrice {
+                price: round.answer,
+                decimals,
+                publish_time: round.timestamp.into(),
+                confidence: 0,
+            }
+        }
+        OracleSource::PythPush => load_pyth_push_price(&price_feed, &exchange.pyth_program)?,
+        OracleSource::PythPull => load_pyth_pull_price(&price_feed)?,
+        OracleSource::Fixed => OraclePrice {
+            price: fixed_price.into(),
+            decimals: AMOUNT_NUM_DECIMALS,
+            publish_time: clock.unix_timestamp,
+            confidence: 0,
+        },
+        OracleSource::Mock => {
+            // mock prices can only drive markets while the exchange is in test mode
+            if !exchange.test_mode {
+                return err!(KrunchErrors::MockOracleDisabled);
+            }
+            load_mock_price(&price_feed)?
+        }
+    };
+
+    // never trade or value collateral against a frozen or broken feed
+    if oracle_price.price <= 0 {
+        return err!(KrunchErrors::OraclePriceInvalid);
+    }
+    if clock.unix_timestamp - oracle_price.publish_time > i64::from(max_price_age) {
+        return err!(KrunchErrors::OraclePriceStale);
+    }
+    if oracle_price.confidence * FEE_DECIMALS > oracle_price.price as u128 * MAX_ORACLE_CONFIDENCE {
+        return err!(KrunchErrors::OraclePriceUncertain);
+    }
+    Ok(oracle_price)
+}
+
+// balance updates fail with MathOverflow instead of panicking
+fn checked_add_assign(value: &mut i64, amount: i64) -> Result<()> {
+    *value = value
+        .checked_add(amount)
+        .ok_or(KrunchErrors::MathOverflow)?;
+    Ok(())
+}
+
+fn checked_sub_assign(value: &mut i64, amount: i64) -> Result<()> {
+    *value = value
+        .checked_sub(amount)
+        .ok_or(KrunchErrors::MathOverflow)?;
+    Ok(())
+}
+
+fn to_amount(value: i64) -> Decimal {
+    Decimal::new(value.into(), AMOUNT_NUM_DECIMALS.into())
+}
+
+fn from_amount(value: Decimal, rounding: Rounding) -> Result<i64> {
+    value.to_i64(AMOUNT_NUM_DECIMALS.into(), rounding)
+}
+
+// value of a token amount at the given price
+fn token_value(token_amount: i64, price: Decimal, rounding: Rounding) -> Result<Decimal> {
+    to_amount(token_amount).checked_mul(price, AMOUNT_NUM_DECIMALS.into(), rounding)
+}
+
+fn calculate_exchange_balance_available(exchange: &Exchange) -> Result<Decimal> {
+    let exchange_total = calculate_exchange_total(exchange)?;
+    let market_weight = Decimal::new(exchange.market_weight.into(), MARKET_WEIGHT_NUM_DECIMALS);
+    exchange_total
+        .checked_mul(market_weight, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?
+        .checked_add(to_amount(exchange.margin_used))
+}
+
+fn calculate_exchange_total(exchange: &Exchange) -> Result<Decimal> {
+    let exchange_hard_amount = to_amount(exchange.collateral_value)
+        .checked_add(to_amount(exchange.house_collateral_value))?
+        .checked_add(to_amount(exchange.lp_collateral_value))?;
+    let leverage = Decimal::new(exchange.leverage.into(), LEVERAGE_NUM_DECIMALS);
+    exchange_hard_amount.checked_mul(leverage, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)
+}
+
+fn exchange_rewards_available(exchange: &Exchange) -> Result<Decimal> {
+    let exchange_total = to_amount(exchange.pnl)
+        .checked_add(to_amount(exchange.rewards))?
+        .checked_add(to_amount(exchange.fees))?
+        .checked_add(to_amount(exchange.rebates))?;
+    if exchange_total.value < 0 {
+        return Ok(to_amount(0));
+    }
+    let reward_rate = Decimal::new(exchange.reward_rate.into(), AMOUNT_NUM_DECIMALS.into());
+    exchange_total.checked_mul(reward_rate, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)
+}
+
+fn calculate_market_total(exchange: &Exchange, market: &Market) -> Result<Decimal> {
+    let exchange_total = calculate_exchange_total(exchange)?;
+    let market_weight = Decimal::new(market.market_weight.into(), MARKET_WEIGHT_NUM_DECIMALS);
+    exchange_total
+        .checked_mul(market_weight, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?
+        .checked_add(to_amount(market.margin_used))
+}
+
+fn get_ratio(value: Decimal, num: i128, denom: i128) -> Result<Decimal> {
+    if denom == 0 {
+        return Ok(Decimal::new(0, value.decimals));
+    }
+    value
+        .checked_mul(Decimal::new(num, 0), value.decimals, Rounding::Down)?
+        .checked_div(Decimal::new(denom, 0), value.decimals, Rounding::Down)
+}
+
+fn calculate_user_equity(user_account: &UserAccount) -> Result<Decimal> {
+    to_amount(user_account.pnl)
+        .checked_add(to_amount(user_account.fees))?
+        .checked_add(to_amount(user_account.rebates))?
+        .checked_add(to_amount(user_account.rewards))?
+        .checked_add(to_amount(user_account.collateral_value))
+}
+
+// equity with collateral counted at its treasury weight
+fn calculate_user_weighted_equity(user_account: &UserAccount) -> Result<Decimal> {
+    calculate_user_equity(user_account)?
+        .checked_sub(to_amount(user_account.collateral_value))?
+        .checked_add(to_amount(user_account.weighted_collateral_value))
+}
+
+fn settle_user_pnl(
+    user_account: &mut UserAccount,
+    user_collateral: &mut UserCollateral,
+    exchange: &mut Exchange,
+    exchange_treasury_position: &mut ExchangeTreasuryPosition,
+    escrow_amount: u64,
+    price: Decimal,
+) -> Result<()> {
+    let decimals = exchange_treasury_position.decimals.into();
+    mark_collateral(
+        user_collateral,
+        user_account,
+        exchange,
+        exchange_treasury_position,
+        price,
+    )?;
+
+    let realized = user_account
+        .pnl
+        .checked_add(user_account.fees)
+        .and_then(|realized| realized.checked_add(user_account.rebates))
+        .and_then(|realized| realized.checked_add(user_account.rewards))
+        .ok_or(KrunchErrors::MathOverflow)?;
+
+    // pnl settles into the user's balance of the given mint. losses only up to
+    // that balance, profits only up to what the house has collected and holds
+    if realized > 0 {
+        if !exchange_treasury_position.active {
+            return err!(KrunchErrors::TreasuryPositionInactive);
+        }
+        let house_tokens = free_escrow_tokens(escrow_amount, exchange_treasury_position);
+        let token_amount = to_amount(realized.min(exchange.settled_pnl.max(0)))
+            .checked_div(price, decimals, Rounding::Down)?
+            .to_u64(decimals, Rounding::Down)?
+            .min(house_tokens);
+        set_collateral_tokens(
+            user_account,
+            user_collateral,
+            exchange_treasury_position,
+            user_collateral
+                .token_amount
+                .checked_add(token_amount)
+                .ok_or(KrunchErrors::MathOverflow)?,
+        )?;
+    } else {
+        let token_amount = to_amount(realized)
+            .checked_neg()?
+            .checked_div(price, decimals, Rounding::Up)?
+            .to_u64(decimals, Rounding::Up)?
+            .min(user_collateral.token_amount);
+        set_collateral_tokens(
+            user_account,
+            user_collateral,
+            exchange_treasury_position,
+            user_collateral
+                .token_amount
+                .checked_sub(token_amount)
+                .ok_or(KrunchErrors::MathOverflow)?,
+        )?;
+    }
+    let collateral_value = user_collateral.collateral_value;
+    mark_collateral(
+        user_collateral,
+        user_account,
+        exchange,
+        exchange_treasury_position,
+        price,
+    )?;
+    let settled = user_collateral
+        .collateral_value
+        .checked_sub(collateral_value)
+        .ok_or(KrunchErrors::MathOverflow)?;
+
+    user_account.pnl = realized
+        .checked_sub(settled)
+        .ok_or(KrunchErrors::MathOverflow)?;
+    user_account.fees = 0;
+    user_account.rebates = 0;
+    user_account.rewards = 0;
+    checked_sub_assign(&mut exchange.settled_pnl, settled)?;
+    msg!("settled {} of {}", settled, realized);
+    Ok(())
+}
+
+// every mint a user holds must be passed wherever the account's health is checked,
+// so the count is kept in step with the balance
+fn set_collateral_tokens(
+    user_account: &mut UserAccount,
+    user_collateral: &mut UserCollateral,
+    exchange_treasury_position: &mut ExchangeTreasuryPosition,
+    token_amount: u64,
+) -> Result<()> {
+    exchange_treasury_position.token_amount = exchange_treasury_position
+        .token_amount
+        .checked_sub(user_collateral.token_amount)
+        .and_then(|total| total.checked_add(token_amount))
+        .ok_or(KrunchErrors::MathOverflow)?;
+    if user_collateral.token_amount == 0 && token_amount != 0 {
+        user_account.open_collaterals = user_account
+            .open_collaterals
+            .checked_add(1)
+            .ok_or(KrunchErrors::MathOverflow)?;
+    } else if user_collateral.token_amount != 0 && token_amount == 0 {
+        user_account.open_collaterals = user_account
+            .open_collaterals
+            .checked_sub(1)
+            .ok_or(KrunchErrors::MathOverflow)?;
+    }
+    user_collateral.token_amount = token_amount;
+    Ok(())
+}
+
+// escrow tokens not owed to users, lps or the house's own deposits, i.e. the
+// pnl the house has collected in this mint
+fn free_escrow_tokens(
+    escrow_amount: u64,
+    exchange_treasury_position: &ExchangeTreasuryPosition,
+) -> u64 {
+    escrow_amount
+        .saturating_sub(exchange_treasury_position.token_amount)
+        .saturating_sub(exchange_treasury_position.lp_token_amount)
+        .saturating_sub(exchange_treasury_position.house_token_amount)
+}
+
+fn mark_collateral(
+    user_collateral: &mut UserCollateral,
+    user_account: &mut UserAccount,
+    exchange: &mut Exchange,
+    exchange_treasury_position: &ExchangeTreasuryPosition,
+    price: Decimal,
+) -> Result<()> {
+    // collateral is carried at the oracle value of the tokens held, and counts
+    // towards account health at that value times the treasury weight
+    let collateral_value = Decimal::new(
+        user_collateral.token_amount.into(),
+        exchange_treasury_position.decimals.into(),
+    )
+    .checked_mul(price, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?;
+    let treasury_weight = Decimal::new(
+        exchange_treasury_position.treasury_weight.into(),
+        TREASURY_WEIGHT_NUM_DECIMALS,
+    );
+    let weighted_collateral_value = from_amount(
+        collateral_value.checked_mul(
+            treasury_weight,
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Down,
+        )?,
+        Rounding::Down,
+    )?;
+    let collateral_value = from_amount(collateral_value, Rounding::Down)?;
+
+    accrue_rewards(exchange, Clock::get()?.unix_timestamp)?;
+    settle_rewards(user_account, exchange)?;
+    let delta = collateral_value
+        .checked_sub(user_collateral.collateral_value)
+        .ok_or(KrunchErrors::MathOverflow)?;
+    user_collateral.collateral_value = collateral_value;
+    checked_add_assign(&mut user_account.collateral_value, delta)?;
+    checked_add_assign(&mut exchange.collateral_value, delta)?;
+
+    checked_add_assign(
+        &mut user_account.weighted_collateral_value,
+        weighted_collateral_value
+            .checked_sub(user_collateral.weighted_collateral_value)
+            .ok_or(KrunchErrors::MathOverflow)?,
+    )?;
+    user_collateral.weighted_collateral_value = weighted_collateral_value;
+    Ok(())
+}
+
+fn mark_house_collateral(
+    exchange: &mut Exchange,
+    exchange_treasury_position: &mut ExchangeTreasuryPosition,
+    price: Decimal,
+) -> Result<()> {
+    let house_collateral_value = from_amount(
+        Decimal::new(
+            exchange_treasury_position.house_token_amount.into(),
+            exchange_treasury_position.decimals.into(),
+        )
+        .checked_mul(price, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?,
+        Rounding::Down,
+    )?;
+    checked_add_assign(
+        &mut exchange.house_collateral_value,
+        house_collateral_value
+            .checked_sub(exchange_treasury_position.house_collateral_value)
+            .ok_or(KrunchErrors::MathOverflow)?,
+    )?;
+    exchange_treasury_position.house_collateral_value = house_collateral_value;
+    Ok(())
+}
+
+fn mark_lp_collateral(
+    exchange: &mut Exchange,
+    exchange_treasury_position: &mut ExchangeTreasuryPosition,
+    price: Decimal,
+) -> Result<()> {
+    let lp_collateral_value = from_amount(
+        Decimal::new(
+            exchange_treasury_position.lp_token_amount.into(),
+            exchange_treasury_position.decimals.into(),
+        )
+        .checked_mul(price, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?,
+        Rounding::Down,
+    )?;
+    checked_add_assign(
+        &mut exchange.lp_collateral_value,
+        lp_collateral_value
+            .checked_sub(exchange_treasury_position.lp_collateral_value)
+            .ok_or(KrunchErrors::MathOverflow)?,
+    )?;
+    exchange_treasury_position.lp_collateral_value = lp_collateral_value;
+    Ok(())
+}
+
+// lps are the counterparty to every trade, so the vault is worth what they put in
+// plus the house pnl, fees, rebates and the marked house side of open trades,
+// less any pnl already paid out to them
+fn calculate_lp_vault_value(
+    exchange: &Exchange,
+    lp_vault: &LpVault,
+    house_unrealized_pnl: Decimal,
+) -> Result<Decimal> {
+    house_unrealized_pnl
+        .checked_add(to_amount(exchange.lp_collateral_value))?
+        .checked_add(to_amount(exchange.pnl))?
+        .checked_add(to_amount(exchange.fees))?
+        .checked_add(to_amount(exchange.rebates))?
+        .checked_sub(to_amount(lp_vault.withdrawn_pnl))
+}
+
+// every market is passed as a (market, price_feed) pair, the house is short
+// whatever the traders hold and its pnl is the negative of theirs
+fn calculate_house_unrealized_pnl<'info>(
+    remaining_accounts: &'info [AccountInfo<'info>],
+    exchange: &Exchange,
+    chainlink_program: &AccountInfo<'info>,
+) -> Result<Decimal> {
+    let count = usize::from(exchange.number_of_markets);
+    if remaining_accounts.len() < count * 2 {
+        return err!(KrunchErrors::MissingMarketAccounts);
+    }
+
+    let mut market_indexes: Vec<u16> = Vec::with_capacity(count);
+    let mut house_pnl = to_amount(0);
+    for accounts in remaining_accounts[..count * 2].chunks(2) {
+        let market: Account<Market> = Account::try_from(&accounts[0])?;
+        let (market_address, _) = Pubkey::find_program_address(
+            &[
+                b"market".as_ref(),
+                market.market_index.to_le_bytes().as_ref(),
+            ],
+            &crate::ID,
+        );
+        if market.key() != market_address
+            || accounts[1].key() != market.feed_address
+            || market_indexes.contains(&market.market_index)
+        {
+            return err!(KrunchErrors::MissingMarketAccounts);
+        }
+        market_indexes.push(market.market_index);
+
+        let current_price = get_oracle_price(
+            market.oracle_source,
+            accounts[1].clone(),
+            chainlink_program.clone(),
+            exchange,
+            market.fixed_price,
+            market.max_price_age,
+        )?
+        .to_decimal();
+
+        // rounding favours the traders so the vault is never overvalued
+        let long_basis = to_amount(market.basis)
+            .checked_neg()?
+            .checked_sub(to_amount(market.short_basis))?;
+        let traders_pnl = token_value(market.long_open_interest, current_price, Rounding::Up)?
+            .checked_add(long_basis)?
+            .checked_add(token_value(
+                -market.short_open_interest,
+                current_price,
+                Rounding::Up,
+            )?)?
+            .checked_sub(to_amount(market.short_basis))?;
+        house_pnl = house_pnl.checked_sub(traders_pnl)?;
+    }
+    Ok(house_pnl)
+}
+
+fn calculate_position_pnl(user_position: &UserPosition, current_price: Decimal) -> Result<Decimal> {
+    let position_value = token_value(user_position.token_amount, current_price, Rounding::Down)?;
+    if user_position.token_amount >= 0 {
+        position_value.checked_add(to_amount(user_position.basis))
+    } else {
+        position_value.checked_sub(to_amount(user_position.basis))
+    }
+}
+
+// margin a position needs at the market's current requirement and price
+fn calculate_margin_required(
+    user_position: &UserPosition,
+    margin: u16,
+    current_price: Decimal,
+) -> Result<i64> {
+    let margin_used = from_amount(
+        token_value(user_position.token_amount, current_price, Rounding::Down)?.abs()?,
+        Rounding::Up,
+    )?;
+    from_amount(
+        to_amount(margin_used).checked_mul(
+            Decimal::new(margin.into(), MARGIN_NUM_DECIMALS),
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Up,
+        )?,
+        Rounding::Up,
+    )
+}
+
+// requirements cached on the account are replaced by ones recomputed from the
+// current market parameters, and every open position counts its unrealized pnl
+fn calculate_account_health(
+    user_account: &UserAccount,
+    primary: Option<(&UserPosition, &Market, Decimal)>,
+    primary_yield: Option<(&UserYieldPosition, &YieldMarket, Decimal)>,
+    positions: &OpenPositions,
+    maintenance: bool,
+) -> Result<Decimal> {
+    let mut total = calculate_user_weighted_equity(user_account)?;
+    let mut required = to_amount(if maintenance {
+        user_account.maintenance_margin_required
+    } else {
+        user_account.initial_margin_required
+    });
+    let marks = positions
+        .positions
+        .iter()
+        .map(|p| (&*p.user_position, &*p.market, p.price))
+        .chain(primary);
+    for (user_position, market, current_price) in marks {
+        let (cached, margin) = if maintenance {
+            (
+                user_position.maintenance_margin_required,
+                market.maintenance_margin,
+            )
+        } else {
+            (user_position.initial_margin_required, market.initial_margin)
+        };
+        required = required
+            .checked_sub(to_amount(cached))?
+            .checked_add(to_amount(calculate_margin_required(
+                user_position,
+                margin,
+                current_price,
+            )?))?;
+        total = total.checked_add(calculate_position_pnl(user_position, current_price)?)?;
+    }
+    let yield_marks = positions
+        .yield_positions
+        .iter()
+        .map(|p| (&*p.user_yield_position, &*p.yield_market, p.price))
+        .chain(primary_yield);
+    for (user_yield_position, yield_market, current_price) in yield_marks {
+        let (cached, margin) = if maintenance {
+            (
+                user_yield_position.maintenance_margin_required,
+                yield_market.maintenance_margin,
+            )
+        } else {
+            (
+                user_yield_position.initial_margin_required,
+                yield_market.initial_margin,
+            )
+        };
+        required = required
+            .checked_sub(to_amount(cached))?
+            .checked_add(to_amount(calculate_yield_margin_required(
+                user_yield_position,
+                margin,
+                current_price,
+            )?))?;
+        total = total.checked_add(calculate_yield_pnl(user_yield_position, current_price)?)?;
+    }
+    total.checked_sub(required)
+}
+
+fn update_margin_used(
+    user_account: &mut UserAccount,
+    user_position: &mut UserPosition,
+    market: &mut Market,
+    exchange: &mut Exchange,
+    current_price: Decimal,
+) -> Result<()> {
+    let f_margin_basis = token_value(user_position.token_amount, current_price, Rounding::Down)?;
+
+    let margin_used = from_amount(f_margin_basis.abs()?, Rounding::Up)?;
+    let f_delta = user_position
+        .margin_used
+        .checked_abs()
+        .and_then(|margin_used_before| margin_used_before.checked_sub(margin_used))
+        .ok_or(KrunchErrors::MathOverflow)?;
+
+    user_position.margin_used = -margin_used;
+    checked_add_assign(&mut user_account.margin_used, f_delta)?;
+    checked_add_assign(&mut market.margin_used, f_delta)?;
+    checked_add_assign(&mut exchange.margin_used, f_delta)?;
+
+    // each market carries its own initial and maintenance requirement
+    let initial_margin_required =
+        calculate_margin_required(user_position, market.initial_margin, current_price)?;
+    let maintenance_margin_required =
+        calculate_margin_required(user_position, market.maintenance_margin, current_price)?;
+    checked_add_assign(
+        &mut user_account.initial_margin_required,
+        initial_margin_required,
+    )?;
+    checked_sub_assign(
+        &mut user_account.initial_margin_required,
+        user_position.initial_margin_required,
+    )?;
+    checked_add_assign(
+        &mut user_account.maintenance_margin_required,
+        maintenance_margin_required,
+    )?;
+    checked_sub_assign(
+        &mut user_account.maintenance_margin_required,
+        user_position.maintenance_margin_required,
+    )?;
+    user_position.initial_margin_required = initial_margin_required;
+    user_position.maintenance_margin_required = maintenance_margin_required;
+    Ok(())
+}
+
+// the losing side pays its basis times the curve's rate per funding period, where
+// the rate is driven by how much of that basis the pnl difference takes up
+fn calculate_yield_funding(
+    yield_market: &YieldMarket,
+    max_amount: Decimal,
+    basis: Decimal,
+    elapsed_time: i64,
+) -> Result<Decimal> {
+    if basis.value <= 0 || max_amount.value <= 0 {
+        return Ok(to_amount(0));
+    }
+    let utilization = max_amount.checked_div(basis, FUNDING_RATE_NUM_DECIMALS, Rounding::Down)?;
+    let max_funding_rate = Decimal::new(
+        yield_market.max_funding_rate.into(),
+        FUNDING_RATE_NUM_DECIMALS,
+    );
+    let funding_rate = match yield_market.funding_curve {
+        FundingCurve::Linear => utilization,
+        FundingCurve::Capped => {
+            if utilization.value > max_funding_rate.value {
+                max_funding_rate
+            } else {
+                utilization
+            }
+        }
+        FundingCurve::Kinked => {
+            let kink_utilization = yield_market.kink_utilization.into();
+            let kink_funding_rate = yield_market.kink_funding_rate.into();
+            if utilization.value <= kink_utilization {
+                get_ratio(utilization, kink_funding_rate, kink_utilization)?
+            } else {
+                let one = 10i128.pow(FUNDING_RATE_NUM_DECIMALS);
+                get_ratio(
+                    utilization
+                        .checked_sub(Decimal::new(kink_utilization, FUNDING_RATE_NUM_DECIMALS))?,
+                    max_funding_rate.value - kink_funding_rate,
+                    one - kink_utilization,
+                )?
+                .checked_add(Decimal::new(kink_funding_rate, FUNDING_RATE_NUM_DECIMALS))?
+            }
+        }
+    };
+    let funding = get_ratio(
+        basis.checked_mul(funding_rate, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?,
+        elapsed_time.into(),
+        yield_market.funding_period.into(),
+    )?;
+    if funding.value > max_amount.value {
+        return Ok(max_amount);
+    }
+    Ok(funding)
+}
+
+fn set_yield_funding(
+    yield_market: &mut YieldMarket,
+    funding_period: i64,
+    max_funding_rate: i64,
+    funding_curve: FundingCurve,
+    kink_utilization: i64,
+    kink_funding_rate: i64,
+) -> Result<()> {
+    if funding_period <= 0 {
+        return err!(KrunchErrors::InvalidFundingPeriod);
+    }
+    // rates and utilization are fractions with FUNDING_RATE_NUM_DECIMALS
+    let one = 10i64.pow(FUNDING_RATE_NUM_DECIMALS);
+    if max_funding_rate < 0
+        || (funding_curve == FundingCurve::Kinked
+            && (kink_utilization <= 0
+                || kink_utilization >= one
+                || kink_funding_rate < 0
+                || kink_funding_rate > max_funding_rate))
+    {
+        return err!(KrunchErrors::InvalidFundingCurve);
+    }
+    yield_market.funding_period = funding_period;
+    yield_market.max_funding_rate = max_funding_rate;
+    yield_market.funding_curve = funding_curve;
+    yield_market.kink_utilization = kink_utilization;
+    yield_market.kink_funding_rate = kink_funding_rate;
+    Ok(())
+}
+
+// without a user position only the market accrues, which is how parameter
+// changes close out the interval at the old curve
+fn accrue_yield_funding(
+    yield_market: &mut YieldMarket,
+    user_yield_position: Option<&mut UserYieldPosition>,
+    current_price: Decimal,
+    now: i64,
+) -> Result<()> {
+    let long_current_value = token_value(
+        yield_market.long_token_amount,
+        current_price,
+        Rounding::Down,
+    )?;
+    let short_current_value = token_value(
+        yield_market.short_token_amount,
+        current_price,
+        Rounding::Down,
+    )?;
+    let old_long_basis =
+        to_amount(yield_market.long_basis).checked_add(to_amount(yield_market.long_funding))?;
+    let old_short_basis =
+        to_amount(yield_market.short_basis).checked_add(to_amount(yield_market.short_funding))?;
+    let long_pnl = long_current_value.checked_sub(old_long_basis)?;
+    let short_pnl = old_short_basis.checked_sub(short_current_value)?;
+
+    let amount;
+    let max_amount;
+    let elapsed_time: i64 = now - yield_market.last_claim_date;
+    let mut long_user_yield_amount = to_amount(0);
+    let mut long_yield_amount = to_amount(0);
+    let mut short_user_yield_amount = to_amount(0);
+    let mut short_yield_amount = to_amount(0);
+
+    if long_pnl.value > short_pnl.value {
+        amount = long_pnl.checked_sub(short_pnl)?;
+        if amount.value > old_short_basis.value {
+            max_amount = old_short_basis;
+        } else {
+            max_amount = amount;
+        }
+        let holds_long = !matches!(&user_yield_position, Some(p) if p.long_token_amount <= 0);
+        if yield_market.long_token_amount > 0 && holds_long {
+            long_yield_amount =
+                calculate_yield_funding(yield_market, max_amount, old_short_basis, elapsed_time)?;
+            if let Some(user_yield_position) = &user_yield_position {
+                long_user_yield_amount = get_ratio(
+                    long_yield_amount,
+                    user_yield_position.long_token_amount.into(),
+                    yield_market.long_token_amount.into(),
+                )?;
+            }
+            short_yield_amount = long_yield_amount.checked_neg()?;
+            short_user_yield_amount = long_user_yield_amount.checked_neg()?;
+        }
+    } else {
+        amount = short_pnl.checked_sub(long_pnl)?;
+        if amount.value > old_long_basis.value {
+            max_amount = old_long_basis;
+        } else {
+            max_amount = amount;
+        }
+        let holds_short = !matches!(&user_yield_position, Some(p) if p.short_token_amount <= 0);
+        if yield_market.short_token_amount > 0 && holds_short {
+            short_yield_amount =
+                calculate_yield_funding(yield_market, max_amount, old_long_basis, elapsed_time)?;
+            if let Some(user_yield_position) = &user_yield_position {
+                short_user_yield_amount = get_ratio(
+                    short_yield_amount,
+                    user_yield_position.short_token_amount.into(),
+                    yield_market.short_token_amount.into(),
+                )?;
+            }
+            long_yield_amount = short_yield_amount.checked_neg()?;
+            long_user_yield_amount = short_user_yield_amount.checked_neg()?;
+        }
+    }
+    if let Some(user_yield_position) = user_yield_position {
+        checked_add_assign(
+            &mut user_yield_position.long_funding,
+            from_amount(long_user_yield_amount, Rounding::Down)?,
+        )?;
+        checked_add_assign(
+            &mut user_yield_position.short_funding,
+            from_amount(short_user_yield_amount, Rounding::Down)?,
+        )?;
+        user_yield_position.last_claim_date = now;
+    }
+    checked_add_assign(
+        &mut yield_market.long_funding,
+        from_amount(long_yield_amount, Rounding::Down)?,
+    )?;
+    checked_add_assign(
+        &mut yield_market.short_funding,
+        from_amount(short_yield_amount, Rounding::Down)?,
+    )?;
+    yield_market.last_claim_date = now;
+    Ok(())
+}
+
+// both sides of a yield position are margined on their current value
+fn calculate_yield_margin_required(
+    user_yield_position: &UserYieldPosition,
+    margin: u16,
+    current_price: Decimal,
+) -> Result<i64> {
+    let margin_basis = token_value(
+        user_yield_position
+            .long_token_amount
+            .checked_add(user_yield_position.short_token_amount)
+            .ok_or(KrunchErrors::MathOverflow)?,
+        current_price,
+        Rounding::Up,
+    )?;
+    from_amount(
+        margin_basis.checked_mul(
+            Decimal::new(margin.into(), MARGIN_NUM_DECIMALS),
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Up,
+        )?,
+        Rounding::Up,
+    )
+}
+
+fn update_yield_margin(
+    user_account: &mut UserAccount,
+    user_yield_position: &mut UserYieldPosition,
+    yield_market: &YieldMarket,
+    current_price: Decimal,
+) -> Result<()> {
+    let initial_margin_required = calculate_yield_margin_required(
+        user_yield_position,
+        yield_market.initial_margin,
+        current_price,
+    )?;
+    let maintenance_margin_required = calculate_yield_margin_required(
+        user_yield_position,
+        yield_market.maintenance_margin,
+        current_price,
+    )?;
+    checked_add_assign(
+        &mut user_account.initial_margin_required,
+        initial_margin_required,
+    )?;
+    checked_sub_assign(
+        &mut user_account.initial_margin_required,
+        user_yield_position.initial_margin_required,
+    )?;
+    checked_add_assign(
+        &mut user_account.maintenance_margin_required,
+        maintenance_margin_required,
+    )?;
+    checked_sub_assign(
+        &mut user_account.maintenance_margin_required,
+        user_yield_position.maintenance_margin_required,
+    )?;
+    user_yield_position.initial_margin_required = initial_margin_required;
+    user_yield_position.maintenance_margin_required = maintenance_margin_required;
+    Ok(())
+}
+
+// both sides against their basis along with any funding not yet claimed
+fn calculate_yield_pnl(
+    user_yield_position: &UserYieldPosition,
+    current_price: Decimal,
+) -> Result<Decimal> {
+    let long_pnl = token_value(
+        user_yield_position.long_token_amount,
+        current_price,
+        Rounding::Down,
+    )?
+    .checked_sub(to_amount(user_yield_position.long_basis))?;
+    let short_pnl = to_amount(user_yield_position.short_basis).checked_sub(token_value(
+        user_yield_position.short_token_amount,
+        current_price,
+        Rounding::Up,
+    )?)?;
+    let funding = user_yield_position
+        .long_funding
+        .checked_add(user_yield_position.short_funding)
+        .and_then(|funding| funding.checked_sub(user_yield_position.settled_funding))
+        .ok_or(KrunchErrors::MathOverflow)?;
+    long_pnl
+        .checked_add(short_pnl)?
+        .checked_add(to_amount(funding))
+}
+
+fn is_yield_position_open(user_yield_position: &UserYieldPosition) -> bool {
+    user_yield_position.long_token_amount != 0 || user_yield_position.short_token_amount != 0
+}
+
+// every open yield position must be passed wherever the account's health is checked
+fn update_open_yield_positions(
+    user_account: &mut UserAccount,
+    was_open: bool,
+    user_yield_position: &UserYieldPosition,
+) -> Result<()> {
+    let is_open = is_yield_position_open(user_yield_position);
+    if !was_open && is_open {
+        user_account.open_yield_positions = user_account
+            .open_yield_positions
+            .checked_add(1)
+            .ok_or(KrunchErrors::MathOverflow)?;
+    } else if was_open && !is_open {
+        user_account.open_yield_positions = user_account
+            .open_yield_positions
+            .checked_sub(1)
+            .ok_or(KrunchErrors::MathOverflow)?;
+    }
+    Ok(())
+}
+
+// realizes the whole position into the account's pnl and takes it out of the market
+fn close_yield(
+    user_account: &mut UserAccount,
+    user_yield_position: &mut UserYieldPosition,
+    yield_market: &mut YieldMarket,
+    current_price: Decimal,
+) -> Result<()> {
+    let pnl = calculate_yield_pnl(user_yield_position, current_price)?;
+    checked_add_assign(&mut user_account.pnl, from_amount(pnl, Rounding::Down)?)?;
+
+    checked_sub_assign(
+        &mut yield_market.long_token_amount,
+        user_yield_position.long_token_amount,
+    )?;
+    checked_sub_assign(
+        &mut yield_market.short_token_amount,
+        user_yield_position.short_token_amount,
+    )?;
+    checked_sub_assign(&mut yield_market.long_basis, user_yield_position.long_basis)?;
+    checked_sub_assign(
+        &mut yield_market.short_basis,
+        user_yield_position.short_basis,
+    )?;
+    checked_sub_assign(
+        &mut yield_market.long_funding,
+        user_yield_position.long_funding,
+    )?;
+    checked_sub_assign(
+        &mut yield_market.short_funding,
+        user_yield_position.short_funding,
+    )?;
+
+    let was_open = is_yield_position_open(user_yield_position);
+    user_yield_position.long_token_amount = 0;
+    user_yield_position.short_token_amount = 0;
+    user_yield_position.long_basis = 0;
+    user_yield_position.short_basis = 0;
+    user_yield_position.long_funding = 0;
+    user_yield_position.short_funding = 0;
+    user_yield_position.settled_funding = 0;
+    update_open_yield_positions(user_account, was_open, user_yield_position)?;
+    update_yield_margin(
+        user_account,
+        user_yield_position,
+        yield_market,
+        current_price,
+    )
+}
+
+fn update_position(
+    user_account: &mut UserAccount,
+    user_position: &mut UserPosition,
+    market: &mut Market,
+    exchange: &mut Exchange,
+    amount: i64,
+    current_price: Decimal,
+) -> Result<()> {
+    // update balances
+    let basis_before = user_position.basis;
+    let token_amount_before = user_position.token_amount;
+
+    let mut token_delta = 0;
+    if (user_position.token_amount < 0 && amount > 0)
+        || (user_position.token_amount > 0 && amount < 0)
+    {
+        token_delta = user_position
+            .token_amount
+            .checked_abs()
+            .zip(amount.checked_abs())
+            .map(|(position, amount)| position.min(amount))
+            .ok_or(KrunchErrors::MathOverflow)?;
+    }
+    checked_sub_assign(&mut market.token_amount, amount)?;
+    checked_add_assign(&mut user_position.token_amount, amount)?;
+
+    // every open position must be passed wherever the account's funding or health is checked
+    if token_amount_before == 0 && user_position.token_amount != 0 {
+        user_account.open_positions = user_account
+            .open_positions
+            .checked_add(1)
+            .ok_or(KrunchErrors::MathOverflow)?;
+    } else if token_amount_before != 0 && user_position.token_amount == 0 {
+        user_account.open_positions = user_account
+            .open_positions
+            .checked_sub(1)
+            .ok_or(KrunchErrors::MathOverflow)?;
+    }
+
+    // update open interest
+    if token_amount_before > 0 {
+        checked_sub_assign(&mut market.long_open_interest, token_amount_before)?;
+    } else {
+        checked_add_assign(&mut market.short_open_interest, token_amount_before)?;
+    }
+    if user_position.token_amount > 0 {
+        checked_add_assign(&mut market.long_open_interest, user_position.token_amount)?;
+    } else {
+        checked_sub_assign(&mut market.short_open_interest, user_position.token_amount)?;
+    }
+
+    // update collateral value
+    update_margin_used(user_account, user_position, market, exchange, current_price)?;
+
+    if token_delta != 0 {
+        // rounding always goes against the closing side
+        let (entry_rounding, exit_rounding) = if token_amount_before > 0 {
+            (Rounding::Up, Rounding::Down)
+        } else {
+            (Rounding::Down, Rounding::Up)
+        };
+        // the closed tokens take their share of the entry basis
+        let entry_basis = to_amount(basis_before).abs()?;
+        let abasis = from_amount(
+            entry_basis
+                .checked_mul(
+                    to_amount(token_delta),
+                    entry_basis.decimals + u32::from(AMOUNT_NUM_DECIMALS),
+                    Rounding::Down,
+                )?
+                .checked_div(
+                    to_amount(token_amount_before).abs()?,
+                    AMOUNT_NUM_DECIMALS.into(),
+                    entry_rounding,
+                )?,
+            entry_rounding,
+        )?;
+        let tbasis = from_amount(
+            token_value(token_delta, current_price, exit_rounding)?,
+            exit_rounding,
+        )?;
+
+        // longs profit when the closing value exceeds the entry basis, shorts the reverse
+        let pnl = if token_amount_before > 0 {
+            tbasis.checked_sub(abasis)
+        } else {
+            abasis.checked_sub(tbasis)
+        }
+        .ok_or(KrunchErrors::MathOverflow)?;
+        let basis_adjustment = -abasis;
+
+        checked_sub_assign(&mut user_position.basis, basis_adjustment)?;
+        checked_add_assign(&mut user_position.pnl, pnl)?;
+
+        checked_sub_assign(&mut user_account.basis, basis_adjustment)?;
+        checked_add_assign(&mut user_account.pnl, pnl)?;
+
+        checked_add_assign(&mut market.basis, basis_adjustment)?;
+        checked_sub_assign(&mut market.pnl, pnl)?;
+
+        checked_add_assign(&mut exchange.basis, basis_adjustment)?;
+        checked_sub_assign(&mut exchange.pnl, pnl)?;
+    }
+
+    // update token basis, longs pay up and shorts receive less
+    let position_increase = amount
+        .checked_abs()
+        .and_then(|amount| amount.checked_sub(token_delta))
+        .ok_or(KrunchErrors::MathOverflow)?;
+    let rounding = if amount > 0 {
+        Rounding::Up
+    } else {
+        Rounding::Down
+    };
+    let basis_increase = from_amount(
+        token_value(position_increase, current_price, rounding)?,
+        rounding,
+    )?;
+
+    checked_sub_assign(&mut user_position.basis, basis_increase)?;
+    checked_sub_assign(&mut user_account.basis, basis_increase)?;
+    checked_add_assign(&mut market.basis, basis_increase)?;
+    checked_add_assign(&mut exchange.basis, basis_increase)?;
+
+    // short basis is tracked apart so the house exposure can be marked to market
+    if token_amount_before < 0 {
+        checked_sub_assign(&mut market.short_basis, basis_before)?;
+    }
+    if user_position.token_amount < 0 {
+        checked_add_assign(&mut market.short_basis, user_position.basis)?;
+    }
+    Ok(())
+}
+
+struct Exposure {
+    long_open_interest: i64,
+    short_open_interest: i64,
+    position_size: i64,
+}
+
+impl Exposure {
+    fn new(market: &Market, user_position: &UserPosition) -> Self {
+        Exposure {
+            long_open_interest: market.long_open_interest,
+            short_open_interest: market.short_open_interest,
+            position_size: user_position.token_amount.abs(),
+        }
+    }
+
+    // caps only block trades that add exposure so positions can always be reduced
+    fn validate(&self, market: &Market, user_position: &UserPosition) -> Result<()> {
+        if market.long_open_interest > self.long_open_interest
+            && market.long_open_interest > market.max_long_open_interest
+        {
+            return err!(KrunchErrors::MaxLongOpenInterestExceeded);
+        }
+        if market.short_open_interest > self.short_open_interest
+            && market.short_open_interest > market.max_short_open_interest
+        {
+            return err!(KrunchErrors::MaxShortOpenInterestExceeded);
+        }
+        let position_size = user_position.token_amount.abs();
+        if position_size > self.position_size && position_size > market.max_position_size {
+            return err!(KrunchErrors::MaxPositionSizeExceeded);
+        }
+        Ok(())
+    }
+}
+
+// an override replaces the market fees, otherwise the user's volume tier
+// applies wherever it is cheaper than the market
+fn get_fee_rates(
+    market: &Market,
+    user_account: &UserAccount,
+    fee_tiers: Option<&FeeTiers>,
+    fee_override: Option<&UserFeeOverride>,
+) -> (i16, i16) {
+    if let Some(fee_override) = fee_override {
+        return (fee_override.maker_fee, fee_override.taker_fee);
+    }
+    match fee_tiers.and_then(|fee_tiers| fee_tiers.find(user_account.volume_30d)) {
+        Some(tier) => (
+            market.maker_fee.min(tier.maker_fee),
+            market.taker_fee.min(tier.taker_fee),
+        ),
+        None => (market.maker_fee, market.taker_fee),
+    }
+}
+
+fn is_within_price_band(price: Decimal, oracle_price: Decimal, price_band: u16) -> Result<bool> {
+    let price_band = Decimal::new(price_band.into(), FEE_NUM_DECIMALS);
+    let max_deviation =
+        oracle_price.checked_mul(price_band, oracle_price.decimals, Rounding::Down)?;
+    let deviation = price.checked_sub(oracle_price)?.abs()?;
+    Ok(deviation.checked_sub(max_deviation)?.value <= 0)
+}
+
+fn update_volume(
+    user_account: &mut UserAccount,
+    amount: i64,
+    price: Decimal,
+    now: i64,
+) -> Result<()> {
+    // rolling volume decays linearly over the window
+    let elapsed_time = (now - user_account.last_volume_update).max(0);
+    let remaining_time = (VOLUME_WINDOW - elapsed_time).max(0);
+    let volume = to_amount(user_account.volume_30d)
+        .checked_mul(
+            Decimal::new(remaining_time.into(), 0),
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Down,
+        )?
+        .checked_div(
+            Decimal::new(VOLUME_WINDOW.into(), 0),
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Down,
+        )?
+        .checked_add(token_value(amount, price, Rounding::Down)?.abs()?)?;
+    user_account.volume_30d = from_amount(volume, Rounding::Down)?;
+    user_account.last_volume_update = now;
+    Ok(())
+}
+
+fn calculate_fee(amount: i64, price: Decimal, fee_rate: i16) -> Result<i64> {
+    // fees round up and rebates round down
+    let basis = token_value(amount, price, Rounding::Up)?.abs()?;
+    let fee_rate = Decimal::new(fee_rate.into(), FEE_NUM_DECIMALS);
+    from_amount(
+        basis.checked_mul(fee_rate, AMOUNT_NUM_DECIMALS.into(), Rounding::Up)?,
+        Rounding::Up,
+    )
+}
+
+// referees get a discount and referrers a share of the discounted fee,
+// the share is paid by the exchange like a rebate once claimed
+fn apply_referral(
+    fee: i64,
+    market: &mut Market,
+    exchange: &mut Exchange,
+    referrer: &mut Referrer,
+) -> Result<i64> {
+    if fee <= 0 {
+        return Ok(fee);
+    }
+    let referee_fee_discount = Decimal::new(exchange.referee_fee_discount.into(), FEE_NUM_DECIMALS);
+    let discount = from_amount(
+        to_amount(fee).checked_mul(
+            referee_fee_discount,
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Down,
+        )?,
+        Rounding::Down,
+    )?;
+    let fee = fee
+        .checked_sub(discount)
+        .ok_or(KrunchErrors::MathOverflow)?;
+    let referrer_fee_share = Decimal::new(exchange.referrer_fee_share.into(), FEE_NUM_DECIMALS);
+    let share = from_amount(
+        to_amount(fee).checked_mul(
+            referrer_fee_share,
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Down,
+        )?,
+        Rounding::Down,
+    )?;
+    checked_sub_assign(&mut exchange.rebates, share)?;
+    checked_sub_assign(&mut market.rebates, share)?;
+    checked_add_assign(&mut referrer.referral_fees, share)?;
+    checked_add_assign(&mut referrer.total_referral_fees, share)?;
+    Ok(fee)
+}
+
+fn charge_fee(
+    user_account: &mut UserAccount,
+    user_position: &mut UserPosition,
+    market: &mut Market,
+    exchange: &mut Exchange,
+    fee: i64,
+) -> Result<()> {
+    // negative fees are rebates paid out by the exchange
+    if fee < 0 {
+        checked_add_assign(&mut exchange.rebates, fee)?;
+        checked_add_assign(&mut market.rebates, fee)?;
+        checked_sub_assign(&mut user_account.rebates, fee)?;
+        checked_sub_assign(&mut user_position.rebates, fee)?;
+    } else {
+        checked_add_assign(&mut market.fees, fee)?;
+        checked_sub_assign(&mut user_position.fees, fee)?;
+        collect_fee(user_account, exchange, fee)?;
+    }
+    Ok(())
+}
+
+fn collect_fee(user_account: &mut UserAccount, exchange: &mut Exchange, fee: i64) -> Result<()> {
+    checked_sub_assign(&mut user_account.fees, fee)?;
+
+    // the insurance share is not house revenue, so rewards and lps never count it
+    let insurance_fee_share = Decimal::new(exchange.insurance_fee_share.into(), FEE_NUM_DECIMALS);
+    let insurance_fee = from_amount(
+        to_amount(fee).checked_mul(
+            insurance_fee_share,
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Down,
+        )?,
+        Rounding::Down,
+    )?;
+    checked_add_assign(&mut exchange.fees, fee)?;
+    checked_sub_assign(&mut exchange.fees, insurance_fee)?;
+    checked_add_assign(&mut exchange.insurance_fees_pending, insurance_fee)
+}
+
+// the liquidation fee on the closed value and the liquidator's share of it
+fn calculate_liquidation_fee(exchange: &Exchange, close_basis: Decimal) -> Result<(i64, i64)> {
+    let liquidation_fee = Decimal::new(exchange.liquidation_fee.into(), FEE_NUM_DECIMALS);
+    let liquidator_share = Decimal::new(exchange.liquidator_share.into(), FEE_NUM_DECIMALS);
+    let fee = from_amount(
+        close_basis.checked_mul(liquidation_fee, AMOUNT_NUM_DECIMALS.into(), Rounding::Up)?,
+        Rounding::Up,
+    )?;
+    let liquidator_fee = from_amount(
+        to_amount(fee).checked_mul(liquidator_share, AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?,
+        Rounding::Down,
+    )?;
+    Ok((fee, liquidator_fee))
+}
+
+fn cover_bad_debt(
+    user_account: &mut UserAccount,
+    market: Option<&mut Market>,
+    exchange: &mut Exchange,
+) -> Result<()> {
+    // only a fully closed account can have its shortfall written off
+    let equity = calculate_user_equity(user_account)?;
+    if user_account.margin_used != 0 || user_account.open_yield_positions != 0 || equity.value >= 0
+    {
+        return Ok(());
+    }
+    let bad_debt = from_amount(equity.checked_neg()?, Rounding::Up)?;
+
+    // the insurance fund pays the house for the shortfall first, the tokens
+    // move from the insurance vault into escrow with pay_insurance_claim
+    let covered = bad_debt.min(exchange.insurance_fund_value.max(0));
+    checked_sub_assign(&mut exchange.insurance_fund_value, covered)?;
+    checked_add_assign(&mut exchange.insurance_claims_pending, covered)?;
+    checked_add_assign(&mut user_account.pnl, covered)?;
+
+    // whatever the fund cannot cover is socialized as a loss of the market the
+    // account was liquidated in, or of the exchange for yield markets, which is
+    // carried by whoever backs the exchange's side of every trade
+    let shortfall = bad_debt
+        .checked_sub(covered)
+        .ok_or(KrunchErrors::MathOverflow)?;
+    if shortfall > 0 {
+        if let Some(market) = market {
+            checked_sub_assign(&mut market.pnl, shortfall)?;
+        }
+        checked_sub_assign(&mut exchange.pnl, shortfall)?;
+        checked_add_assign(&mut exchange.socialized_loss, shortfall)?;
+        checked_add_assign(&mut user_account.pnl, shortfall)?;
+    }
+    msg!(
+        "bad debt {} covered {} socialized {}",
+        bad_debt,
+        covered,
+        shortfall
+    );
+    Ok(())
+}
+
+fn accrue_funding(market: &mut Market, current_price: Decimal, now: i64) -> Result<()> {
+    let elapsed_time = now - market.last_funding_time;
+    if elapsed_time <= 0 || market.funding_period <= 0 {
+        return Ok(());
+    }
+    // funding owed per whole token over the elapsed time
+    let funding_rate = Decimal::new(market.funding_rate.into(), FUNDING_RATE_NUM_DECIMALS);
+    let funding = current_price
+        .checked_mul(
+            funding_rate,
+            current_price.decimals + FUNDING_RATE_NUM_DECIMALS,
+            Rounding::Down,
+        )?
+        .checked_mul(
+            Decimal::new(elapsed_time.into(), 0),
+            current_price.decimals + FUNDING_RATE_NUM_DECIMALS,
+            Rounding::Down,
+        )?
+        .checked_div(
+            Decimal::new(market.funding_period.into(), 0),
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Down,
+        )?;
+    checked_add_assign(
+        &mut market.cumulative_funding,
+        from_amount(funding, Rounding::Down)?,
+    )?;
+    market.last_funding_time = now;
+    Ok(())
+}
+
+fn settle_funding(
+    user_account: &mut UserAccount,
+    user_position: &mut UserPosition,
+    market: &mut Market,
+    exchange: &mut Exchange,
+) -> Result<()> {
+    // longs pay shorts when the funding rate is positive
+    let funding_delta = market
+        .cumulative_funding
+        .checked_sub(user_position.last_cumulative_funding)
+        .ok_or(KrunchErrors::MathOverflow)?;
+    let funding = from_amount(
+        to_amount(user_position.token_amount)
+            .checked_neg()?
+            .checked_mul(
+                to_amount(funding_delta),
+                AMOUNT_NUM_DECIMALS.into(),
+                Rounding::Down,
+            )?,
+        Rounding::Down,
+    )?;
+    user_position.last_cumulative_funding = market.cumulative_funding;
+    if funding == 0 {
+        return Ok(());
+    }
+    checked_add_assign(&mut user_position.funding, funding)?;
+    checked_add_assign(&mut user_position.pnl, funding)?;
+    checked_add_assign(&mut user_account.pnl, funding)?;
+    checked_sub_assign(&mut market.pnl, funding)?;
+    checked_sub_assign(&mut exchange.pnl, funding)?;
+    Ok(())
+}
+
+// the account's other open positions are passed as (user_position, market,
+// price_feed) triples, all of them must be present so none can be left out
+struct OpenPosition<'info> {
+    user_position: Account<'info, UserPosition>,
+    market: Account<'info, Market>,
+    price: Decimal,
+}
+
+struct OpenYieldPosition<'info> {
+    user_yield_position: Account<'info, UserYieldPosition>,
+    yield_market: Account<'info, YieldMarket>,
+    price: Decimal,
+}
+
+struct OpenCollateral<'info> {
+    user_collateral: Account<'info, UserCollateral>,
+    exchange_treasury_position: Account<'info, ExchangeTreasuryPosition>,
+    price: Decimal,
+}
+
+// everything the account holds besides what the instruction itself works on
+struct OpenPositions<'info> {
+    positions: Vec<OpenPosition<'info>>,
+    yield_positions: Vec<OpenYieldPosition<'info>>,
+    collaterals: Vec<OpenCollateral<'info>>,
+}
+
+impl OpenPositions<'_> {
+    // remaining accounts taken up, anything after them belongs to the instruction
+    fn account_count(&self) -> usize {
+        (self.positions.len() + self.yield_positions.len() + self.collaterals.len()) * 3
+    }
+}
+
+fn count_open(open: u16, excluded_open: bool) -> Result<usize> {
+    Ok(usize::from(
+        open.checked_sub(u16::from(excluded_open))
+            .ok_or(KrunchErrors::MathOverflow)?,
+    ))
+}
+
+// open positions come first as (user_position, market, price_feed) triples,
+// then open yield positions as (user_yield_position, yield_market, price_feed)
+// triples, followed by every mint held as (user_collateral,
+// exchange_treasury_position, price_feed) triples
+fn load_open_positions<'info>(
+    remaining_accounts: &'info [AccountInfo<'info>],
+    user_account: &UserAccount,
+    exchange: &Exchange,
+    chainlink_program: &AccountInfo<'info>,
+    excluded: Option<&UserPosition>,
+    excluded_yield: Option<&UserYieldPosition>,
+    excluded_collateral: Option<&UserCollateral>,
+) -> Result<OpenPositions<'info>> {
+    let position_count = count_open(
+        user_account.open_positions,
+        excluded.is_some_and(|p| p.token_amount != 0),
+    )?;
+    let yield_count = count_open(
+        user_account.open_yield_positions,
+        excluded_yield.is_some_and(is_yield_position_open),
+    )?;
+    let collateral_count = count_open(
+        user_account.open_collaterals,
+        excluded_collateral.is_some_and(|c| c.token_amount != 0),
+    )?;
+    if remaining_accounts.len() < (position_count + yield_count + collateral_count) * 3 {
+        return err!(KrunchErrors::MissingOpenPositions);
+    }
+    let (position_accounts, remaining_accounts) = remaining_accounts.split_at(position_count * 3);
+    let (yield_accounts, remaining_accounts) = remaining_accounts.split_at(yield_count * 3);
+
+    let mut positions: Vec<OpenPosition> = Vec::with_capacity(position_count);
+    for accounts in position_accounts.chunks(3) {
+        let user_position: Account<UserPosition> = Account::try_from(&accounts[0])?;
+        let market: Account<Market> = Account::try_from(&accounts[1])?;
+        let market_index = user_position.market_index;
+        let seed_index = market_index.to_le_bytes();
+        let (position_address, _) = Pubkey::find_program_address(
+            &[
+                b"user_position".as_ref(),
+                user_account.owner.as_ref(),
+                seed_index.as_ref(),
+            ],
+            &crate::ID,
+        );
+        let (market_address, _) =
+            Pubkey::find_program_address(&[b"market".as_ref(), seed_index.as_ref()], &crate::ID);
+        if user_position.key() != position_address
+            || market.key() != market_address
+            || accounts[2].key() != market.feed_address
+        {
+            return err!(KrunchErrors::InvalidPositionAccounts);
+        }
+
+        // flat or repeated positions would stand in for an open one
+        if user_position.token_amount == 0
+            || excluded.is_some_and(|p| p.market_index == market_index)
+            || positions
+                .iter()
+                .any(|p| p.user_position.market_index == market_index)
+        {
+            return err!(KrunchErrors::MissingOpenPositions);
+        }
+
+        let price = get_oracle_price(
+            market.oracle_source,
+            accounts[2].clone(),
+            chainlink_program.clone(),
+            exchange,
+            market.fixed_price,
+            market.max_price_age,
+        )?
+        .to_decimal();
+        positions.push(OpenPosition {
+            user_position,
+            market,
+            price,
+        });
+    }
+
+    let mut yield_positions: Vec<OpenYieldPosition> = Vec::with_capacity(yield_count);
+    for accounts in yield_accounts.chunks(3) {
+        let user_yield_position: Account<UserYieldPosition> = Account::try_from(&accounts[0])?;
+        let yield_market: Account<YieldMarket> = Account::try_from(&accounts[1])?;
+        let market_index = user_yield_position.market_index;
+        let seed_index = market_index.to_le_bytes();
+        let (position_address, _) = Pubkey::find_program_address(
+            &[
+                b"user_yield_position".as_ref(),
+                seed_index.as_ref(),
+                user_account.owner.as_ref(),
+            ],
+            &crate::ID,
+        );
+        let (market_address, _) = Pubkey::find_program_address(
+            &[b"yield_market".as_ref(), seed_index.as_ref()],
+            &crate::ID,
+        );
+        if user_yield_position.key() != position_address
+            || yield_market.key() != market_address
+            || accounts[2].key() != yield_market.feed_address
+        {
+            return err!(KrunchErrors::InvalidPositionAccounts);
+        }
+
+        if !is_yield_position_open(&user_yield_position)
+            || excluded_yield.is_some_and(|p| p.market_index == market_index)
+            || yield_positions
+                .iter()
+                .any(|p| p.user_yield_position.market_index == market_index)
+        {
+            return err!(KrunchErrors::MissingOpenPositions);
+        }
+
+        let price = get_oracle_price(
+            yield_market.oracle_source,
+            accounts[2].clone(),
+            chainlink_program.clone(),
+            exchange,
+            yield_market.fixed_price,
+            yield_market.max_price_age,
+        )?
+        .to_decimal();
+        yield_positions.push(OpenYieldPosition {
+            user_yield_position,
+            yield_market,
+            price,
+        });
+    }
+
+    let mut collaterals: Vec<OpenCollateral> = Vec::with_capacity(collateral_count);
+    for accounts in remaining_accounts[..collateral_count * 3].chunks(3) {
+        let user_collateral: Account<UserCollateral> = Account::try_from(&accounts[0])?;
+        let exchange_treasury_position: Account<ExchangeTreasuryPosition> =
+            Account::try_from(&accounts[1])?;
+        let mint = user_collateral.mint;
+        let (collateral_address, _) = Pubkey::find_program_address(
+            &[
+                b"user_collateral".as_ref(),
+                user_account.owner.as_ref(),
+                mint.as_ref(),
+            ],
+            &crate::ID,
+        );
+        let (treasury_address, _) = Pubkey::find_program_address(
+            &[b"exchange_position".as_ref(), mint.as_ref()],
+            &crate::ID,
+        );
+        if user_collateral.key() != collateral_address
+            || exchange_treasury_position.key() != treasury_address
+            || accounts[2].key() != exchange_treasury_position.feed_address
+        {
+            return err!(KrunchErrors::InvalidPositionAccounts);
+        }
+
+        // empty or repeated balances would stand in for a held one
+        if user_collateral.token_amount == 0
+            || excluded_collateral.is_some_and(|c| c.mint == mint)
+            || collaterals.iter().any(|c| c.user_collateral.mint == mint)
+        {
+            return err!(KrunchErrors::MissingOpenPositions);
+        }
+
+        let price = get_oracle_price(
+            exchange_treasury_position.oracle_source,
+            accounts[2].clone(),
+            chainlink_program.clone(),
+            exchange,
+            exchange_treasury_position.fixed_price,
+            exchange_treasury_position.max_price_age,
+        )?
+        .to_decimal();
+        collaterals.push(OpenCollateral {
+            user_collateral,
+            exchange_treasury_position,
+            price,
+        });
+    }
+    Ok(OpenPositions {
+        positions,
+        yield_positions,
+        collaterals,
+    })
+}
+
+// settles funding on every open position and marks every balance to its oracle
+// price, so health is computed from current values only
+fn settle_open_positions(
+    positions: &mut OpenPositions,
+    user_account: &mut UserAccount,
+    exchange: &mut Exchange,
+) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    for position in positions.positions.iter_mut() {
+        accrue_funding(&mut position.market, position.price, now)?;
+        settle_funding(
+            user_account,
+            &mut position.user_position,
+            &mut position.market,
+            exchange,
+        )?;
+    }
+    for position in positions.yield_positions.iter_mut() {
+        accrue_yield_funding(
+            &mut position.yield_market,
+            Some(&mut position.user_yield_position),
+            position.price,
+            now,
+        )?;
+    }
+    for collateral in positions.collaterals.iter_mut() {
+        mark_collateral(
+            &mut collateral.user_collateral,
+            user_account,
+            exchange,
+            &collateral.exchange_treasury_position,
+            collateral.price,
+        )?;
+    }
+    Ok(())
+}
+
+fn exit_open_positions(positions: &OpenPositions) -> Result<()> {
+    for position in &positions.positions {
+        position.user_position.exit(&crate::ID)?;
+        position.market.exit(&crate::ID)?;
+    }
+    for position in &positions.yield_positions {
+        position.user_yield_position.exit(&crate::ID)?;
+        position.yield_market.exit(&crate::ID)?;
+    }
+    for collateral in &positions.collaterals {
+        collateral.user_collateral.exit(&crate::ID)?;
+    }
+    Ok(())
+}
+
+// moves the part of the locked amount vested since the last update into the
+// claimable balance, the rest keeps vesting linearly until the end
+fn release_vested(reward_vesting: &mut RewardVesting, now: i64) -> Result<()> {
+    if now <= reward_vesting.vesting_start {
+        return Ok(());
+    }
+    let released = if now >= reward_vesting.vesting_end {
+        reward_vesting.locked_amount
+    } else {
+        get_ratio(
+            Decimal::new(
+                reward_vesting.locked_amount.into(),
+                AMOUNT_NUM_DECIMALS.into(),
+            ),
+            now.checked_sub(reward_vesting.vesting_start)
+                .ok_or(KrunchErrors::MathOverflow)?
+                .into(),
+            reward_vesting
+                .vesting_end
+                .checked_sub(reward_vesting.vesting_start)
+                .ok_or(KrunchErrors::MathOverflow)?
+                .into(),
+        )?
+        .to_u64(AMOUNT_NUM_DECIMALS.into(), Rounding::Down)?
+    };
+    reward_vesting.locked_amount = reward_vesting
+        .locked_amount
+        .checked_sub(released)
+        .ok_or(KrunchErrors::MathOverflow)?;
+    reward_vesting.vested_amount = reward_vesting
+        .vested_amount
+        .checked_add(released)
+        .ok_or(KrunchErrors::MathOverflow)?;
+    reward_vesting.vesting_start = now;
+    Ok(())
+}
+
+// reads an account still in its v0 layout, accounts already grown past it
+// have been migrated
+fn read_v0_account<T: AnchorDeserialize>(
+    account: &AccountInfo,
+    discriminator: [u8; 8],
+    v0_space: usize,
+) -> Result<T> {
+    if *account.owner != crate::ID {
+        return err!(KrunchErrors::InvalidAccountVersion);
+    }
+    let data = account.try_borrow_data()?;
+    if data.len() > v0_space {
+        return err!(KrunchErrors::AccountAlreadyMigrated);
+    }
+    if data.len() != v0_space || data[..8] != discriminator {
+        return err!(KrunchErrors::InvalidAccountVersion);
+    }
+    Ok(T::deserialize(&mut &data[8..])?)
+}
+
+// grows the account to the current layout, the payer tops up the rent
+fn write_migrated_account<'info, T: AccountSerialize>(
+    migrated: &T,
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let mut data = Vec::new();
+    migrated.try_serialize(&mut data)?;
+    let rent = Rent::get()?.minimum_balance(data.len());
+    let top_up = rent.saturating_sub(account.lamports());
+    if top_up > 0 {
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                system_program.clone(),
+                anchor_lang::system_program::Transfer {
+                    from: payer.clone(),
+                    to: account.clone(),
+                },
+            ),
+            top_up,
+        )?;
+    }
+    account.realloc(data.len(), false)?;
+    account.try_borrow_mut_data()?.copy_from_slice(&data);
+    Ok(())
+}
+
+fn execute_claim(
+    user_account: &mut UserAccount,
+    exchange: &mut Exchange,
+    throw_error: bool,
+) -> Result<i64> {
+    accrue_rewards(exchange, Clock::get()?.unix_timestamp)?;
+    let amount = settle_rewards(user_account, exchange)?;
+    if amount == 0 && throw_error {
+        return err!(KrunchErrors::NoRewardsAvailable);
+    }
+    Ok(amount)
+}
+
+// each epoch distributes a pool snapshotted when it starts, streamed over the
+// epoch against the collateral held at the time. whatever is not streamed out,
+// because no collateral was held or the exchange was idle, rolls into a later pool
+fn accrue_rewards(exchange: &mut Exchange, now: i64) -> Result<()> {
+    let epoch_length =
+        i64::try_from(exchange.reward_frequency).map_err(|_| error!(KrunchErrors::MathOverflow))?;
+    if epoch_length <= 0 {
+        exchange.last_reward_update = now;
+        return Ok(());
+    }
+    while exchange.last_reward_update < now {
+        let epoch_end = exchange.reward_epoch_start + epoch_length;
+        let until = now.min(epoch_end);
+        if exchange.collateral_value > 0 {
+            let amount = get_ratio(
+                to_amount(exchange.reward_epoch_pool),
+                (until - exchange.last_reward_update).into(),
+                epoch_length.into(),
+            )?;
+            let amount = from_amount(amount, Rounding::Down)?.min(
+                exchange
+                    .reward_epoch_pool
+                    .checked_sub(exchange.reward_epoch_distributed)
+                    .ok_or(KrunchErrors::MathOverflow)?,
+            );
+            let index_delta = to_amount(amount).checked_div(
+                to_amount(exchange.collateral_value),
+                REWARD_INDEX_NUM_DECIMALS,
+                Rounding::Down,
+            )?;
+            exchange.reward_index = exchange
+                .reward_index
+                .checked_add(index_delta.value)
+                .ok_or(KrunchErrors::MathOverflow)?;
+            checked_add_assign(&mut exchange.reward_epoch_distributed, amount)?;
+            checked_sub_assign(&mut exchange.rewards, amount)?;
+        }
+        exchange.last_reward_update = until;
+
+        if until == epoch_end {
+            // epochs the exchange sat idle through are skipped, not replayed
+            let skipped = (now - epoch_end) / epoch_length;
+            exchange.reward_epoch = u64::try_from(skipped)
+                .ok()
+                .and_then(|skipped| exchange.reward_epoch.checked_add(skipped + 1))
+                .ok_or(KrunchErrors::MathOverflow)?;
+            exchange.reward_epoch_start = epoch_end + skipped * epoch_length;
+            exchange.last_reward_update = exchange.reward_epoch_start;
+            exchange.reward_epoch_pool =
+                from_amount(exchange_rewards_available(exchange)?, Rounding::Down)?;
+            exchange.reward_epoch_distributed = 0;
+        }
+    }
+    Ok(())
+}
+
+// credits the rewards a user's collateral earned since their last checkpoint,
+// must run before their collateral value changes
+fn settle_rewards(user_account: &mut UserAccount, exchange: &Exchange) -> Result<i64> {
+    let index_delta = Decimal::new(
+        exchange
+            .reward_index
+            .checked_sub(user_account.reward_index)
+            .ok_or(KrunchErrors::MathOverflow)?,
+        REWARD_INDEX_NUM_DECIMALS,
+    );
+    let amount = from_amount(
+        to_amount(user_account.collateral_value.max(0)).checked_mul(
+            index_delta,
+            AMOUNT_NUM_DECIMALS.into(),
+            Rounding::Down,
+        )?,
+        Rounding::Down,
+    )?;
+    checked_add_assign(&mut user_account.rewards, amount)?;
+    user_account.reward_index = exchange.reward_index;
+    Ok(amount)
 }
 
 #[error_code]
@@ -676,4 +4661,90 @@ pub enum KrunchErrors {
     NoRewardsAvailable,
     #[msg("Yield Amount Insufficient")]
     YieldAmountInsufficient,
+    #[msg("User Account is not below maintenance margin")]
+    UserNotLiquidatable,
+    #[msg("Invalid Liquidation Amount")]
+    InvalidLiquidationAmount,
+    #[msg("Cannot Liquidate Own Account")]
+    CannotLiquidateSelf,
+    #[msg("Maintenance margin cannot exceed initial margin")]
+    InvalidMarginRequirement,
+    #[msg("Funding period must be positive")]
+    InvalidFundingPeriod,
+    #[msg("Invalid Position Accounts")]
+    InvalidPositionAccounts,
+    #[msg("Oracle price is stale")]
+    OraclePriceStale,
+    #[msg("Oracle price is zero or negative")]
+    OraclePriceInvalid,
+    #[msg("Oracle confidence interval is too wide")]
+    OraclePriceUncertain,
+    #[msg("Invalid Oracle Account")]
+    InvalidOracleAccount,
+    #[msg("Mock prices are only available in test mode")]
+    MockOracleDisabled,
+    #[msg("Invalid Order")]
+    InvalidOrder,
+    #[msg("Order Book is full")]
+    OrderBookFull,
+    #[msg("Order Not Found")]
+    OrderNotFound,
+    #[msg("Order belongs to another user")]
+    OrderOwnerMismatch,
+    #[msg("Maker accounts missing or invalid")]
+    MissingMakerAccounts,
+    #[msg("Fill price is worse than the limit price")]
+    SlippageExceeded,
+    #[msg("Reduce only trade would increase the position")]
+    ReduceOnlyViolation,
+    #[msg("Position must be flat to close")]
+    PositionNotFlat,
+    #[msg("Market long open interest cap exceeded")]
+    MaxLongOpenInterestExceeded,
+    #[msg("Market short open interest cap exceeded")]
+    MaxShortOpenInterestExceeded,
+    #[msg("Max position size exceeded")]
+    MaxPositionSizeExceeded,
+    #[msg("Math overflow")]
+    MathOverflow,
+    #[msg("Collateral balance is insufficient")]
+    CollateralBalanceInsufficient,
+    #[msg("Treasury position is not active")]
+    TreasuryPositionInactive,
+    #[msg("LP vault value is not positive")]
+    LpVaultInsolvent,
+    #[msg("Invalid LP Amount")]
+    InvalidLpAmount,
+    #[msg("LP withdrawal cooldown has not ended")]
+    LpCooldownActive,
+    #[msg("LP vault liquidity is insufficient")]
+    LpVaultLiquidityInsufficient,
+    #[msg("Fee tiers must be in increasing volume order")]
+    InvalidFeeTiers,
+    #[msg("No referral fees available")]
+    NoReferralFeesAvailable,
+    #[msg("No vested rewards available")]
+    NoVestedRewards,
+    #[msg("Funding curve parameters are invalid")]
+    InvalidFundingCurve,
+    #[msg("Exchange is paused")]
+    ExchangePaused,
+    #[msg("Every open position must be passed")]
+    MissingOpenPositions,
+    #[msg("Max funding rate cannot be negative")]
+    InvalidMaxFundingRate,
+    #[msg("Resting orders must be cancelled first")]
+    RestingOrdersOpen,
+    #[msg("Order book parameters are invalid")]
+    InvalidOrderBookParameters,
+    #[msg("Too many resting orders")]
+    MaxOrdersExceeded,
+    #[msg("Reward emission budget is exhausted")]
+    RewardBudgetExhausted,
+    #[msg("Every market must be passed")]
+    MissingMarketAccounts,
+    #[msg("Account is not in a migratable layout")]
+    InvalidAccountVersion,
+    #[msg("Account has already been migrated")]
+    AccountAlreadyMigrated,
 }
@@ -0,0 +1,41 @@
+use crate::state::Exchange;
+use anchor_lang::prelude::*;
+
+#[derive(Accounts)]
+pub struct ExchangeAdmin<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    #[account(mut)]
+    pub pending_admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.pending_admin == pending_admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+    #[account(mut)]
+    pub pause_guardian: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.pause_guardian == pause_guardian.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+}
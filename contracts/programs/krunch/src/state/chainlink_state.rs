@@ -9,15 +9,140 @@ pub struct GetPrice<'info> {
 }
 
 #[account]
+#[derive(Copy)]
 pub struct Decimal {
     pub value: i128,
     pub decimals: u32,
 }
 
+// direction to round in whenever precision is dropped, Down is towards
+// negative infinity, Up towards positive infinity and Nearest rounds half
+// away from zero
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+    Nearest,
+}
+
+fn pow10(exponent: u32) -> Result<i128> {
+    10i128
+        .checked_pow(exponent)
+        .ok_or_else(|| error!(crate::KrunchErrors::MathOverflow))
+}
+
+fn div_round(numerator: i128, denominator: i128, rounding: Rounding) -> Result<i128> {
+    let quotient = numerator
+        .checked_div(denominator)
+        .ok_or_else(|| error!(crate::KrunchErrors::MathOverflow))?;
+    if numerator % denominator == 0 {
+        return Ok(quotient);
+    }
+    // integer division truncates towards zero
+    let negative = (numerator < 0) != (denominator < 0);
+    let away = if negative { quotient - 1 } else { quotient + 1 };
+    match rounding {
+        Rounding::Down if negative => Ok(away),
+        Rounding::Up if !negative => Ok(away),
+        Rounding::Nearest => {
+            let remainder = (numerator % denominator).unsigned_abs();
+            if remainder >= denominator.unsigned_abs() - remainder {
+                Ok(away)
+            } else {
+                Ok(quotient)
+            }
+        }
+        _ => Ok(quotient),
+    }
+}
+
 impl Decimal {
     pub fn new(value: i128, decimals: u32) -> Self {
         Decimal { value, decimals }
     }
+
+    pub fn rescale(self, decimals: u32, rounding: Rounding) -> Result<Decimal> {
+        let value = if decimals >= self.decimals {
+            self.value
+                .checked_mul(pow10(decimals - self.decimals)?)
+                .ok_or_else(|| error!(crate::KrunchErrors::MathOverflow))?
+        } else {
+            div_round(self.value, pow10(self.decimals - decimals)?, rounding)?
+        };
+        Ok(Decimal::new(value, decimals))
+    }
+
+    pub fn checked_add(self, other: Decimal) -> Result<Decimal> {
+        let decimals = self.decimals.max(other.decimals);
+        let value = self
+            .rescale(decimals, Rounding::Down)?
+            .value
+            .checked_add(other.rescale(decimals, Rounding::Down)?.value)
+            .ok_or_else(|| error!(crate::KrunchErrors::MathOverflow))?;
+        Ok(Decimal::new(value, decimals))
+    }
+
+    pub fn checked_sub(self, other: Decimal) -> Result<Decimal> {
+        self.checked_add(other.checked_neg()?)
+    }
+
+    pub fn checked_neg(self) -> Result<Decimal> {
+        let value = self
+            .value
+            .checked_neg()
+            .ok_or_else(|| error!(crate::KrunchErrors::MathOverflow))?;
+        Ok(Decimal::new(value, self.decimals))
+    }
+
+    pub fn abs(self) -> Result<Decimal> {
+        let value = self
+            .value
+            .checked_abs()
+            .ok_or_else(|| error!(crate::KrunchErrors::MathOverflow))?;
+        Ok(Decimal::new(value, self.decimals))
+    }
+
+    pub fn checked_mul(self, other: Decimal, decimals: u32, rounding: Rounding) -> Result<Decimal> {
+        let value = self
+            .value
+            .checked_mul(other.value)
+            .ok_or_else(|| error!(crate::KrunchErrors::MathOverflow))?;
+        Decimal::new(value, self.decimals + other.decimals).rescale(decimals, rounding)
+    }
+
+    pub fn checked_div(self, other: Decimal, decimals: u32, rounding: Rounding) -> Result<Decimal> {
+        // (a / 10^da) / (b / 10^db) * 10^d == a * 10^(d + db - da) / b
+        let mut numerator = self.value;
+        let mut denominator = other.value;
+        let scale = decimals + other.decimals;
+        if scale >= self.decimals {
+            numerator = numerator
+                .checked_mul(pow10(scale - self.decimals)?)
+                .ok_or_else(|| error!(crate::KrunchErrors::MathOverflow))?;
+        } else {
+            denominator = denominator
+                .checked_mul(pow10(self.decimals - scale)?)
+                .ok_or_else(|| error!(crate::KrunchErrors::MathOverflow))?;
+        }
+        Ok(Decimal::new(
+            div_round(numerator, denominator, rounding)?,
+            decimals,
+        ))
+    }
+
+    pub fn to_i64(self, decimals: u32, rounding: Rounding) -> Result<i64> {
+        self.rescale(decimals, rounding)?
+            .value
+            .try_into()
+            .map_err(|_| error!(crate::KrunchErrors::MathOverflow))
+    }
+
+    pub fn to_u64(self, decimals: u32, rounding: Rounding) -> Result<u64> {
+        self.rescale(decimals, rounding)?
+            .value
+            .try_into()
+            .map_err(|_| error!(crate::KrunchErrors::MathOverflow))
+    }
 }
 
 impl std::fmt::Display for Decimal {
@@ -42,3 +167,103 @@ pub struct DataFeed {
     pub description: String,
     pub decimals: u8,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_decimal(decimal: Decimal, value: i128, decimals: u32) {
+        assert_eq!((decimal.value, decimal.decimals), (value, decimals));
+    }
+
+    fn assert_overflow<T>(result: Result<T>) {
+        match result {
+            Err(Error::AnchorError(error)) => assert_eq!(error.error_name, "MathOverflow"),
+            Err(error) => panic!("unexpected error {}", error),
+            Ok(_) => panic!("expected MathOverflow"),
+        }
+    }
+
+    #[test]
+    fn rescale_down_rounds_in_the_requested_direction() {
+        let cases = [
+            (15, Rounding::Down, 1),
+            (15, Rounding::Up, 2),
+            (15, Rounding::Nearest, 2),
+            (14, Rounding::Nearest, 1),
+            (-15, Rounding::Down, -2),
+            (-15, Rounding::Up, -1),
+            (-15, Rounding::Nearest, -2),
+            (-14, Rounding::Nearest, -1),
+            (20, Rounding::Up, 2),
+            (-20, Rounding::Down, -2),
+        ];
+        for (value, rounding, expected) in cases {
+            assert_decimal(Decimal::new(value, 1).rescale(0, rounding).unwrap(), expected, 0);
+        }
+    }
+
+    #[test]
+    fn rescale_up_is_exact() {
+        assert_decimal(Decimal::new(-12, 1).rescale(4, Rounding::Down).unwrap(), -12000, 4);
+        assert_decimal(Decimal::new(12, 1).rescale(1, Rounding::Up).unwrap(), 12, 1);
+        assert_overflow(Decimal::new(i128::MAX, 0).rescale(1, Rounding::Down));
+    }
+
+    #[test]
+    fn add_and_sub_align_decimals() {
+        let sum = Decimal::new(15, 1).checked_add(Decimal::new(25, 2)).unwrap();
+        assert_decimal(sum, 175, 2);
+        let difference = Decimal::new(15, 1).checked_sub(Decimal::new(175, 2)).unwrap();
+        assert_decimal(difference, -25, 2);
+        assert_overflow(Decimal::new(i128::MAX, 0).checked_add(Decimal::new(1, 0)));
+        assert_overflow(Decimal::new(i128::MIN, 0).checked_neg());
+        assert_overflow(Decimal::new(i128::MIN, 0).abs());
+    }
+
+    #[test]
+    fn mul_rounds_the_product() {
+        let a = Decimal::new(15, 1);
+        assert_decimal(a.checked_mul(a, 1, Rounding::Down).unwrap(), 22, 1);
+        assert_decimal(a.checked_mul(a, 1, Rounding::Up).unwrap(), 23, 1);
+        assert_decimal(a.checked_mul(a, 1, Rounding::Nearest).unwrap(), 23, 1);
+        let b = Decimal::new(-15, 1);
+        assert_decimal(b.checked_mul(a, 1, Rounding::Down).unwrap(), -23, 1);
+        assert_decimal(b.checked_mul(a, 1, Rounding::Up).unwrap(), -22, 1);
+        assert_decimal(b.checked_mul(a, 1, Rounding::Nearest).unwrap(), -23, 1);
+        assert_overflow(Decimal::new(i128::MAX, 0).checked_mul(
+            Decimal::new(2, 0),
+            0,
+            Rounding::Down,
+        ));
+    }
+
+    #[test]
+    fn div_rounds_the_quotient() {
+        let ten = Decimal::new(10, 0);
+        let three = Decimal::new(3, 0);
+        assert_decimal(ten.checked_div(three, 2, Rounding::Down).unwrap(), 333, 2);
+        assert_decimal(ten.checked_div(three, 2, Rounding::Up).unwrap(), 334, 2);
+        assert_decimal(ten.checked_div(three, 2, Rounding::Nearest).unwrap(), 333, 2);
+        let minus_ten = Decimal::new(-10, 0);
+        assert_decimal(minus_ten.checked_div(three, 2, Rounding::Down).unwrap(), -334, 2);
+        assert_decimal(minus_ten.checked_div(three, 2, Rounding::Up).unwrap(), -333, 2);
+        assert_decimal(minus_ten.checked_div(three, 2, Rounding::Nearest).unwrap(), -333, 2);
+        // dividing to fewer decimals than the dividend scales the divisor instead
+        let value = Decimal::new(1_000_000, 6);
+        assert_decimal(value.checked_div(three, 0, Rounding::Down).unwrap(), 0, 0);
+        assert_decimal(value.checked_div(three, 0, Rounding::Up).unwrap(), 1, 0);
+        assert_overflow(ten.checked_div(Decimal::new(0, 0), 2, Rounding::Down));
+    }
+
+    #[test]
+    fn integer_conversions_round_and_check_range() {
+        let value = Decimal::new(-1_500_000_001, 9);
+        assert_eq!(value.to_i64(0, Rounding::Down).unwrap(), -2);
+        assert_eq!(value.to_i64(0, Rounding::Up).unwrap(), -1);
+        assert_eq!(value.to_i64(0, Rounding::Nearest).unwrap(), -2);
+        assert_eq!(Decimal::new(25, 1).to_u64(0, Rounding::Nearest).unwrap(), 3);
+        assert_overflow(Decimal::new(i128::from(i64::MAX) + 1, 0).to_i64(0, Rounding::Down));
+        assert_overflow(value.to_u64(0, Rounding::Down));
+    }
+}
@@ -1,6 +1,7 @@
 use anchor_lang::prelude::*;
+use crate::state::{FeeTiers, OracleSource, Referrer, UserFeeOverride};
 use anchor_spl::{
-    token::{ Mint, Token, TokenAccount},
+    token_interface::{Mint, TokenAccount, TokenInterface},
 };
 
 #[derive(Accounts)]
@@ -24,11 +25,35 @@ pub struct InitializeExchange<'info> {
                 + 4 // leverage:u32
                 + 8 // rebates:i64
                 + 8 // rewards:i64
-                + 8 // last_rewards_claim:i64
+                + 8 // last_reward_update:i64
                 + 8 // reward_frequency:u64
                 + 8 // reward_rate:u64
                 + 1 // test_mode:bool
                 + 32 // chainlink_program:Pubkey
+                + 32 // pyth_program:Pubkey
+                + 2 // liquidation_fee:u16
+                + 2 // liquidator_share:u16
+                + 8 // settled_pnl:i64
+                + 2 // insurance_fee_share:u16
+                + 8 // insurance_fees_pending:i64
+                + 8 // insurance_fund_value:i64
+                + 8 // socialized_loss:i64
+                + 8 // house_collateral_value:i64
+                + 8 // lp_collateral_value:i64
+                + 2 // referrer_fee_share:u16
+                + 2 // referee_fee_discount:u16
+                + 8 // reward_epoch:u64
+                + 8 // reward_epoch_start:i64
+                + 8 // reward_epoch_pool:i64
+                + 8 // reward_epoch_distributed:i64
+                + 16 // reward_index:i128
+                + 32 // pending_admin:Pubkey
+                + 32 // risk_admin:Pubkey
+                + 32 // oracle_admin:Pubkey
+                + 32 // fee_admin:Pubkey
+                + 32 // pause_guardian:Pubkey
+                + 1 // paused:bool
+                + 8 // insurance_claims_pending:i64
             )]
     pub exchange: Account<'info, Exchange>,
     system_program: Program<'info, System>,
@@ -60,22 +85,118 @@ pub struct ExecuteTrade<'info> {
     #[account(
         mut, 
         seeds = [b"exchange".as_ref()],
-        bump
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
     )]
     pub exchange: Account<'info, Exchange>,
     system_program: Program<'info, System>,
     
     #[account(
-        constraint = *chainlink_feed.key == market.feed_address,
+        constraint = *price_feed.key == market.feed_address,
     )]
-    /// CHECK: validate chainlink feed
-    pub chainlink_feed: AccountInfo<'info>,
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
     
     #[account(
         constraint = *chainlink_program.key == exchange.chainlink_program,
     )]
     /// CHECK: validate chainlink feed
-     pub chainlink_program: AccountInfo<'info>
+     pub chainlink_program: AccountInfo<'info>,
+    #[account(
+        seeds = [b"fee_tiers".as_ref()],
+        bump
+    )]
+    pub fee_tiers: Option<Account<'info, FeeTiers>>,
+    #[account(
+        seeds = [b"fee_override".as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub fee_override: Option<Account<'info, UserFeeOverride>>,
+    #[account(
+        mut,
+        seeds = [b"referrer".as_ref(), user_account.referrer.as_ref()],
+        constraint = referrer.owner == user_account.referrer,
+        bump
+    )]
+    pub referrer: Option<Account<'info, Referrer>>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct LiquidateUser<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),liquidator.key().as_ref()],
+        constraint = liquidator_account.owner == liquidator.key(),
+        bump)]
+    pub liquidator_account: Account<'info, UserAccount>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),user_account.owner.as_ref()],
+        constraint = user_account.owner != liquidator.key() @ crate::KrunchErrors::CannotLiquidateSelf,
+        bump)]
+    pub user_account: Account<'info, UserAccount>,
+    #[account(
+        mut,
+        seeds = [b"user_position".as_ref(),user_account.owner.as_ref(),market_index.to_le_bytes().as_ref()],
+        bump)]
+    pub user_position: Account<'info, UserPosition>,
+    #[account(
+        mut, 
+        seeds = [b"market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut, 
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+
+    #[account(
+        constraint = *price_feed.key == market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct UpdateFunding<'info> {
+    #[account(
+        mut, 
+        seeds = [b"market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump
+    )]
+    pub exchange: Account<'info, Exchange>,
+
+    #[account(
+        constraint = *price_feed.key == market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
 }
 
 // data validation
@@ -94,7 +215,61 @@ pub struct UpdateMarket<'info> {
         mut, 
         seeds = [b"exchange".as_ref()],
         bump,
-        constraint = exchange.admin == owner.key(),
+        constraint = exchange.risk_admin == owner.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+
+    #[account(
+        constraint = *price_feed.key == market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct UpdateMarketFees<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut, 
+        seeds = [b"market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut, 
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.fee_admin == owner.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct UpdateMarketOracle<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut, 
+        seeds = [b"market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut, 
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.oracle_admin == owner.key(),
     )]
     pub exchange: Account<'info, Exchange>,
     system_program: Program<'info, System>,
@@ -120,9 +295,9 @@ pub struct Deposit<'info> {
     #[account(mut,
         constraint = user_token_account.owner == owner.key(),
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
-    pub mint: Account<'info, Mint>,
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub mint: InterfaceAccount<'info, Mint>,
     #[account(
         init_if_needed,
         payer = owner,
@@ -132,8 +307,23 @@ pub struct Deposit<'info> {
         bump,
         token::mint=mint,
         token::authority=exchange,
+        token::token_program=token_program,
     )]
-    pub escrow_account: Account<'info, TokenAccount>,
+    pub escrow_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8
+                + 32 // owner:Pubkey
+                + 32 // mint:Pubkey
+                + 8 // token_amount:u64
+                + 8 // collateral_value:i64
+                + 8 // weighted_collateral_value:i64
+        ,
+        seeds = [b"user_collateral".as_ref(), owner.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub user_collateral: Account<'info, UserCollateral>,
     #[account(
         mut, 
         seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
@@ -141,10 +331,10 @@ pub struct Deposit<'info> {
     )]
     pub exchange_treasury_position: Account<'info, ExchangeTreasuryPosition>,
     #[account(
-        constraint = *chainlink_feed.key == exchange_treasury_position.feed_address,
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
     )]
-    /// CHECK: validate chainlink feed
-    pub chainlink_feed: AccountInfo<'info>,
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
     
     #[account(
         constraint = *chainlink_program.key == exchange.chainlink_program,
@@ -160,7 +350,8 @@ pub struct ClaimRewards<'info> {
     #[account(
         mut, 
         seeds = [b"exchange".as_ref()],
-        bump
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
     )]
     pub exchange: Account<'info, Exchange>,
     #[account(
@@ -173,15 +364,132 @@ pub struct ClaimRewards<'info> {
 }
 
 #[derive(Accounts)]
-pub struct Withdraw<'info> {
+pub struct SettlePnl<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
+    #[account(
+        mut, 
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Account<'info, Exchange>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),owner.key().as_ref()],
+        constraint = user_account.owner == owner.key(),
+        bump)]
+    pub user_account: Account<'info, UserAccount>,
+    system_program: Program<'info, System>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        seeds = [
+            exchange.key().as_ref(),
+            mint.key().as_ref()],
+        bump,
+        token::mint=mint,
+        token::authority=exchange,
+    )]
+    pub escrow_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8
+                + 32 // owner:Pubkey
+                + 32 // mint:Pubkey
+                + 8 // token_amount:u64
+                + 8 // collateral_value:i64
+                + 8 // weighted_collateral_value:i64
+        ,
+        seeds = [b"user_collateral".as_ref(), owner.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub user_collateral: Account<'info, UserCollateral>,
+    #[account(
+        mut, 
+        seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Account<'info, ExchangeTreasuryPosition>,
+    #[account(
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+pub struct UpdateCollateral<'info> {
     #[account(
         mut, 
         seeds = [b"exchange".as_ref()],
         bump
     )]
     pub exchange: Account<'info, Exchange>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),user_collateral.owner.as_ref()],
+        bump)]
+    pub user_account: Account<'info, UserAccount>,
+    #[account(
+        mut,
+        seeds = [b"user_collateral".as_ref(), user_collateral.owner.as_ref(), user_collateral.mint.as_ref()],
+        bump
+    )]
+    pub user_collateral: Account<'info, UserCollateral>,
+    #[account(
+        seeds = [b"exchange_position".as_ref(), user_collateral.mint.as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Account<'info, ExchangeTreasuryPosition>,
+    #[account(
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct CloseUserPosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        close = owner,
+        seeds = [b"user_position".as_ref(),owner.key().as_ref(),market_index.to_le_bytes().as_ref()],
+        constraint = user_position.token_amount == 0 @ crate::KrunchErrors::PositionNotFlat,
+        bump)]
+    pub user_position: Account<'info, UserPosition>,
+    #[account(
+        seeds = [b"order_book".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    /// CHECK: markets without an order book leave this uninitialized
+    pub order_book: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Withdraw<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut, 
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Account<'info, Exchange>,
     #[account(
         mut,
         seeds = [b"user_account".as_ref(),owner.key().as_ref()],
@@ -192,37 +500,149 @@ pub struct Withdraw<'info> {
     #[account(mut,
         constraint = user_token_account.owner == owner.key(),
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
-    pub mint: Account<'info, Mint>,
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        seeds = [
+            exchange.key().as_ref(),
+            mint.key().as_ref()],
+        bump,
+        token::mint=mint,
+        token::authority=exchange,
+        token::token_program=token_program,
+    )]
+    pub escrow_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [b"user_collateral".as_ref(), owner.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub user_collateral: Account<'info, UserCollateral>,
+    #[account(
+        mut, 
+        seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Account<'info, ExchangeTreasuryPosition>,
+    #[account(
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+   
+}
+
+#[derive(Accounts)]
+pub struct FundInsurance<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(
+        mut, 
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.fee_admin == payer.key(),
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        mut,
+        seeds = [
+            exchange.key().as_ref(),
+            mint.key().as_ref()],
+        bump,
+        token::mint=mint,
+        token::authority=exchange,
+        token::token_program=token_program,
+    )]
+    pub escrow_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        seeds = [
+            b"insurance_fund".as_ref(),
+            mint.key().as_ref()],
+        bump,
+        token::mint=mint,
+        token::authority=exchange,
+        token::token_program=token_program,
+    )]
+    pub insurance_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Account<'info, ExchangeTreasuryPosition>,
+    #[account(
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+pub struct PayInsuranceClaim<'info> {
+    #[account(
+        mut, 
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Account<'info, Exchange>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub mint: InterfaceAccount<'info, Mint>,
     #[account(
-        init_if_needed,
-        payer = owner,
+        mut,
         seeds = [
             exchange.key().as_ref(),
             mint.key().as_ref()],
         bump,
         token::mint=mint,
         token::authority=exchange,
+        token::token_program=token_program,
     )]
-    pub escrow_account: Account<'info, TokenAccount>,
+    pub escrow_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [
+            b"insurance_fund".as_ref(),
+            mint.key().as_ref()],
+        bump,
+        token::mint=mint,
+        token::authority=exchange,
+        token::token_program=token_program,
+    )]
+    pub insurance_vault: InterfaceAccount<'info, TokenAccount>,
     #[account(
-        mut, 
         seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
         bump
     )]
     pub exchange_treasury_position: Account<'info, ExchangeTreasuryPosition>,
     #[account(
-        constraint = *chainlink_feed.key == exchange_treasury_position.feed_address,
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
     )]
-    /// CHECK: validate chainlink feed
-    pub chainlink_feed: AccountInfo<'info>,
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
     #[account(
         constraint = *chainlink_program.key == exchange.chainlink_program,
     )]
     /// CHECK: validate chainlink feed
     pub chainlink_program: AccountInfo<'info>
-   
 }
 
 #[derive(Accounts)]
@@ -240,9 +660,9 @@ pub struct ExchangeTransaction<'info> {
     #[account(mut,
         constraint = user_token_account.owner == owner.key(),
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
-    pub mint: Account<'info, Mint>,
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub mint: InterfaceAccount<'info, Mint>,
     #[account(
         init_if_needed,
         payer = owner,
@@ -252,8 +672,9 @@ pub struct ExchangeTransaction<'info> {
         bump,
         token::mint=mint,
         token::authority=exchange,
+        token::token_program=token_program,
     )]
-    pub escrow_account: Account<'info, TokenAccount>,
+    pub escrow_account: InterfaceAccount<'info, TokenAccount>,
     #[account(
         mut, 
         seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
@@ -261,10 +682,10 @@ pub struct ExchangeTransaction<'info> {
     )]
     pub exchange_treasury_position: Account<'info, ExchangeTreasuryPosition>,
     #[account(
-        constraint = *chainlink_feed.key == exchange_treasury_position.feed_address,
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
     )]
-    /// CHECK: validate chainlink feed
-    pub chainlink_feed: AccountInfo<'info>,
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
     #[account(
         constraint = *chainlink_program.key == exchange.chainlink_program,
     )]
@@ -291,9 +712,25 @@ pub struct CreateUserAccount<'info> {
                 + 8 // fees:i64
                 + 8 // rebates:i64
                 + 8 // rewards:i64
-                + 8 // last_rewards_claim:i64
+                + 16 // reward_index:i128
+                + 8 // initial_margin_required:i64
+                + 8 // maintenance_margin_required:i64
+                + 8 // weighted_collateral_value:i64
+                + 8 // volume_30d:i64
+                + 8 // last_volume_update:i64
+                + 32 // referrer:Pubkey
+                + 2 // open_positions:u16
+                + 2 // open_collaterals:u16
+                + 2 // open_yield_positions:u16
             )]
     pub user_account: Account<'info, UserAccount>,
+    #[account(
+        mut,
+        seeds = [b"referrer".as_ref(), referrer.owner.as_ref()],
+        constraint = referrer.owner != owner.key(),
+        bump
+    )]
+    pub referrer: Option<Account<'info, Referrer>>,
     system_program: Program<'info, System>,
 }
 
@@ -314,10 +751,25 @@ pub struct AddMarket<'info> {
                 + 8 // fees:i64
                 + 2 // taker_fee:i16
                 + 2 // maker_fee:i16
-                + 4 // leverage:u32
+                + 2 // initial_margin:u16
+                + 2 // maintenance_margin:u16
                 + 8 // margin_used:i64
                 + 32 // feed_address:Pubkey
                 + 8 // rebates:i64
+                + 8 // long_open_interest:i64
+                + 8 // short_open_interest:i64
+                + 8 // funding_rate:i64
+                + 8 // max_funding_rate:i64
+                + 8 // funding_period:i64
+                + 8 // cumulative_funding:i64
+                + 8 // last_funding_time:i64
+                + 4 // max_price_age:u32
+                + 1 // oracle_source:OracleSource
+                + 8 // fixed_price:i64
+                + 8 // max_long_open_interest:i64
+                + 8 // max_short_open_interest:i64
+                + 8 // max_position_size:i64
+                + 8 // short_basis:i64
         ,
         seeds = [b"market".as_ref(), market_index.to_le_bytes().as_ref()],
         bump
@@ -347,6 +799,14 @@ pub struct AddExchangeTreasuryPosition<'info> {
                 + 2 // treasuryWeight:u16,
                 + 1 // decimals:u8
                 + 32 // feed_address:Pubkey
+                + 4 // max_price_age:u32
+                + 1 // oracle_source:OracleSource
+                + 8 // fixed_price:i64
+                + 8 // token_amount:u64
+                + 8 // house_token_amount:u64
+                + 8 // house_collateral_value:i64
+                + 8 // lp_token_amount:u64
+                + 8 // lp_collateral_value:i64
         ,
         seeds = [b"exchange_position".as_ref(), token_mint.key().as_ref()],
         bump
@@ -370,7 +830,21 @@ pub struct UpdateExchange<'info> {
         mut, 
         seeds = [b"exchange".as_ref()],
         bump,
-        constraint = exchange.admin == admin.key(),
+        constraint = exchange.risk_admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateExchangeFees<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut, 
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.fee_admin == admin.key(),
     )]
     pub exchange: Account<'info, Exchange>,
     system_program: Program<'info, System>,
@@ -391,7 +865,28 @@ pub struct UpdateExchangeTreasuryPosition<'info> {
         mut, 
         seeds = [b"exchange".as_ref()],
         bump,
-        constraint = exchange.admin == owner.key(),
+        constraint = exchange.risk_admin == owner.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: Pubkey)]
+pub struct UpdateExchangeTreasuryOracle<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut, 
+        seeds = [b"exchange_position".as_ref(), token_mint.key().as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Account<'info, ExchangeTreasuryPosition>,
+    #[account(
+        mut, 
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.oracle_admin == owner.key(),
     )]
     pub exchange: Account<'info, Exchange>,
     system_program: Program<'info, System>,
@@ -401,7 +896,7 @@ pub struct UpdateExchangeTreasuryPosition<'info> {
 #[instruction(market_index: u16)]
 pub struct AddUserPosition<'info> {
     #[account(mut)]
-    owner: Signer<'info>,
+    pub owner: Signer<'info>,
     #[account(
         init, 
         payer = owner,
@@ -414,6 +909,10 @@ pub struct AddUserPosition<'info> {
                 + 8 // fees:i64
                 + 8 // margin_used:i64
                 + 8 // rebates:i64
+                + 8 // initial_margin_required:i64
+                + 8 // maintenance_margin_required:i64
+                + 8 // funding:i64
+                + 8 // last_cumulative_funding:i64
         ,
         seeds = [b"user_position".as_ref(),owner.key().as_ref(), market_index.to_le_bytes().as_ref()],
         bump
@@ -454,8 +953,19 @@ pub struct AddYieldMarket<'info> {
                 + 8 // short_fees:i64
                 + 8 // long_fees:i64
                 + 8 // last_claim_date:i64  
-                + 32 // chainlink_feed:Pubkey
+                + 32 // feed_address:Pubkey
                 + 32 // chainlink_program:Pubkey              
+                + 4 // max_price_age:u32
+                + 1 // oracle_source:OracleSource
+                + 8 // fixed_price:i64
+                + 2 // fee:i16
+                + 2 // initial_margin:u16
+                + 2 // maintenance_margin:u16
+                + 8 // funding_period:i64
+                + 8 // max_funding_rate:i64
+                + 1 // funding_curve:FundingCurve
+                + 8 // kink_utilization:i64
+                + 8 // kink_funding_rate:i64
         ,
         seeds = [b"yield_market".as_ref(), market_index.to_le_bytes().as_ref()],
         bump
@@ -470,6 +980,37 @@ pub struct AddYieldMarket<'info> {
     system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct UpdateYieldMarket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"yield_market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub yield_market: Account<'info, YieldMarket>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.risk_admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+
+    #[account(
+        constraint = *price_feed.key == yield_market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+}
+
 #[derive(Accounts)]
 #[instruction(market_index: u16)]
 pub struct AddYield<'info> {
@@ -490,6 +1031,9 @@ pub struct AddYield<'info> {
                 + 8 // short_fees:i64
                 + 8 // long_fees:i64
                 + 8 // last_claim_date:i64            
+                + 8 // initial_margin_required:i64
+                + 8 // maintenance_margin_required:i64
+                + 8 // settled_funding:i64
         ,
         seeds = [b"user_yield_position".as_ref(), 
             market_index.to_le_bytes().as_ref(),
@@ -513,6 +1057,12 @@ pub struct UpdateYield<'info> {
         bump
     )]
     pub user_yield_position: Account<'info, UserYieldPosition>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),owner.key().as_ref()],
+        constraint = user_account.owner == owner.key(),
+        bump)]
+    pub user_account: Account<'info, UserAccount>,
     #[account(
         mut, 
         seeds = [b"yield_market".as_ref(), market_index.to_le_bytes().as_ref()],
@@ -522,16 +1072,16 @@ pub struct UpdateYield<'info> {
     system_program: Program<'info, System>,
 
      #[account(
-        constraint = *chainlink_feed.key == yield_market.chainlink_feed,
+        constraint = *price_feed.key == yield_market.feed_address,
     )]
-    /// CHECK: validate chainlink feed
-     pub chainlink_feed: AccountInfo<'info>,
+    /// CHECK: validate price feed
+     pub price_feed: AccountInfo<'info>,
 
      #[account(
         mut, 
         seeds = [b"exchange".as_ref()],
         bump,
-        constraint = exchange.admin == owner.key(),
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
     )]
     pub exchange: Account<'info, Exchange>,
      #[account(
@@ -542,6 +1092,213 @@ pub struct UpdateYield<'info> {
 
 }
 
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct ClaimYield<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),owner.key().as_ref()],
+        constraint = user_account.owner == owner.key(),
+        bump)]
+    pub user_account: Box<Account<'info, UserAccount>>,
+    #[account(
+        mut,
+        constraint = user_yield_position.owner == owner.key(),
+        seeds = [b"user_yield_position".as_ref(), market_index.to_le_bytes().as_ref(),owner.key().as_ref()],
+        bump
+    )]
+    pub user_yield_position: Box<Account<'info, UserYieldPosition>>,
+    #[account(
+        mut,
+        seeds = [b"yield_market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub yield_market: Box<Account<'info, YieldMarket>>,
+    #[account(
+        constraint = *yield_price_feed.key == yield_market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub yield_price_feed: AccountInfo<'info>,
+    system_program: Program<'info, System>,
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(
+        seeds = [
+            exchange.key().as_ref(),
+            mint.key().as_ref()],
+        bump,
+        token::mint=mint,
+        token::authority=exchange,
+    )]
+    pub escrow_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8
+                + 32 // owner:Pubkey
+                + 32 // mint:Pubkey
+                + 8 // token_amount:u64
+                + 8 // collateral_value:i64
+                + 8 // weighted_collateral_value:i64
+        ,
+        seeds = [b"user_collateral".as_ref(), owner.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub user_collateral: Box<Account<'info, UserCollateral>>,
+    #[account(
+        mut,
+        seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Box<Account<'info, ExchangeTreasuryPosition>>,
+    #[account(
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct CloseYieldPosition<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),owner.key().as_ref()],
+        constraint = user_account.owner == owner.key(),
+        bump)]
+    pub user_account: Box<Account<'info, UserAccount>>,
+    #[account(
+        mut,
+        close = owner,
+        constraint = user_yield_position.owner == owner.key(),
+        seeds = [b"user_yield_position".as_ref(), market_index.to_le_bytes().as_ref(),owner.key().as_ref()],
+        bump
+    )]
+    pub user_yield_position: Box<Account<'info, UserYieldPosition>>,
+    #[account(
+        mut,
+        seeds = [b"yield_market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub yield_market: Box<Account<'info, YieldMarket>>,
+    #[account(
+        constraint = *yield_price_feed.key == yield_market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub yield_price_feed: AccountInfo<'info>,
+    system_program: Program<'info, System>,
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(
+        seeds = [
+            exchange.key().as_ref(),
+            mint.key().as_ref()],
+        bump,
+        token::mint=mint,
+        token::authority=exchange,
+    )]
+    pub escrow_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8
+                + 32 // owner:Pubkey
+                + 32 // mint:Pubkey
+                + 8 // token_amount:u64
+                + 8 // collateral_value:i64
+                + 8 // weighted_collateral_value:i64
+        ,
+        seeds = [b"user_collateral".as_ref(), owner.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub user_collateral: Box<Account<'info, UserCollateral>>,
+    #[account(
+        mut,
+        seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Box<Account<'info, ExchangeTreasuryPosition>>,
+    #[account(
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct LiquidateYield<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),liquidator.key().as_ref()],
+        constraint = liquidator_account.owner == liquidator.key(),
+        bump)]
+    pub liquidator_account: Box<Account<'info, UserAccount>>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),user_account.owner.as_ref()],
+        constraint = user_account.owner != liquidator.key() @ crate::KrunchErrors::CannotLiquidateSelf,
+        bump)]
+    pub user_account: Box<Account<'info, UserAccount>>,
+    #[account(
+        mut,
+        seeds = [b"user_yield_position".as_ref(), market_index.to_le_bytes().as_ref(),user_account.owner.as_ref()],
+        bump
+    )]
+    pub user_yield_position: Box<Account<'info, UserYieldPosition>>,
+    #[account(
+        mut,
+        seeds = [b"yield_market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub yield_market: Box<Account<'info, YieldMarket>>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        constraint = *price_feed.key == yield_market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>
+}
+
 // Data structures
 #[account]
 pub struct Exchange {
@@ -556,11 +1313,35 @@ pub struct Exchange {
     pub leverage: u32,
     pub rebates: i64,
     pub rewards: i64,
-    pub last_rewards_claim: i64,
+    pub last_reward_update: i64,
     pub reward_frequency: u64,
     pub reward_rate: u64,
     pub test_mode: bool,
     pub chainlink_program: Pubkey,
+    pub pyth_program: Pubkey,
+    pub liquidation_fee: u16,
+    pub liquidator_share: u16,
+    pub settled_pnl: i64,
+    pub insurance_fee_share: u16,
+    pub insurance_fees_pending: i64,
+    pub insurance_fund_value: i64,
+    pub socialized_loss: i64,
+    pub house_collateral_value: i64,
+    pub lp_collateral_value: i64,
+    pub referrer_fee_share: u16,
+    pub referee_fee_discount: u16,
+    pub reward_epoch: u64,
+    pub reward_epoch_start: i64,
+    pub reward_epoch_pool: i64,
+    pub reward_epoch_distributed: i64,
+    pub reward_index: i128,
+    pub pending_admin: Pubkey,
+    pub risk_admin: Pubkey,
+    pub oracle_admin: Pubkey,
+    pub fee_admin: Pubkey,
+    pub pause_guardian: Pubkey,
+    pub paused: bool,
+    pub insurance_claims_pending: i64,
 }
 
 #[account]
@@ -570,6 +1351,14 @@ pub struct ExchangeTreasuryPosition {
     pub treasury_weight: u16,
     pub decimals: u8,
     pub feed_address: Pubkey,
+    pub max_price_age: u32,
+    pub oracle_source: OracleSource,
+    pub fixed_price: i64,
+    pub token_amount: u64,
+    pub house_token_amount: u64,
+    pub house_collateral_value: i64,
+    pub lp_token_amount: u64,
+    pub lp_collateral_value: i64,
 }
 
 #[account]
@@ -582,10 +1371,25 @@ pub struct Market {
     pub fees: i64,
     pub taker_fee: i16,
     pub maker_fee: i16,
-    pub leverage: u32,
+    pub initial_margin: u16,
+    pub maintenance_margin: u16,
     pub margin_used: i64,
     pub feed_address: Pubkey,
     pub rebates: i64,
+    pub long_open_interest: i64,
+    pub short_open_interest: i64,
+    pub funding_rate: i64,
+    pub max_funding_rate: i64,
+    pub funding_period: i64,
+    pub cumulative_funding: i64,
+    pub last_funding_time: i64,
+    pub max_price_age: u32,
+    pub oracle_source: OracleSource,
+    pub fixed_price: i64,
+    pub max_long_open_interest: i64,
+    pub max_short_open_interest: i64,
+    pub max_position_size: i64,
+    pub short_basis: i64,
 }
 
 #[account]
@@ -598,7 +1402,25 @@ pub struct UserAccount {
     pub fees: i64,
     pub rebates: i64,
     pub rewards: i64,
-    pub last_rewards_claim: i64,
+    pub reward_index: i128,
+    pub initial_margin_required: i64,
+    pub maintenance_margin_required: i64,
+    pub weighted_collateral_value: i64,
+    pub volume_30d: i64,
+    pub last_volume_update: i64,
+    pub referrer: Pubkey,
+    pub open_positions: u16,
+    pub open_collaterals: u16,
+    pub open_yield_positions: u16,
+}
+
+#[account]
+pub struct UserCollateral {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub token_amount: u64,
+    pub collateral_value: i64,
+    pub weighted_collateral_value: i64,
 }
 
 #[account]
@@ -611,6 +1433,10 @@ pub struct UserPosition {
     pub fees: i64,
     pub margin_used: i64,
     pub rebates: i64,
+    pub initial_margin_required: i64,
+    pub maintenance_margin_required: i64,
+    pub funding: i64,
+    pub last_cumulative_funding: i64,
 }
 
 #[account]
@@ -625,7 +1451,25 @@ pub struct YieldMarket {
     pub short_fees: i64,
     pub long_fees: i64,
     pub last_claim_date: i64,
-    pub chainlink_feed: Pubkey,
+    pub feed_address: Pubkey,
+    pub max_price_age: u32,
+    pub oracle_source: OracleSource,
+    pub fixed_price: i64,
+    pub fee: i16,
+    pub initial_margin: u16,
+    pub maintenance_margin: u16,
+    pub funding_period: i64,
+    pub max_funding_rate: i64,
+    pub funding_curve: FundingCurve,
+    pub kink_utilization: i64,
+    pub kink_funding_rate: i64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum FundingCurve {
+    Linear,
+    Capped,
+    Kinked,
 }
 
 #[account]
@@ -641,5 +1485,8 @@ pub struct UserYieldPosition {
     pub short_fees: i64,
     pub long_fees: i64,
     pub last_claim_date: i64,
+    pub initial_margin_required: i64,
+    pub maintenance_margin_required: i64,
+    pub settled_funding: i64,
 }
 
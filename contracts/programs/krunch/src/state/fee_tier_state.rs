@@ -0,0 +1,108 @@
+use crate::state::Exchange;
+use anchor_lang::prelude::*;
+
+pub const MAX_FEE_TIERS: usize = 8;
+
+#[derive(Accounts)]
+pub struct UpdateFeeTiers<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = 8
+                + 4 + MAX_FEE_TIERS * FeeTier::SIZE // tiers:Vec<FeeTier>
+        ,
+        seeds = [b"fee_tiers".as_ref()],
+        bump
+    )]
+    pub fee_tiers: Account<'info, FeeTiers>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.fee_admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct SetUserFeeOverride<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = 8
+                + 32 // owner:Pubkey
+                + 2 // maker_fee:i16
+                + 2 // taker_fee:i16
+        ,
+        seeds = [b"fee_override".as_ref(), owner.as_ref()],
+        bump
+    )]
+    pub fee_override: Account<'info, UserFeeOverride>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.fee_admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct RemoveUserFeeOverride<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        close = admin,
+        seeds = [b"fee_override".as_ref(), owner.as_ref()],
+        bump
+    )]
+    pub fee_override: Account<'info, UserFeeOverride>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.fee_admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct FeeTier {
+    pub volume_threshold: i64,
+    pub maker_fee: i16,
+    pub taker_fee: i16,
+}
+
+impl FeeTier {
+    pub const SIZE: usize = 8 // volume_threshold:i64
+        + 2 // maker_fee:i16
+        + 2; // taker_fee:i16
+}
+
+#[account]
+pub struct FeeTiers {
+    pub tiers: Vec<FeeTier>,
+}
+
+impl FeeTiers {
+    // tiers are kept in increasing volume order, the highest one reached applies
+    pub fn find(&self, volume: i64) -> Option<&FeeTier> {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|tier| volume >= tier.volume_threshold)
+    }
+}
+
+#[account]
+pub struct UserFeeOverride {
+    pub owner: Pubkey,
+    pub maker_fee: i16,
+    pub taker_fee: i16,
+}
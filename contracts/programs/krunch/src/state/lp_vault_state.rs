@@ -0,0 +1,260 @@
+use crate::state::{Exchange, ExchangeTreasuryPosition};
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+#[derive(Accounts)]
+pub struct InitializeLpVault<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8
+                + 32 // share_mint:Pubkey
+                + 8 // cooldown_period:i64
+                + 8 // withdrawn_pnl:i64
+        ,
+        seeds = [b"lp_vault".as_ref()],
+        bump
+    )]
+    pub lp_vault: Account<'info, LpVault>,
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"lp_share_mint".as_ref()],
+        bump,
+        mint::decimals = crate::AMOUNT_NUM_DECIMALS,
+        mint::authority = exchange,
+        mint::token_program = share_token_program,
+    )]
+    pub share_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"lp_share_escrow".as_ref()],
+        bump,
+        token::mint = share_mint,
+        token::authority = exchange,
+        token::token_program = share_token_program,
+    )]
+    pub share_escrow: InterfaceAccount<'info, TokenAccount>,
+    pub share_token_program: Interface<'info, TokenInterface>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateLpVault<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    #[account(
+        mut,
+        seeds = [b"lp_vault".as_ref()],
+        bump
+    )]
+    pub lp_vault: Account<'info, LpVault>,
+}
+
+#[derive(Accounts)]
+pub struct LpDeposit<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        seeds = [b"lp_vault".as_ref()],
+        bump
+    )]
+    pub lp_vault: Box<Account<'info, LpVault>>,
+    system_program: Program<'info, System>,
+    #[account(mut,
+        constraint = user_token_account.owner == owner.key(),
+    )]
+    pub user_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        seeds = [
+            exchange.key().as_ref(),
+            mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = exchange,
+        token::token_program = token_program,
+    )]
+    pub escrow_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Box<Account<'info, ExchangeTreasuryPosition>>,
+    #[account(
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"lp_share_mint".as_ref()],
+        bump
+    )]
+    pub share_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut,
+        constraint = user_share_account.owner == owner.key(),
+        constraint = user_share_account.mint == share_mint.key(),
+    )]
+    pub user_share_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        seeds = [b"lp_share_escrow".as_ref()],
+        bump
+    )]
+    pub share_escrow: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub share_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RequestLpWithdrawal<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8
+                + 32 // owner:Pubkey
+                + 8 // cooldown_shares:u64
+                + 8 // cooldown_start:i64
+        ,
+        seeds = [b"lp_position".as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub lp_position: Account<'info, LpPosition>,
+    #[account(
+        seeds = [b"lp_share_mint".as_ref()],
+        bump
+    )]
+    pub share_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut,
+        constraint = user_share_account.owner == owner.key(),
+        constraint = user_share_account.mint == share_mint.key(),
+    )]
+    pub user_share_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [b"lp_share_escrow".as_ref()],
+        bump
+    )]
+    pub share_escrow: InterfaceAccount<'info, TokenAccount>,
+    pub share_token_program: Interface<'info, TokenInterface>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct LpWithdraw<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        mut,
+        seeds = [b"lp_vault".as_ref()],
+        bump
+    )]
+    pub lp_vault: Box<Account<'info, LpVault>>,
+    #[account(
+        mut,
+        seeds = [b"lp_position".as_ref(), owner.key().as_ref()],
+        constraint = lp_position.owner == owner.key(),
+        bump
+    )]
+    pub lp_position: Box<Account<'info, LpPosition>>,
+    #[account(mut,
+        constraint = user_token_account.owner == owner.key(),
+    )]
+    pub user_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(
+        mut,
+        seeds = [
+            exchange.key().as_ref(),
+            mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = exchange,
+        token::token_program = token_program,
+    )]
+    pub escrow_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        mut,
+        seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Box<Account<'info, ExchangeTreasuryPosition>>,
+    #[account(
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"lp_share_mint".as_ref()],
+        bump
+    )]
+    pub share_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(
+        mut,
+        seeds = [b"lp_share_escrow".as_ref()],
+        bump
+    )]
+    pub share_escrow: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub share_token_program: Interface<'info, TokenInterface>,
+}
+
+#[account]
+pub struct LpVault {
+    pub share_mint: Pubkey,
+    pub cooldown_period: i64,
+    pub withdrawn_pnl: i64,
+}
+
+#[account]
+pub struct LpPosition {
+    pub owner: Pubkey,
+    pub cooldown_shares: u64,
+    pub cooldown_start: i64,
+}
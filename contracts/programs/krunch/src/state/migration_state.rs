@@ -0,0 +1,371 @@
+use crate::state::{
+    Exchange, ExchangeTreasuryPosition, Market, UserAccount, UserCollateral, YieldMarket,
+};
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+// accounts created before the risk, funding and role fields were added are
+// still in the v0 layout, they are told apart by their size and are grown
+// to the current layout by the migrate instructions
+pub const EXCHANGE_V0_SPACE: usize = 8 + 153;
+pub const MARKET_V0_SPACE: usize = 8 + 92;
+pub const USER_ACCOUNT_V0_SPACE: usize = 8 + 96;
+pub const USER_POSITION_V0_SPACE: usize = 8 + 82;
+// v0 treasury positions reserved two bytes for the active flag
+pub const EXCHANGE_TREASURY_POSITION_V0_SPACE: usize = 8 + 69;
+// v0 yield markets reserved space for a chainlink program they never stored
+pub const YIELD_MARKET_V0_SPACE: usize = 8 + 138;
+pub const USER_YIELD_POSITION_V0_SPACE: usize = 8 + 106;
+
+#[derive(Accounts)]
+pub struct MigrateExchange<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+    )]
+    /// CHECK: v0 layout, the admin is checked once it is read
+    pub exchange: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct MigrateMarket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        mut,
+        seeds = [b"market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    /// CHECK: v0 layout, read by the instruction
+    pub market: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateUserAccount<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: only used to derive the user account address
+    pub owner: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    /// CHECK: v0 layout, read by the instruction
+    pub user_account: AccountInfo<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(
+        seeds = [exchange.key().as_ref(), mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = exchange,
+        token::token_program = token_program,
+    )]
+    pub escrow_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8
+                + 32 // owner:Pubkey
+                + 32 // mint:Pubkey
+                + 8 // token_amount:u64
+                + 8 // collateral_value:i64
+                + 8 // weighted_collateral_value:i64
+        ,
+        seeds = [b"user_collateral".as_ref(), owner.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub user_collateral: Box<Account<'info, UserCollateral>>,
+    #[account(
+        mut,
+        seeds = [b"exchange_position".as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub exchange_treasury_position: Box<Account<'info, ExchangeTreasuryPosition>>,
+    #[account(
+        constraint = *price_feed.key == exchange_treasury_position.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct MigrateUserPosition<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: only used to derive the position addresses
+    pub owner: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_account: Box<Account<'info, UserAccount>>,
+    #[account(
+        mut,
+        seeds = [b"market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub market: Box<Account<'info, Market>>,
+    #[account(
+        mut,
+        seeds = [b"user_position".as_ref(), owner.key().as_ref(), market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    /// CHECK: v0 layout, read by the instruction
+    pub user_position: AccountInfo<'info>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        constraint = *price_feed.key == market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_mint: Pubkey)]
+pub struct MigrateExchangeTreasuryPosition<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        mut,
+        seeds = [b"exchange_position".as_ref(), token_mint.as_ref()],
+        bump,
+    )]
+    /// CHECK: v0 layout, read by the instruction
+    pub exchange_treasury_position: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct MigrateYieldMarket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        mut,
+        seeds = [b"yield_market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    /// CHECK: v0 layout, read by the instruction
+    pub yield_market: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct MigrateUserYieldPosition<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: only used to derive the position addresses
+    pub owner: AccountInfo<'info>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_account: Box<Account<'info, UserAccount>>,
+    #[account(
+        seeds = [b"yield_market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub yield_market: Box<Account<'info, YieldMarket>>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        mut,
+        seeds = [b"user_yield_position".as_ref(), market_index.to_le_bytes().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    /// CHECK: v0 layout, read by the instruction
+    pub user_yield_position: AccountInfo<'info>,
+    #[account(
+        constraint = *price_feed.key == yield_market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct ExchangeV0 {
+    pub admin: Pubkey,
+    pub margin_used: i64,
+    pub number_of_markets: u16,
+    pub market_weight: u16,
+    pub basis: i64,
+    pub pnl: i64,
+    pub fees: i64,
+    pub collateral_value: i64,
+    pub leverage: u32,
+    pub rebates: i64,
+    pub rewards: i64,
+    pub last_rewards_claim: i64,
+    pub reward_frequency: u64,
+    pub reward_rate: u64,
+    pub test_mode: bool,
+    pub chainlink_program: Pubkey,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct MarketV0 {
+    pub market_index: u16,
+    pub market_weight: u16,
+    pub token_amount: i64,
+    pub basis: i64,
+    pub pnl: i64,
+    pub fees: i64,
+    pub taker_fee: i16,
+    pub maker_fee: i16,
+    pub leverage: u32,
+    pub margin_used: i64,
+    pub feed_address: Pubkey,
+    pub rebates: i64,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct UserAccountV0 {
+    pub owner: Pubkey,
+    pub collateral_value: i64,
+    pub margin_used: i64,
+    pub basis: i64,
+    pub pnl: i64,
+    pub fees: i64,
+    pub rebates: i64,
+    pub rewards: i64,
+    pub last_rewards_claim: i64,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct UserPositionV0 {
+    pub owner: Pubkey,
+    pub market_index: u16,
+    pub token_amount: i64,
+    pub basis: i64,
+    pub pnl: i64,
+    pub fees: i64,
+    pub margin_used: i64,
+    pub rebates: i64,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct ExchangeTreasuryPositionV0 {
+    pub token_mint: Pubkey,
+    pub active: bool,
+    pub treasury_weight: u16,
+    pub decimals: u8,
+    pub feed_address: Pubkey,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct YieldMarketV0 {
+    pub market_index: u16,
+    pub long_token_amount: i64,
+    pub short_token_amount: i64,
+    pub long_basis: i64,
+    pub short_basis: i64,
+    pub long_funding: i64,
+    pub short_funding: i64,
+    pub short_fees: i64,
+    pub long_fees: i64,
+    pub last_claim_date: i64,
+    pub chainlink_feed: Pubkey,
+}
+
+#[derive(AnchorDeserialize)]
+pub struct UserYieldPositionV0 {
+    pub owner: Pubkey,
+    pub market_index: u16,
+    pub long_token_amount: i64,
+    pub short_token_amount: i64,
+    pub long_basis: i64,
+    pub short_basis: i64,
+    pub long_funding: i64,
+    pub short_funding: i64,
+    pub short_fees: i64,
+    pub long_fees: i64,
+    pub last_claim_date: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a v0 body must be read exactly up to the space it over-reserved, or the
+    // size check would pass accounts whose fields are misaligned
+    fn assert_layout<T: AnchorDeserialize>(space: usize, padding: usize) {
+        let data = vec![0u8; space - 8];
+        let mut remaining = &data[..];
+        T::deserialize(&mut remaining).unwrap();
+        assert_eq!(remaining.len(), padding);
+        assert!(T::deserialize(&mut &data[padding + 1..]).is_err());
+    }
+
+    #[test]
+    fn v0_spaces_match_the_v0_layouts() {
+        assert_layout::<ExchangeV0>(EXCHANGE_V0_SPACE, 0);
+        assert_layout::<MarketV0>(MARKET_V0_SPACE, 0);
+        assert_layout::<UserAccountV0>(USER_ACCOUNT_V0_SPACE, 0);
+        assert_layout::<UserPositionV0>(USER_POSITION_V0_SPACE, 0);
+        assert_layout::<ExchangeTreasuryPositionV0>(EXCHANGE_TREASURY_POSITION_V0_SPACE, 1);
+        assert_layout::<YieldMarketV0>(YIELD_MARKET_V0_SPACE, 32);
+        assert_layout::<UserYieldPositionV0>(USER_YIELD_POSITION_V0_SPACE, 0);
+    }
+}
@@ -1,5 +1,21 @@
 pub mod exchange_state;
 pub mod chainlink_state;
+pub mod oracle_state;
+pub mod order_book_state;
+pub mod lp_vault_state;
+pub mod fee_tier_state;
+pub mod referral_state;
+pub mod reward_state;
+pub mod admin_state;
+pub mod migration_state;
 pub use exchange_state::*;
 pub use chainlink_state::*;
+pub use oracle_state::*;
+pub use order_book_state::*;
+pub use lp_vault_state::*;
+pub use fee_tier_state::*;
+pub use referral_state::*;
+pub use reward_state::*;
+pub use admin_state::*;
+pub use migration_state::*;
 
@@ -0,0 +1,247 @@
+use crate::state::{Decimal, Exchange};
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hash;
+
+// rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ, the Pyth receiver program that owns
+// pull oracle PriceUpdateV2 accounts
+pub const PYTH_RECEIVER_PROGRAM: Pubkey = Pubkey::new_from_array([
+    12, 183, 250, 187, 82, 247, 166, 72, 187, 91, 49, 125, 154, 1, 139, 144, 87, 203, 2, 71, 116,
+    250, 254, 1, 230, 196, 223, 152, 204, 56, 88, 129,
+]);
+
+const PYTH_PUSH_MAGIC: u32 = 0xa1b2c3d4;
+const PYTH_PUSH_VERSION: u32 = 2;
+const PYTH_PUSH_PRICE_ACCOUNT: u32 = 3;
+const PYTH_PUSH_TRADING: u32 = 1;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OracleSource {
+    Chainlink,
+    PythPush,
+    PythPull,
+    Fixed,
+    Mock,
+}
+
+pub struct OraclePrice {
+    pub price: i128,
+    pub decimals: u8,
+    pub publish_time: i64,
+    pub confidence: u128,
+}
+
+impl OraclePrice {
+    pub fn new(price: i64, confidence: u64, exponent: i32, publish_time: i64) -> Result<Self> {
+        // prices are always returned with a non-negative number of decimals
+        let mut price = price as i128;
+        let mut confidence = confidence as u128;
+        let mut decimals = 0;
+        if exponent > 0 {
+            let scale = 10u128
+                .checked_pow(exponent as u32)
+                .ok_or_else(|| error!(crate::KrunchErrors::OraclePriceInvalid))?;
+            price = i128::try_from(scale)
+                .ok()
+                .and_then(|scale| price.checked_mul(scale))
+                .ok_or_else(|| error!(crate::KrunchErrors::OraclePriceInvalid))?;
+            confidence = confidence
+                .checked_mul(scale)
+                .ok_or_else(|| error!(crate::KrunchErrors::OraclePriceInvalid))?;
+        } else {
+            decimals = exponent
+                .checked_neg()
+                .and_then(|decimals| u8::try_from(decimals).ok())
+                .ok_or_else(|| error!(crate::KrunchErrors::OraclePriceInvalid))?;
+        }
+        Ok(OraclePrice {
+            price,
+            decimals,
+            publish_time,
+            confidence,
+        })
+    }
+
+    pub fn to_decimal(&self) -> Decimal {
+        Decimal::new(self.price, self.decimals.into())
+    }
+}
+
+#[derive(AnchorDeserialize)]
+enum VerificationLevel {
+    Partial { _num_signatures: u8 },
+    Full,
+}
+
+#[derive(AnchorDeserialize)]
+struct PriceFeedMessage {
+    _feed_id: [u8; 32],
+    price: i64,
+    conf: u64,
+    exponent: i32,
+    publish_time: i64,
+    _prev_publish_time: i64,
+    _ema_price: i64,
+    _ema_conf: u64,
+}
+
+#[derive(AnchorDeserialize)]
+struct PriceUpdateV2 {
+    _write_authority: Pubkey,
+    verification_level: VerificationLevel,
+    price_message: PriceFeedMessage,
+    _posted_slot: u64,
+}
+
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N]> {
+    data.get(offset..offset + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| error!(crate::KrunchErrors::InvalidOracleAccount))
+}
+
+// legacy push oracle price account, see pyth-sdk-solana SolanaPriceAccount
+pub fn load_pyth_push_price(
+    price_feed: &AccountInfo,
+    pyth_program: &Pubkey,
+) -> Result<OraclePrice> {
+    if price_feed.owner != pyth_program {
+        return err!(crate::KrunchErrors::InvalidOracleAccount);
+    }
+    let data = price_feed.try_borrow_data()?;
+    let magic = u32::from_le_bytes(read_bytes(&data, 0)?);
+    let version = u32::from_le_bytes(read_bytes(&data, 4)?);
+    let account_type = u32::from_le_bytes(read_bytes(&data, 8)?);
+    if magic != PYTH_PUSH_MAGIC
+        || version != PYTH_PUSH_VERSION
+        || account_type != PYTH_PUSH_PRICE_ACCOUNT
+    {
+        return err!(crate::KrunchErrors::InvalidOracleAccount);
+    }
+    let exponent = i32::from_le_bytes(read_bytes(&data, 20)?);
+    let publish_time = i64::from_le_bytes(read_bytes(&data, 96)?);
+    let price = i64::from_le_bytes(read_bytes(&data, 208)?);
+    let confidence = u64::from_le_bytes(read_bytes(&data, 216)?);
+    let status = u32::from_le_bytes(read_bytes(&data, 224)?);
+    if status != PYTH_PUSH_TRADING {
+        return err!(crate::KrunchErrors::OraclePriceInvalid);
+    }
+    OraclePrice::new(price, confidence, exponent, publish_time)
+}
+
+// pull oracle PriceUpdateV2 account posted through the Pyth receiver program
+pub fn load_pyth_pull_price(price_feed: &AccountInfo) -> Result<OraclePrice> {
+    if *price_feed.owner != PYTH_RECEIVER_PROGRAM {
+        return err!(crate::KrunchErrors::InvalidOracleAccount);
+    }
+    let data = price_feed.try_borrow_data()?;
+    let discriminator = &hash(b"account:PriceUpdateV2").to_bytes()[..8];
+    if data.len() < 8 || &data[..8] != discriminator {
+        return err!(crate::KrunchErrors::InvalidOracleAccount);
+    }
+    let update = PriceUpdateV2::deserialize(&mut &data[8..])?;
+    if !matches!(update.verification_level, VerificationLevel::Full) {
+        return err!(crate::KrunchErrors::InvalidOracleAccount);
+    }
+    let message = update.price_message;
+    OraclePrice::new(
+        message.price,
+        message.conf,
+        message.exponent,
+        message.publish_time,
+    )
+}
+
+// admin controlled price used by integration tests while the exchange is in test mode
+pub fn load_mock_price(price_feed: &AccountInfo) -> Result<OraclePrice> {
+    if *price_feed.owner != crate::ID {
+        return err!(crate::KrunchErrors::InvalidOracleAccount);
+    }
+    let data = price_feed.try_borrow_data()?;
+    let mock_price = MockPrice::try_deserialize(&mut &data[..])?;
+    Ok(OraclePrice {
+        price: mock_price.price.into(),
+        decimals: mock_price.decimals,
+        publish_time: mock_price.publish_time,
+        confidence: 0,
+    })
+}
+
+#[derive(Accounts)]
+#[instruction(mock_index: u16)]
+pub struct AddMockPrice<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8
+                + 8 // price:i64
+                + 1 // decimals:u8
+                + 8 // publish_time:i64
+        ,
+        seeds = [b"mock_price".as_ref(), mock_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub mock_price: Account<'info, MockPrice>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(mock_index: u16)]
+pub struct UpdateMockPrice<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"mock_price".as_ref(), mock_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub mock_price: Account<'info, MockPrice>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+}
+
+#[account]
+pub struct MockPrice {
+    pub price: i64,
+    pub decimals: u8,
+    pub publish_time: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_invalid(result: Result<OraclePrice>) {
+        match result {
+            Err(Error::AnchorError(error)) => assert_eq!(error.error_name, "OraclePriceInvalid"),
+            Err(error) => panic!("unexpected error {}", error),
+            Ok(_) => panic!("expected OraclePriceInvalid"),
+        }
+    }
+
+    #[test]
+    fn exponents_scale_or_become_decimals() {
+        let price = OraclePrice::new(1234, 5, -2, 7).unwrap();
+        assert_eq!((price.price, price.decimals, price.confidence), (1234, 2, 5));
+        let price = OraclePrice::new(-12, 3, 2, 7).unwrap();
+        assert_eq!((price.price, price.decimals, price.confidence), (-1200, 0, 300));
+    }
+
+    #[test]
+    fn out_of_range_exponents_are_rejected() {
+        assert_invalid(OraclePrice::new(1, 0, 39, 0));
+        assert_invalid(OraclePrice::new(i64::MAX, 0, 20, 0));
+        assert_invalid(OraclePrice::new(1, 0, -256, 0));
+        assert_invalid(OraclePrice::new(1, 0, i32::MIN, 0));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::state::{
+    Exchange, FeeTiers, Market, Referrer, UserAccount, UserFeeOverride, UserPosition,
+};
+use anchor_lang::prelude::*;
+
+pub const MAX_ORDERS: usize = 32;
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct AddOrderBook<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8
+                + 2 // market_index:u16
+                + 8 // next_order_id:u64
+                + 4 + MAX_ORDERS * Order::SIZE // bids:Vec<Order>
+                + 4 + MAX_ORDERS * Order::SIZE // asks:Vec<Order>
+                + 2 // price_band:u16
+                + 8 // min_order_size:i64
+                + 1 // max_orders_per_user:u8
+        ,
+        seeds = [b"order_book".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub order_book: Box<Account<'info, OrderBook>>,
+    #[account(
+        seeds = [b"market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct UpdateOrderBook<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"order_book".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub order_book: Box<Account<'info, OrderBook>>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.risk_admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct PlaceOrder<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"order_book".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub order_book: Box<Account<'info, OrderBook>>,
+    #[account(
+        mut,
+        seeds = [b"market".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(),owner.key().as_ref()],
+        constraint = user_account.owner == owner.key(),
+        bump)]
+    pub user_account: Account<'info, UserAccount>,
+    #[account(
+        mut,
+        seeds = [b"user_position".as_ref(),owner.key().as_ref(),market_index.to_le_bytes().as_ref()],
+        bump)]
+    pub user_position: Account<'info, UserPosition>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Account<'info, Exchange>,
+
+    #[account(
+        constraint = *price_feed.key == market.feed_address,
+    )]
+    /// CHECK: validate price feed
+    pub price_feed: AccountInfo<'info>,
+
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>,
+    #[account(
+        seeds = [b"fee_tiers".as_ref()],
+        bump
+    )]
+    pub fee_tiers: Option<Account<'info, FeeTiers>>,
+    #[account(
+        seeds = [b"fee_override".as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub fee_override: Option<Account<'info, UserFeeOverride>>,
+    #[account(
+        mut,
+        seeds = [b"referrer".as_ref(), user_account.referrer.as_ref()],
+        constraint = referrer.owner == user_account.referrer,
+        bump
+    )]
+    pub referrer: Option<Account<'info, Referrer>>,
+}
+
+#[derive(Accounts)]
+#[instruction(market_index: u16)]
+pub struct CancelOrder<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"order_book".as_ref(), market_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub order_book: Box<Account<'info, OrderBook>>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct Order {
+    pub order_id: u64,
+    pub owner: Pubkey,
+    pub price: i64,
+    pub size: i64,
+    pub expiry: i64,
+}
+
+impl Order {
+    pub const SIZE: usize = 8 // order_id:u64
+        + 32 // owner:Pubkey
+        + 8 // price:i64
+        + 8 // size:i64
+        + 8; // expiry:i64
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expiry <= now
+    }
+}
+
+#[account]
+pub struct OrderBook {
+    pub market_index: u16,
+    pub next_order_id: u64,
+    pub bids: Vec<Order>,
+    pub asks: Vec<Order>,
+    pub price_band: u16,
+    pub min_order_size: i64,
+    pub max_orders_per_user: u8,
+}
+
+impl OrderBook {
+    // bids are kept highest price first and asks lowest price first,
+    // orders at the same price fill in the order they were placed
+    pub fn insert(&mut self, is_bid: bool, order: Order) -> Result<()> {
+        let orders = if is_bid {
+            &mut self.bids
+        } else {
+            &mut self.asks
+        };
+        if orders.len() >= MAX_ORDERS {
+            return err!(crate::KrunchErrors::OrderBookFull);
+        }
+        let index = orders
+            .iter()
+            .position(|resting| {
+                if is_bid {
+                    order.price > resting.price
+                } else {
+                    order.price < resting.price
+                }
+            })
+            .unwrap_or(orders.len());
+        orders.insert(index, order);
+        Ok(())
+    }
+
+    pub fn order_count(&self, owner: &Pubkey) -> usize {
+        self.bids
+            .iter()
+            .chain(self.asks.iter())
+            .filter(|order| order.owner == *owner)
+            .count()
+    }
+
+    pub fn remove(&mut self, order_id: u64, owner: &Pubkey) -> Result<Order> {
+        for orders in [&mut self.bids, &mut self.asks] {
+            if let Some(index) = orders.iter().position(|order| order.order_id == order_id) {
+                if orders[index].owner != *owner {
+                    return err!(crate::KrunchErrors::OrderOwnerMismatch);
+                }
+                return Ok(orders.remove(index));
+            }
+        }
+        err!(crate::KrunchErrors::OrderNotFound)
+    }
+}
@@ -0,0 +1,50 @@
+use crate::state::UserAccount;
+use anchor_lang::prelude::*;
+
+#[derive(Accounts)]
+pub struct CreateReferrer<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        init,
+        payer = owner,
+        space = 8
+                + 32 // owner:Pubkey
+                + 4 // referee_count:u32
+                + 8 // referral_fees:i64
+                + 8 // total_referral_fees:i64
+        ,
+        seeds = [b"referrer".as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub referrer: Account<'info, Referrer>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralFees<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"referrer".as_ref(), owner.key().as_ref()],
+        constraint = referrer.owner == owner.key(),
+        bump
+    )]
+    pub referrer: Account<'info, Referrer>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(), owner.key().as_ref()],
+        constraint = user_account.owner == owner.key(),
+        bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+}
+
+#[account]
+pub struct Referrer {
+    pub owner: Pubkey,
+    pub referee_count: u32,
+    pub referral_fees: i64,
+    pub total_referral_fees: i64,
+}
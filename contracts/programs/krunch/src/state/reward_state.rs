@@ -0,0 +1,153 @@
+use crate::state::{Exchange, UserAccount};
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+#[derive(Accounts)]
+pub struct InitializeRewardToken<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8
+                + 32 // reward_mint:Pubkey
+                + 8 // vesting_period:i64
+                + 8 // emission_budget:u64
+                + 8 // emitted:u64
+        ,
+        seeds = [b"reward_token".as_ref()],
+        bump
+    )]
+    pub reward_token: Account<'info, RewardToken>,
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"reward_mint".as_ref()],
+        bump,
+        mint::decimals = crate::AMOUNT_NUM_DECIMALS,
+        mint::authority = exchange,
+        mint::token_program = reward_token_program,
+    )]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    pub reward_token_program: Interface<'info, TokenInterface>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateRewardToken<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = exchange.admin == admin.key(),
+    )]
+    pub exchange: Account<'info, Exchange>,
+    #[account(
+        mut,
+        seeds = [b"reward_token".as_ref()],
+        bump
+    )]
+    pub reward_token: Account<'info, RewardToken>,
+}
+
+#[derive(Accounts)]
+pub struct VestRewards<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Box<Account<'info, Exchange>>,
+    #[account(
+        mut,
+        seeds = [b"user_account".as_ref(), owner.key().as_ref()],
+        constraint = user_account.owner == owner.key(),
+        bump
+    )]
+    pub user_account: Box<Account<'info, UserAccount>>,
+    #[account(
+        mut,
+        seeds = [b"reward_token".as_ref()],
+        bump
+    )]
+    pub reward_token: Account<'info, RewardToken>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8
+                + 32 // owner:Pubkey
+                + 8 // locked_amount:u64
+                + 8 // vested_amount:u64
+                + 8 // vesting_start:i64
+                + 8 // vesting_end:i64
+        ,
+        seeds = [b"reward_vesting".as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub reward_vesting: Account<'info, RewardVesting>,
+    system_program: Program<'info, System>,
+
+    #[account(
+        constraint = *chainlink_program.key == exchange.chainlink_program,
+    )]
+    /// CHECK: validate chainlink feed
+    pub chainlink_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimVested<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [b"exchange".as_ref()],
+        bump,
+        constraint = !exchange.paused @ crate::KrunchErrors::ExchangePaused,
+    )]
+    pub exchange: Account<'info, Exchange>,
+    #[account(
+        mut,
+        seeds = [b"reward_vesting".as_ref(), owner.key().as_ref()],
+        constraint = reward_vesting.owner == owner.key(),
+        bump
+    )]
+    pub reward_vesting: Account<'info, RewardVesting>,
+    #[account(
+        mut,
+        seeds = [b"reward_mint".as_ref()],
+        bump
+    )]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut,
+        constraint = user_reward_account.owner == owner.key(),
+        constraint = user_reward_account.mint == reward_mint.key(),
+    )]
+    pub user_reward_account: InterfaceAccount<'info, TokenAccount>,
+    pub reward_token_program: Interface<'info, TokenInterface>,
+}
+
+#[account]
+pub struct RewardToken {
+    pub reward_mint: Pubkey,
+    pub vesting_period: i64,
+    pub emission_budget: u64,
+    pub emitted: u64,
+}
+
+#[account]
+pub struct RewardVesting {
+    pub owner: Pubkey,
+    pub locked_amount: u64,
+    pub vested_amount: u64,
+    pub vesting_start: i64,
+    pub vesting_end: i64,
+}
@@ -0,0 +1,443 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { Krunch } from "../target/types/krunch";
+import { expect } from 'chai'
+import { PublicKey, Keypair, AccountMeta, LAMPORTS_PER_SOL } from '@solana/web3.js';
+import { findAddress } from 'utils/src/utils'
+import {
+  createMint,
+  getAccount,
+  getOrCreateAssociatedTokenAccount,
+  mintTo,
+  TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
+} from "@solana/spl-token"
+
+export const PRICE_DECIMALS = 10 ** 9;
+export const FEE_DECIMALS = 10 ** 4;
+export const MARGIN_DECIMALS = 10 ** 4;
+export const MARKET_WEIGHT_DECIMALS = 10 ** 4;
+export const AMOUNT_DECIMALS = 10 ** 9;
+export const LEVERAGE_DECIMALS = 10 ** 4;
+export const FUNDING_RATE_DECIMALS = 10 ** 9;
+export const USDC_DECIMALS = 6;
+
+// the chainlink program is only compared against, every feed in these tests is a mock
+export const CHAINLINK_PROGRAM = new PublicKey("HEvSKofvBgfaexv23kMabbYqxasxU3mQ4ibBMEmJWHny");
+export const PYTH_PROGRAM = new PublicKey("FsJ3A3u2vn5cTVofAjvy6y5kwABJAqYWpe4975bi2epH");
+
+export const MARKET_1 = 1;
+export const MARKET_2 = 2;
+export const YIELD_MARKET = 1;
+export const MARKET_FEEDS = { [MARKET_1]: 1, [MARKET_2]: 2 };
+export const YIELD_FEED = 3;
+export const TAKER_FEE = .001 * FEE_DECIMALS;
+export const MAKER_FEE = -.0002 * FEE_DECIMALS;
+export const INITIAL_MARGIN = .1 * MARGIN_DECIMALS;
+export const MAINTENANCE_MARGIN = .05 * MARGIN_DECIMALS;
+export const FUNDING_PERIOD = 3600;
+export const MAX_FUNDING_RATE = .01 * FUNDING_RATE_DECIMALS;
+export const MAX_PRICE_AGE = 600;
+export const INSURANCE_FEE_SHARE = .1 * FEE_DECIMALS;
+export const REFERRER_FEE_SHARE = .2 * FEE_DECIMALS;
+export const REFEREE_FEE_DISCOUNT = .1 * FEE_DECIMALS;
+export const REWARD_FREQUENCY = 2;
+
+export const bn = (value: number) => new anchor.BN(Math.round(value));
+export const tokens = (amount: number) => bn(amount * AMOUNT_DECIMALS);
+export const usd = (value: number) => bn(value * AMOUNT_DECIMALS);
+export const sleep = (seconds: number) => new Promise(resolve => setTimeout(resolve, seconds * 1000));
+
+export const expectError = async (promise: Promise<any>, code: string) => {
+  try {
+    await promise;
+  } catch (err) {
+    const message = err instanceof anchor.AnchorError ? err.error.errorCode.code : `${err}`;
+    expect(message).to.contain(code);
+    return;
+  }
+  expect.fail(`expected ${code}`);
+};
+
+// Configure the client to use the local cluster.
+anchor.setProvider(anchor.AnchorProvider.env());
+
+export const program = anchor.workspace.Krunch as Program<Krunch>;
+const pg = anchor.AnchorProvider.env()
+export const connection = pg.connection;
+export const payer = (pg.wallet as anchor.Wallet).payer;
+export const admin = pg.wallet.publicKey;
+
+export const trader = Keypair.generate();
+export const maker = Keypair.generate();
+export const victim = Keypair.generate();
+export const liquidator = Keypair.generate();
+export const referee = Keypair.generate();
+export const guardian = Keypair.generate();
+export const newAdmin = Keypair.generate();
+
+export let usdc: PublicKey;
+export let exchange: PublicKey;
+const tokenAccounts = new Map<string, PublicKey>();
+
+export const now = async () => (await connection.getBlockTime(await connection.getSlot()))!;
+export const address = (...seeds: any[]) => findAddress(program, seeds);
+export const userAccount = (owner: PublicKey) => address("user_account", owner);
+export const userPosition = (owner: PublicKey, marketIndex: number) => address("user_position", owner, marketIndex);
+export const market = (marketIndex: number) => address("market", marketIndex);
+export const mockPrice = (mockIndex: number) => address("mock_price", mockIndex);
+export const priceFeed = (marketIndex: number) => mockPrice(MARKET_FEEDS[marketIndex]);
+
+export const setPrice = async (mockIndex: number, price: number, publishTime?: number) => {
+  await program.methods.updateMockPrice(mockIndex, bn(price * PRICE_DECIMALS), 9, bn(publishTime ?? await now()))
+    .accounts({ admin, mockPrice: await mockPrice(mockIndex), exchange })
+    .rpc();
+};
+
+// every open position of the owner as (user_position, market, price_feed), then every
+// open yield position as (user_yield_position, yield_market, price_feed), then every
+// mint they hold as (user_collateral, exchange_treasury_position, price_feed)
+export const openPositions = async (owner: PublicKey, excludedMarket?: number, excludedMint?: PublicKey,
+  excludedYieldMarket?: number): Promise<AccountMeta[]> => {
+  const accounts: AccountMeta[] = [];
+  for (const marketIndex of [MARKET_1, MARKET_2]) {
+    if (marketIndex === excludedMarket) {
+      continue;
+    }
+    const position = await program.account.userPosition.fetchNullable(await userPosition(owner, marketIndex));
+    if (!position || position.tokenAmount.isZero()) {
+      continue;
+    }
+    accounts.push(
+      { pubkey: await userPosition(owner, marketIndex), isWritable: true, isSigner: false },
+      { pubkey: await market(marketIndex), isWritable: true, isSigner: false },
+      { pubkey: await priceFeed(marketIndex), isWritable: false, isSigner: false },
+    );
+  }
+  const yieldPositions = await program.account.userYieldPosition.all([{ memcmp: { offset: 8, bytes: owner.toBase58() } }]);
+  for (const { publicKey, account } of yieldPositions) {
+    if ((account.longTokenAmount.isZero() && account.shortTokenAmount.isZero())
+      || account.marketIndex === excludedYieldMarket) {
+      continue;
+    }
+    const yieldMarket = await address("yield_market", account.marketIndex);
+    const { feedAddress } = await program.account.yieldMarket.fetch(yieldMarket);
+    accounts.push(
+      { pubkey: publicKey, isWritable: true, isSigner: false },
+      { pubkey: yieldMarket, isWritable: true, isSigner: false },
+      { pubkey: feedAddress, isWritable: false, isSigner: false },
+    );
+  }
+  const collaterals = await program.account.userCollateral.all([{ memcmp: { offset: 8, bytes: owner.toBase58() } }]);
+  for (const { publicKey, account } of collaterals) {
+    if (account.tokenAmount.isZero() || account.mint.equals(excludedMint ?? PublicKey.default)) {
+      continue;
+    }
+    const treasuryPosition = await address("exchange_position", account.mint);
+    const { feedAddress } = await program.account.exchangeTreasuryPosition.fetch(treasuryPosition);
+    accounts.push(
+      { pubkey: publicKey, isWritable: true, isSigner: false },
+      { pubkey: treasuryPosition, isWritable: false, isSigner: false },
+      { pubkey: feedAddress, isWritable: false, isSigner: false },
+    );
+  }
+  return accounts;
+};
+
+// every market as (market, price_feed) for the lp vault valuation
+export const allMarkets = async (): Promise<AccountMeta[]> => {
+  const accounts: AccountMeta[] = [];
+  for (const marketIndex of [MARKET_1, MARKET_2]) {
+    accounts.push(
+      { pubkey: await market(marketIndex), isWritable: false, isSigner: false },
+      { pubkey: await priceFeed(marketIndex), isWritable: false, isSigner: false },
+    );
+  }
+  return accounts;
+};
+
+export const tokenAccount = async (owner: PublicKey, mint = usdc, tokenProgram = TOKEN_PROGRAM_ID) => {
+  const key = `${owner}:${mint}`;
+  if (!tokenAccounts.has(key)) {
+    const account = await getOrCreateAssociatedTokenAccount(
+      connection, payer, mint, owner, false, undefined, undefined, tokenProgram);
+    tokenAccounts.set(key, account.address);
+  }
+  return tokenAccounts.get(key)!;
+};
+
+export const collateralAccounts = async (owner: PublicKey, mint = usdc, tokenProgram = TOKEN_PROGRAM_ID) => ({
+  owner,
+  exchange,
+  userAccount: await userAccount(owner),
+  userTokenAccount: await tokenAccount(owner, mint, tokenProgram),
+  tokenProgram,
+  mint,
+  escrowAccount: await address(exchange, mint),
+  userCollateral: await address("user_collateral", owner, mint),
+  exchangeTreasuryPosition: await address("exchange_position", mint),
+  priceFeed: mint,
+  chainlinkProgram: CHAINLINK_PROGRAM,
+});
+
+export const deposit = async (user: Keypair, value: number) => {
+  await program.methods.deposit(usd(value))
+    .accounts(await collateralAccounts(user.publicKey))
+    .remainingAccounts(await openPositions(user.publicKey, undefined, usdc))
+    .signers([user])
+    .rpc();
+};
+
+export const tradeAccounts = async (owner: PublicKey, marketIndex: number) => ({
+  owner,
+  market: await market(marketIndex),
+  userAccount: await userAccount(owner),
+  userPosition: await userPosition(owner, marketIndex),
+  exchange,
+  priceFeed: await priceFeed(marketIndex),
+  chainlinkProgram: CHAINLINK_PROGRAM,
+  feeTiers: null,
+  feeOverride: null,
+  referrer: null,
+});
+
+export const trade = async (user: Keypair, marketIndex: number, amount: number, options: {
+  limitPrice?: number, reduceOnly?: boolean, accounts?: any
+} = {}) => {
+  const limitPrice = options.limitPrice === undefined ? null : bn(options.limitPrice * AMOUNT_DECIMALS);
+  await program.methods.executeTrade(marketIndex, tokens(amount), limitPrice, options.reduceOnly ?? false)
+    .accounts({ ...await tradeAccounts(user.publicKey, marketIndex), ...options.accounts })
+    .remainingAccounts(await openPositions(user.publicKey, marketIndex))
+    .signers([user])
+    .rpc();
+};
+
+export const marketParams = (overrides: any = {}) => {
+  const params = {
+    initialMargin: INITIAL_MARGIN,
+    maintenanceMargin: MAINTENANCE_MARGIN,
+    marketWeight: MARKET_WEIGHT_DECIMALS,
+    fundingPeriod: bn(FUNDING_PERIOD),
+    maxFundingRate: bn(MAX_FUNDING_RATE),
+    maxLongOpenInterest: tokens(1_000_000),
+    maxShortOpenInterest: tokens(1_000_000),
+    maxPositionSize: tokens(100_000),
+    ...overrides,
+  };
+  return [params.initialMargin, params.maintenanceMargin, params.marketWeight, params.fundingPeriod,
+  params.maxFundingRate, params.maxLongOpenInterest, params.maxShortOpenInterest, params.maxPositionSize];
+};
+
+export const updateMarket = async (marketIndex: number, overrides: any = {}) => {
+  const [initialMargin, maintenanceMargin, marketWeight, fundingPeriod, maxFundingRate,
+    maxLongOpenInterest, maxShortOpenInterest, maxPositionSize] = marketParams(overrides);
+  await program.methods.updateMarket(marketIndex, initialMargin, maintenanceMargin, marketWeight, fundingPeriod,
+    maxFundingRate, maxLongOpenInterest, maxShortOpenInterest, maxPositionSize)
+    .accounts({
+      owner: admin,
+      market: await market(marketIndex),
+      exchange,
+      priceFeed: await priceFeed(marketIndex),
+      chainlinkProgram: CHAINLINK_PROGRAM,
+    })
+    .rpc();
+};
+
+export const orderAccounts = async (owner: PublicKey, marketIndex: number) => ({
+  owner,
+  orderBook: await address("order_book", marketIndex),
+  market: await market(marketIndex),
+  userAccount: await userAccount(owner),
+  userPosition: await userPosition(owner, marketIndex),
+  exchange,
+  priceFeed: await priceFeed(marketIndex),
+  chainlinkProgram: CHAINLINK_PROGRAM,
+  feeTiers: null,
+  feeOverride: null,
+  referrer: null,
+});
+
+// each maker is followed by its own open positions for the margin check
+export const makerAccounts = async (owner: PublicKey, marketIndex: number): Promise<AccountMeta[]> => [
+  { pubkey: await userAccount(owner), isWritable: true, isSigner: false },
+  { pubkey: await userPosition(owner, marketIndex), isWritable: true, isSigner: false },
+  { pubkey: await address("fee_override", owner), isWritable: false, isSigner: false },
+  ...await openPositions(owner, marketIndex),
+];
+
+export const placeOrder = async (user: Keypair, marketIndex: number, amount: number, price: number, options: {
+  expiry?: number, makers?: Keypair[], referrer?: PublicKey
+} = {}) => {
+  const makers: AccountMeta[] = [];
+  for (const m of options.makers ?? []) {
+    makers.push(...await makerAccounts(m.publicKey, marketIndex));
+  }
+  await program.methods.placeOrder(marketIndex, tokens(amount), bn(price * AMOUNT_DECIMALS),
+    bn(options.expiry ?? (await now()) + 3600))
+    .accounts({ ...await orderAccounts(user.publicKey, marketIndex), referrer: options.referrer ?? null })
+    .remainingAccounts([...await openPositions(user.publicKey, marketIndex), ...makers])
+    .signers([user])
+    .rpc();
+};
+
+export const ordersOf = async (owner: PublicKey, marketIndex: number) => {
+  const orderBook = await program.account.orderBook.fetch(await address("order_book", marketIndex));
+  return [...orderBook.bids, ...orderBook.asks].filter(order => order.owner.equals(owner));
+};
+
+export const cancelOrders = async (user: Keypair, marketIndex: number) => {
+  for (const order of await ordersOf(user.publicKey, marketIndex)) {
+    await program.methods.cancelOrder(marketIndex, order.orderId)
+      .accounts({ owner: user.publicKey, orderBook: await address("order_book", marketIndex) })
+      .signers([user])
+      .rpc();
+  }
+};
+
+export const settlePnl = async (user: Keypair) => {
+  const { userTokenAccount, tokenProgram, ...accounts } = await collateralAccounts(user.publicKey);
+  await program.methods.settlePnl()
+    .accounts(accounts)
+    .remainingAccounts(await openPositions(user.publicKey, undefined, usdc))
+    .signers([user])
+    .rpc();
+};
+
+export const updateYield = async (user: Keypair, longAmount: number, shortAmount: number) => {
+  await program.methods.updateYield(YIELD_MARKET, tokens(longAmount), tokens(shortAmount))
+    .accounts({
+      owner: user.publicKey,
+      userYieldPosition: await address("user_yield_position", YIELD_MARKET, user.publicKey),
+      userAccount: await userAccount(user.publicKey),
+      yieldMarket: await address("yield_market", YIELD_MARKET),
+      priceFeed: await mockPrice(YIELD_FEED),
+      exchange,
+      chainlinkProgram: CHAINLINK_PROGRAM,
+    })
+    .remainingAccounts(await openPositions(user.publicKey, undefined, undefined, YIELD_MARKET))
+    .signers([user])
+    .rpc();
+};
+
+export const updateCollateral = async (owner: PublicKey) => {
+  await program.methods.updateCollateral()
+    .accounts({
+      exchange,
+      userAccount: await userAccount(owner),
+      userCollateral: await address("user_collateral", owner, usdc),
+      exchangeTreasuryPosition: await address("exchange_position", usdc),
+      priceFeed: usdc,
+      chainlinkProgram: CHAINLINK_PROGRAM,
+    })
+    .rpc();
+};
+
+export const insuranceAccounts = async () => ({
+  exchange,
+  tokenProgram: TOKEN_PROGRAM_ID,
+  mint: usdc,
+  escrowAccount: await address(exchange, usdc),
+  insuranceVault: await address("insurance_fund", usdc),
+  exchangeTreasuryPosition: await address("exchange_position", usdc),
+  priceFeed: usdc,
+  chainlinkProgram: CHAINLINK_PROGRAM,
+});
+
+// the exchange, markets, collateral and users every request's tests start from
+export const setup = () => {
+  it("initializes the exchange, markets and collateral", async () => {
+    exchange = await address("exchange");
+    await program.methods.initializeExchange(
+      10 * LEVERAGE_DECIMALS,
+      bn(REWARD_FREQUENCY),
+      bn(.5 * AMOUNT_DECIMALS),
+      true,
+      MARKET_WEIGHT_DECIMALS,
+      CHAINLINK_PROGRAM,
+      PYTH_PROGRAM,
+      .01 * FEE_DECIMALS,
+      .5 * FEE_DECIMALS,
+      INSURANCE_FEE_SHARE,
+      REFERRER_FEE_SHARE,
+      REFEREE_FEE_DISCOUNT,
+    ).accounts({ admin, exchange }).rpc();
+
+    for (const [mockIndex, price] of [[1, 10], [2, 3.333333333], [YIELD_FEED, 1]]) {
+      await program.methods.addMockPrice(mockIndex, bn(price * PRICE_DECIMALS), 9)
+        .accounts({ admin, mockPrice: await mockPrice(mockIndex), exchange })
+        .rpc();
+    }
+
+    for (const marketIndex of [MARKET_1, MARKET_2]) {
+      await program.methods.addMarket(
+        marketIndex,
+        TAKER_FEE,
+        MAKER_FEE,
+        INITIAL_MARGIN,
+        MAINTENANCE_MARGIN,
+        MARKET_WEIGHT_DECIMALS,
+        await priceFeed(marketIndex),
+        bn(FUNDING_PERIOD),
+        bn(MAX_FUNDING_RATE),
+        MAX_PRICE_AGE,
+        { mock: {} },
+        bn(0),
+        tokens(1_000_000),
+        tokens(1_000_000),
+        tokens(100_000),
+      ).accounts({ admin, market: await market(marketIndex), exchange }).rpc();
+    }
+
+    // usdc is valued at a fixed $1
+    usdc = await createMint(connection, payer, admin, null, USDC_DECIMALS);
+    await program.methods.addExchangePosition(
+      usdc, true, MARKET_WEIGHT_DECIMALS, USDC_DECIMALS, usdc, MAX_PRICE_AGE, { fixed: {} }, bn(PRICE_DECIMALS),
+    ).accounts({ admin, exchangeTreasuryPosition: await address("exchange_position", usdc), exchange }).rpc();
+
+    await mintTo(connection, payer, usdc, await tokenAccount(admin), payer, 1_000_000 * 10 ** USDC_DECIMALS);
+    const { owner, userAccount: _, userCollateral: __, ...houseAccounts } = await collateralAccounts(admin);
+    await program.methods.exchangeDeposit(usd(100_000))
+      .accounts({ owner, ...houseAccounts })
+      .rpc();
+
+    const exchangeAccount = await program.account.exchange.fetch(exchange);
+    expect(exchangeAccount.numberOfMarkets).to.equal(2);
+    expect(exchangeAccount.houseCollateralValue.toString()).to.equal(usd(100_000).toString());
+    expect(exchangeAccount.riskAdmin.equals(admin)).to.be.true;
+  });
+
+  it("creates and funds user accounts", async () => {
+    for (const user of [trader, maker, victim, liquidator, referee, guardian, newAdmin]) {
+      await connection.confirmTransaction(
+        await connection.requestAirdrop(user.publicKey, 10 * LAMPORTS_PER_SOL), "confirmed");
+    }
+    for (const user of [trader, maker, victim, liquidator]) {
+      await mintTo(connection, payer, usdc, await tokenAccount(user.publicKey), payer, 10_000 * 10 ** USDC_DECIMALS);
+      await program.methods.createUserAccount()
+        .accounts({ owner: user.publicKey, userAccount: await userAccount(user.publicKey), referrer: null })
+        .signers([user])
+        .rpc();
+    }
+    for (const [user, marketIndex] of [[trader, MARKET_1], [trader, MARKET_2], [maker, MARKET_1], [victim, MARKET_1]] as [Keypair, number][]) {
+      await program.methods.addUserPosition(marketIndex)
+        .accounts({
+          owner: user.publicKey,
+          userPosition: await userPosition(user.publicKey, marketIndex),
+          userAccount: await userAccount(user.publicKey),
+          market: await market(marketIndex),
+        })
+        .signers([user])
+        .rpc();
+    }
+    await deposit(trader, 1_000);
+    await deposit(maker, 1_000);
+    await deposit(victim, 20);
+    await deposit(liquidator, 1_000);
+
+    const collateral = await program.account.userCollateral.fetch(await address("user_collateral", trader.publicKey, usdc));
+    expect(collateral.tokenAmount.toNumber()).to.equal(1_000 * 10 ** USDC_DECIMALS);
+    const account = await program.account.userAccount.fetch(await userAccount(trader.publicKey));
+    expect(account.collateralValue.toString()).to.equal(usd(1_000).toString());
+    expect(account.weightedCollateralValue.toString()).to.equal(usd(1_000).toString());
+  });
+};
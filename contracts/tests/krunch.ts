@@ -1,192 +1,64 @@
-import * as anchor from "@coral-xyz/anchor";
-import { Program } from "@coral-xyz/anchor";
-import { Krunch } from "../target/types/krunch";
-import { expect } from 'chai'
-import { PublicKey, Keypair } from '@solana/web3.js';
-import { findAddress, fetchOrCreateAccount, fetchAccount } from 'utils/src/utils' 
-import {
-  getAssociatedTokenAddress,
-  createMint,
-  TOKEN_PROGRAM_ID,
-  ASSOCIATED_TOKEN_PROGRAM_ID,
-  getAccount,
-} from "@solana/spl-token"
-
-console.log("TOKEN_PROGRAM_ID", TOKEN_PROGRAM_ID.toString())
-console.log("ASSOCIATED_TOKEN_PROGRAM_ID", ASSOCIATED_TOKEN_PROGRAM_ID.toString())
-const PRICE_DECIMALS = 10 ** 9;
-const FEE_DECIMALS = 10 ** 4;
-const MARKET_WEIGHT_DECIMALS = 10 ** 4;
-const AMOUNT_DECIMALS = 10 ** 9;
-const LEVERAGE_DECIMALS = 10 ** 4;
-
+import { setup } from "./harness";
+import * as user001 from "./user-001";
+import * as user002 from "./user-002";
+import * as user003 from "./user-003";
+import * as user004 from "./user-004";
+import * as user005 from "./user-005";
+import * as user006 from "./user-006";
+import * as user007 from "./user-007";
+import * as user008 from "./user-008";
+import * as user009 from "./user-009";
+import * as user010 from "./user-010";
+import * as user011 from "./user-011";
+import * as user012 from "./user-012";
+import * as user013 from "./user-013";
+import * as user014 from "./user-014";
+import * as user015 from "./user-015";
+import * as user016 from "./user-016";
+import * as user017 from "./user-017";
+import * as user019 from "./user-019";
+import * as user020 from "./user-020";
+import * as user021 from "./user-021";
+import * as user022 from "./user-022";
+import * as user024 from "./user-024";
+import * as user025 from "./user-025";
+
+// each request keeps its tests in its own file, they run in this order since
+// later tests build on the state earlier ones leave
 describe("krunch", () => {
-  // Configure the client to use the local cluster.
-  anchor.setProvider(anchor.AnchorProvider.env());
-
-  const program = anchor.workspace.Krunch as Program<Krunch>;
-  const pg = anchor.AnchorProvider.env()
-  
-  it("Is initialized!", async () => {
-
-    const exchangeBuffer = Buffer.from("exchange");
-    const [exchange, _exchangeBump] =
-      await anchor.web3.PublicKey.findProgramAddress(
-        [exchangeBuffer],
-        program.programId
-      );
-    const tx = await program.methods.initializeExchange().accounts({
-      exchange
-    }).rpc();
-    console.log("Your transaction signature", tx);
-  });
-
-  it("create_user_account", async () => {
-    const [userAccount] =
-      await anchor.web3.PublicKey.findProgramAddress(
-        [
-          Buffer.from("user_account"),
-          pg.wallet.publicKey.toBuffer()
-        ],
-        program.programId
-      );
-    const tx = await program.methods.createUserAccount().accounts({
-      userAccount,
-
-    }).rpc();
-    console.log("create_user_account transaction signature", tx);
-  });
-
-  it("Add Market", async () => {
-    // Add your test here.
-    const market_index = 1;
-    const tx = await program.methods.addMarket(1,
-      .1 * FEE_DECIMALS,
-      -.1 * FEE_DECIMALS,
-      1 * LEVERAGE_DECIMALS,
-      .1 * MARKET_WEIGHT_DECIMALS).accounts({
-        market: await findAddress(program, ["market", market_index]),
-        exchange: await findAddress(program, ["exchange"])
-      }).rpc();
-    console.log("Your transaction signature", tx);
-  });
-
-  it("Update Price", async () => {
-    const market_index = 1;
-    const market = await findAddress(program, ["market", market_index])
-    const tx = await program.methods.updateMarket(
-      1
-      , new anchor.BN(10 * PRICE_DECIMALS)
-      , .1 * FEE_DECIMALS
-      , .1 * FEE_DECIMALS
-      , 1 * LEVERAGE_DECIMALS
-      , .1 * MARKET_WEIGHT_DECIMALS
-    ).accounts({
-      market
-    }).rpc();
-    console.log("Your transaction signature", tx);
-    const marketUpdated = await program.account.market.fetch(market);
-    
-  });
-
-  it("Add User Position", async () => {
-    const market_index = 1;
-    const tx = await program.methods.addUserPosition(1).accounts({
-      userPosition: await findAddress(program, ["user_position", pg.wallet.publicKey, market_index]),
-      userAccount: await findAddress(program, ["user_account", pg.wallet.publicKey]),
-      market: await findAddress(program, ["market", market_index])
-    }).rpc();
-    console.log("Your transaction signature", tx);
-  });
-
-  it("execute_trade 1", async () => {
-    const market_index = 1;
-
-    const userPosition = await findAddress(program, ["user_position", pg.wallet.publicKey, market_index])
-    const userAccount = await findAddress(program, ["user_account", pg.wallet.publicKey])
-    const market = await findAddress(program, ["market", market_index])
-    const exchange = await findAddress(program, ["exchange"])
-
-    const tx = await program.methods.executeTrade(1, new anchor.BN(4 * AMOUNT_DECIMALS)).accounts({
-      userPosition,
-      userAccount,
-      market,
-      exchange
-
-    }).rpc();
-    console.log("execute_trade transaction signature", tx);
-
-    const exchangeUpdated = await program.account.exchange.fetch(exchange);
-    console.log("exchange basis: ", exchangeUpdated.basis.toNumber());
-    console.log("exchange fees: ", exchangeUpdated.fees.toNumber());
-
-    const userAccountUpdated = await program.account.userAccount.fetch(userAccount);
-    console.log("userAccount collateralValue: ", userAccountUpdated.collateralValue.toNumber());
-    console.log("userAccount fees: ", userAccountUpdated.fees.toNumber());
-
-    const marketUpdated = await program.account.market.fetch(market);
-    console.log("market tokenAmount is: ", marketUpdated.tokenAmount.toNumber());
-    console.log("market basis is: ", marketUpdated.basis.toNumber());
-    console.log("market fees is: ", marketUpdated.fees.toNumber());
-    expect(marketUpdated.tokenAmount.toNumber(), 'market tokenAmount should be 4').to.equal(4 * AMOUNT_DECIMALS);
-
-    const userPositionUpdated = await program.account.userPosition.fetch(userPosition);
-    console.log("userPositionUpdated tokenAmount is: ", userPositionUpdated.tokenAmount.toNumber());
-    console.log("userPositionUpdated basis: ", userPositionUpdated.basis.toNumber());
-    console.log("userPositionUpdated fees: ", userPositionUpdated.fees.toNumber());
-    expect(userPositionUpdated.tokenAmount.toNumber(), 'userPositionUpdated tokenAmount should be 4').to.equal(4 * AMOUNT_DECIMALS);
-
-  });
-
-
-  it("execute_trade 2", async () => {
-    const market_index = 1;
-
-    const userPosition = await findAddress(program, ["user_position", pg.wallet.publicKey, market_index])
-    const userAccount = await findAddress(program, ["user_account", pg.wallet.publicKey])
-    const market = await findAddress(program, ["market", market_index])
-    const exchange = await findAddress(program, ["exchange"])
-
-    await program.methods.updateMarket(
-      1
-      , new anchor.BN(10 * PRICE_DECIMALS)
-      , .1 * FEE_DECIMALS
-      , .1 * FEE_DECIMALS
-      , 1 * LEVERAGE_DECIMALS
-      , .1 * MARKET_WEIGHT_DECIMALS
-    ).accounts({
-      market
-    }).rpc();
-
-    const tx = await program.methods.executeTrade(1, new anchor.BN(-4 * AMOUNT_DECIMALS)).accounts({
-      userPosition,
-      userAccount,
-      market,
-      exchange
-
-    }).rpc();
-    console.log("execute_trade transaction signature", tx);
-
-    const exchangeUpdated = await program.account.exchange.fetch(exchange);
-    console.log("exchange basis: ", exchangeUpdated.basis.toNumber());
-    console.log("exchange fees: ", exchangeUpdated.fees.toNumber());
-
-    const userAccountUpdated = await program.account.userAccount.fetch(userAccount);
-    console.log("userAccount collateralValue: ", userAccountUpdated.collateralValue.toNumber());
-    console.log("userAccount fees: ", userAccountUpdated.fees.toNumber());
-
-    const marketUpdated = await program.account.market.fetch(market);
-    console.log("market tokenAmount is: ", marketUpdated.tokenAmount.toNumber());
-    console.log("market basis is: ", marketUpdated.basis.toNumber());
-    console.log("market fees is: ", marketUpdated.fees.toNumber());
-    expect(marketUpdated.tokenAmount.toNumber(), 'market tokenAmount should be 4').to.equal(4);
-
-    const userPositionUpdated = await program.account.userPosition.fetch(userPosition);
-    console.log("userPositionUpdated tokenAmount is: ", userPositionUpdated.tokenAmount.toNumber());
-    console.log("userPositionUpdated basis: ", userPositionUpdated.basis.toNumber());
-    console.log("userPositionUpdated fees: ", userPositionUpdated.fees.toNumber());
-    expect(userPositionUpdated.tokenAmount.toNumber(), 'userPositionUpdated tokenAmount should be 4').to.equal(4);
-
-  });
-
+  setup();
+  user003.countsOpenPositionsAndFundsTheSkew();
+  user003.requiresEveryOpenPositionToBePassed();
+  user012.roundsTradeBasisInTheHousesFavour();
+  user008.enforcesLimitPricesAndReduceOnlyTrades();
+  user010.capsPositionSizeAndOpenInterest();
+  user002.checksInitialMarginAndValidatesMarginParameters();
+  user004.rejectsStaleAndInvalidOraclePrices();
+  user005.onlyReadsPythPricesFromPythOwnedAccounts();
+  user006.onlyUsesMockPricesInTestMode();
+  user011.appliesFeeTiersAndSplitsTheInsuranceShare();
+  user007.validatesOrdersAndLimitsRestingOrdersPerUser();
+  user009.keepsPositionsWithRestingOrdersOpen();
+  user007.fillsAgainstMakersAtTheirFeeOverride();
+  user007.dropsRestingOrdersOutsideTheOraclePriceBand();
+  user009.settlesRealizedPnlIntoCollateral();
+  user011.movesCollectedInsuranceFeesIntoTheVault();
+  user001.liquidatesUnderwaterAccountsAndCoversBadDebt();
+  user011.paysInsuranceClaimsFromTheVaultIntoEscrow();
+  user013.withdrawsFromTheBalanceHeldInEachMint();
+  user014.appliesTreasuryWeightsAndTheActiveFlag();
+  user015.acceptsToken2022Collateral();
+  user016.limitsHouseWithdrawalsToTheHouseDeposits();
+  user017.pricesLpSharesAgainstEveryMarket();
+  user019.sharesDiscountedFeesWithTheReferrer();
+  user020.streamsRewardsOverEpochs();
+  user021.vestsRewardTokensUpToTheEmissionBudget();
+  user022.letsUsersTradeAndSettleYield();
+  user022.countsYieldPositionsInHealthAndLiquidatesThem();
+  user024.validatesTheYieldFundingCurveAndAccruesBeforeChangingIt();
+  user025.transfersAdminInTwoSteps();
+  user025.pausesTradingWithdrawalsAndLiquidationsButNotDeposits();
+  user025.onlyMigratesAccountsStillInTheV0Layout();
+  user013.onlyMigratesTreasuryPositionsStillInTheV0Layout();
+  user022.onlyMigratesYieldAccountsStillInTheV0Layout();
 });
@@ -0,0 +1,77 @@
+import { expect } from 'chai'
+import {
+  CHAINLINK_PROGRAM,
+  MARKET_1,
+  MARKET_FEEDS,
+  bn,
+  exchange,
+  expectError,
+  liquidator,
+  market,
+  openPositions,
+  priceFeed,
+  program,
+  setPrice,
+  tokens,
+  usd,
+  userAccount,
+  userPosition,
+  victim,
+} from "./harness";
+
+export const liquidatesUnderwaterAccountsAndCoversBadDebt = () => {
+  it("[user-001] [user-011] liquidates underwater accounts and covers bad debt", async () => {
+    const liquidateAccounts = async () => ({
+      liquidator: liquidator.publicKey,
+      liquidatorAccount: await userAccount(liquidator.publicKey),
+      userAccount: await userAccount(victim.publicKey),
+      userPosition: await userPosition(victim.publicKey, MARKET_1),
+      market: await market(MARKET_1),
+      exchange,
+      priceFeed: await priceFeed(MARKET_1),
+      chainlinkProgram: CHAINLINK_PROGRAM,
+    });
+
+    await expectError(
+      program.methods.liquidateUser(MARKET_1, bn(0))
+        .accounts(await liquidateAccounts())
+        .remainingAccounts(await openPositions(victim.publicKey, MARKET_1))
+        .signers([liquidator])
+        .rpc(),
+      "UserNotLiquidatable");
+    await expectError(
+      program.methods.liquidateUser(MARKET_1, bn(0))
+        .accounts({ ...await liquidateAccounts(), liquidator: victim.publicKey, liquidatorAccount: await userAccount(victim.publicKey) })
+        .signers([victim])
+        .rpc(),
+      "CannotLiquidateSelf");
+
+    // 15 tokens bought at $10 with $20 are $2.50 under water at $8.50
+    await setPrice(MARKET_FEEDS[MARKET_1], 8.5);
+    const exchangeBefore = await program.account.exchange.fetch(exchange);
+    const liquidatorBefore = await program.account.userAccount.fetch(await userAccount(liquidator.publicKey));
+    await program.methods.liquidateUser(MARKET_1, bn(0))
+      .accounts(await liquidateAccounts())
+      .remainingAccounts(await openPositions(victim.publicKey, MARKET_1))
+      .signers([liquidator])
+      .rpc();
+    await setPrice(MARKET_FEEDS[MARKET_1], 10);
+
+    const position = await program.account.userPosition.fetch(await userPosition(victim.publicKey, MARKET_1));
+    expect(position.tokenAmount.toNumber()).to.equal(0);
+    const victimAccount = await program.account.userAccount.fetch(await userAccount(victim.publicKey));
+    expect(victimAccount.openPositions).to.equal(0);
+
+    // a 1% fee on $127.50, half of it to the liquidator
+    const liquidatorAfter = await program.account.userAccount.fetch(await userAccount(liquidator.publicKey));
+    expect(liquidatorAfter.rebates.sub(liquidatorBefore.rebates).toString()).to.equal(usd(.6375).toString());
+    // the fee is charged like a trading fee, so the insurance fund takes its share
+    const exchangeAfter = await program.account.exchange.fetch(exchange);
+    expect(exchangeAfter.insuranceFeesPending.gt(exchangeBefore.insuranceFeesPending)).to.be.true;
+
+    // the shortfall exceeds the fund, the fund pays what it holds and the rest is socialized
+    expect(exchangeAfter.insuranceFundValue.toNumber()).to.equal(0);
+    expect(exchangeAfter.insuranceClaimsPending.toString()).to.equal(exchangeBefore.insuranceFundValue.toString());
+    expect(exchangeAfter.socializedLoss.gt(exchangeBefore.socializedLoss)).to.be.true;
+  });
+};
@@ -0,0 +1,25 @@
+import { expect } from 'chai'
+import {
+  INITIAL_MARGIN,
+  MARKET_1,
+  expectError,
+  program,
+  trade,
+  updateMarket,
+  usd,
+  userAccount,
+  victim,
+} from "./harness";
+
+export const checksInitialMarginAndValidatesMarginParameters = () => {
+  it("[user-002] checks initial margin and validates margin parameters", async () => {
+    await expectError(updateMarket(MARKET_1, { maintenanceMargin: INITIAL_MARGIN + 1 }), "InvalidMarginRequirement");
+
+    // $20 of collateral supports $200 of notional at 10% initial margin, less fees
+    await trade(victim, MARKET_1, 15);
+    await expectError(trade(victim, MARKET_1, 5), "UserMarginInsufficient");
+    const account = await program.account.userAccount.fetch(await userAccount(victim.publicKey));
+    expect(account.initialMarginRequired.toString()).to.equal(usd(15).toString());
+    expect(account.maintenanceMarginRequired.toString()).to.equal(usd(7.5).toString());
+  });
+};
@@ -0,0 +1,60 @@
+import { expect } from 'chai'
+import {
+  CHAINLINK_PROGRAM,
+  MARKET_1,
+  MARKET_2,
+  MAX_FUNDING_RATE,
+  bn,
+  exchange,
+  expectError,
+  market,
+  priceFeed,
+  program,
+  tokens,
+  trade,
+  tradeAccounts,
+  trader,
+  updateMarket,
+  userAccount,
+  userPosition,
+} from "./harness";
+
+export const countsOpenPositionsAndFundsTheSkew = () => {
+  it("[user-003] counts open positions and funds the skew", async () => {
+    await trade(trader, MARKET_1, 1);
+
+    const position = await program.account.userPosition.fetch(await userPosition(trader.publicKey, MARKET_1));
+    expect(position.tokenAmount.toString()).to.equal(tokens(1).toString());
+    const account = await program.account.userAccount.fetch(await userAccount(trader.publicKey));
+    expect(account.openPositions).to.equal(1);
+    const marketAccount = await program.account.market.fetch(await market(MARKET_1));
+    expect(marketAccount.longOpenInterest.toString()).to.equal(tokens(1).toString());
+    expect(marketAccount.shortOpenInterest.toNumber()).to.equal(0);
+
+    // only longs are open so funding runs at the max rate
+    await program.methods.updateFunding(MARKET_1)
+      .accounts({
+        market: await market(MARKET_1),
+        exchange,
+        priceFeed: await priceFeed(MARKET_1),
+        chainlinkProgram: CHAINLINK_PROGRAM,
+      })
+      .rpc();
+    const funded = await program.account.market.fetch(await market(MARKET_1));
+    expect(funded.fundingRate.toNumber()).to.equal(MAX_FUNDING_RATE);
+
+    await expectError(updateMarket(MARKET_1, { maxFundingRate: bn(-1) }), "InvalidMaxFundingRate");
+  });
+};
+
+export const requiresEveryOpenPositionToBePassed = () => {
+  it("[user-003] requires every open position to be passed", async () => {
+    // the market 1 position is open, so trading market 2 without it is rejected
+    await expectError(
+      program.methods.executeTrade(MARKET_2, tokens(1), null, false)
+        .accounts(await tradeAccounts(trader.publicKey, MARKET_2))
+        .signers([trader])
+        .rpc(),
+      "MissingOpenPositions");
+  });
+};
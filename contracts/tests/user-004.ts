@@ -0,0 +1,20 @@
+import {
+  MARKET_1,
+  MARKET_FEEDS,
+  MAX_PRICE_AGE,
+  expectError,
+  now,
+  setPrice,
+  trade,
+  trader,
+} from "./harness";
+
+export const rejectsStaleAndInvalidOraclePrices = () => {
+  it("[user-004] rejects stale and invalid oracle prices", async () => {
+    await setPrice(MARKET_FEEDS[MARKET_1], 10, (await now()) - MAX_PRICE_AGE - 60);
+    await expectError(trade(trader, MARKET_1, 1), "OraclePriceStale");
+    await setPrice(MARKET_FEEDS[MARKET_1], 0);
+    await expectError(trade(trader, MARKET_1, 1), "OraclePriceInvalid");
+    await setPrice(MARKET_FEEDS[MARKET_1], 10);
+  });
+};
@@ -0,0 +1,24 @@
+import {
+  MARKET_2,
+  MAX_PRICE_AGE,
+  admin,
+  bn,
+  exchange,
+  expectError,
+  market,
+  program,
+  trade,
+  trader,
+} from "./harness";
+
+export const onlyReadsPythPricesFromPythOwnedAccounts = () => {
+  it("[user-005] only reads pyth prices from pyth owned accounts", async () => {
+    await program.methods.updateMarketOracle(MARKET_2, MAX_PRICE_AGE, { pythPull: {} }, bn(0))
+      .accounts({ owner: admin, market: await market(MARKET_2), exchange })
+      .rpc();
+    await expectError(trade(trader, MARKET_2, 1), "InvalidOracleAccount");
+    await program.methods.updateMarketOracle(MARKET_2, MAX_PRICE_AGE, { mock: {} }, bn(0))
+      .accounts({ owner: admin, market: await market(MARKET_2), exchange })
+      .rpc();
+  });
+};
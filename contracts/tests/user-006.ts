@@ -0,0 +1,17 @@
+import {
+  MARKET_1,
+  admin,
+  exchange,
+  expectError,
+  program,
+  trade,
+  trader,
+} from "./harness";
+
+export const onlyUsesMockPricesInTestMode = () => {
+  it("[user-006] only uses mock prices in test mode", async () => {
+    await program.methods.setTestMode(false).accounts({ admin, exchange }).rpc();
+    await expectError(trade(trader, MARKET_1, 1), "MockOracleDisabled");
+    await program.methods.setTestMode(true).accounts({ admin, exchange }).rpc();
+  });
+};
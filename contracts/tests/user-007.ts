@@ -0,0 +1,106 @@
+import { expect } from 'chai'
+import {
+  AMOUNT_DECIMALS,
+  FEE_DECIMALS,
+  MARKET_1,
+  TAKER_FEE,
+  address,
+  admin,
+  bn,
+  cancelOrders,
+  exchange,
+  expectError,
+  maker,
+  makerAccounts,
+  market,
+  now,
+  openPositions,
+  orderAccounts,
+  ordersOf,
+  placeOrder,
+  program,
+  tokens,
+  trader,
+  usd,
+  userAccount,
+  userPosition,
+} from "./harness";
+
+export const validatesOrdersAndLimitsRestingOrdersPerUser = () => {
+  it("[user-007] validates orders and limits resting orders per user", async () => {
+    await program.methods.addOrderBook(MARKET_1, .05 * FEE_DECIMALS, tokens(1), 2)
+      .accounts({ admin, orderBook: await address("order_book", MARKET_1), market: await market(MARKET_1), exchange })
+      .rpc();
+    await expectError(
+      program.methods.updateOrderBook(MARKET_1, .05 * FEE_DECIMALS, bn(0), 2)
+        .accounts({ admin, orderBook: await address("order_book", MARKET_1), exchange })
+        .rpc(),
+      "InvalidOrderBookParameters");
+    await expectError(
+      program.methods.updateOrderBook(MARKET_1, 0, tokens(1), 2)
+        .accounts({ admin, orderBook: await address("order_book", MARKET_1), exchange })
+        .rpc(),
+      "InvalidOrderBookParameters");
+
+    await expectError(placeOrder(maker, MARKET_1, -2, 10.1, { expiry: (await now()) - 1 }), "InvalidOrder");
+    await expectError(placeOrder(maker, MARKET_1, -.5, 10.1), "InvalidOrder");
+    await placeOrder(maker, MARKET_1, -2, 10.1);
+    await placeOrder(maker, MARKET_1, -1, 10.2);
+    await expectError(placeOrder(maker, MARKET_1, -1, 10.3), "MaxOrdersExceeded");
+    expect((await ordersOf(maker.publicKey, MARKET_1)).length).to.equal(2);
+  });
+};
+
+export const fillsAgainstMakersAtTheirFeeOverride = () => {
+  it("[user-007] [user-018] fills against makers at their fee override", async () => {
+    await program.methods.setUserFeeOverride(maker.publicKey, -.0005 * FEE_DECIMALS, TAKER_FEE)
+      .accounts({ admin, feeOverride: await address("fee_override", maker.publicKey), exchange })
+      .rpc();
+    const makerBefore = await program.account.userAccount.fetch(await userAccount(maker.publicKey));
+    const takerBefore = await program.account.userPosition.fetch(await userPosition(trader.publicKey, MARKET_1));
+
+    // the maker's margin is checked against everything the maker holds
+    await expectError(
+      program.methods.placeOrder(MARKET_1, tokens(1), bn(10.1 * AMOUNT_DECIMALS), bn((await now()) + 3600))
+        .accounts(await orderAccounts(trader.publicKey, MARKET_1))
+        .remainingAccounts([
+          ...await openPositions(trader.publicKey, MARKET_1),
+          ...(await makerAccounts(maker.publicKey, MARKET_1)).slice(0, 3),
+        ])
+        .signers([trader])
+        .rpc(),
+      "MissingOpenPositions");
+    await placeOrder(trader, MARKET_1, 1, 10.1, { makers: [maker] });
+
+    const makerPosition = await program.account.userPosition.fetch(await userPosition(maker.publicKey, MARKET_1));
+    expect(makerPosition.tokenAmount.toString()).to.equal(tokens(-1).toString());
+    const takerAfter = await program.account.userPosition.fetch(await userPosition(trader.publicKey, MARKET_1));
+    expect(takerAfter.tokenAmount.sub(takerBefore.tokenAmount).toString()).to.equal(tokens(1).toString());
+
+    // the maker earns the 5 bps override rebate on $10.10
+    const makerAfter = await program.account.userAccount.fetch(await userAccount(maker.publicKey));
+    expect(makerAfter.rebates.sub(makerBefore.rebates).toString()).to.equal(usd(.00505).toString());
+    expect(makerAfter.openPositions).to.equal(1);
+
+    // the rest of the partially filled order stays on the book
+    const orders = await ordersOf(maker.publicKey, MARKET_1);
+    expect(orders.map(order => order.size.toString())).to.include(tokens(1).toString());
+  });
+};
+
+export const dropsRestingOrdersOutsideTheOraclePriceBand = () => {
+  it("[user-007] drops resting orders outside the oracle price band", async () => {
+    await cancelOrders(maker, MARKET_1);
+    await placeOrder(maker, MARKET_1, -1, 12);
+    const makerBefore = await program.account.userPosition.fetch(await userPosition(maker.publicKey, MARKET_1));
+
+    await placeOrder(trader, MARKET_1, 1, 12, { makers: [maker] });
+
+    const makerAfter = await program.account.userPosition.fetch(await userPosition(maker.publicKey, MARKET_1));
+    expect(makerAfter.tokenAmount.toString()).to.equal(makerBefore.tokenAmount.toString());
+    expect(await ordersOf(maker.publicKey, MARKET_1)).to.be.empty;
+    // the unfilled bid rests instead
+    expect((await ordersOf(trader.publicKey, MARKET_1)).length).to.equal(1);
+    await cancelOrders(trader, MARKET_1);
+  });
+};
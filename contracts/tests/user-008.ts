@@ -0,0 +1,15 @@
+import {
+  MARKET_1,
+  expectError,
+  trade,
+  trader,
+} from "./harness";
+
+export const enforcesLimitPricesAndReduceOnlyTrades = () => {
+  it("[user-008] enforces limit prices and reduce only trades", async () => {
+    await expectError(trade(trader, MARKET_1, 1, { limitPrice: 9.9 }), "SlippageExceeded");
+    await expectError(trade(trader, MARKET_1, -1, { limitPrice: 10.1 }), "SlippageExceeded");
+    await expectError(trade(trader, MARKET_1, 1, { reduceOnly: true }), "ReduceOnlyViolation");
+    await expectError(trade(trader, MARKET_1, -2, { reduceOnly: true }), "ReduceOnlyViolation");
+  });
+};
@@ -0,0 +1,48 @@
+import { expect } from 'chai'
+import {
+  MARKET_1,
+  address,
+  exchange,
+  expectError,
+  maker,
+  program,
+  settlePnl,
+  tokens,
+  trader,
+  usdc,
+  userAccount,
+  userPosition,
+} from "./harness";
+
+export const keepsPositionsWithRestingOrdersOpen = () => {
+  it("[user-009] keeps positions with resting orders open", async () => {
+    await expectError(
+      program.methods.closeUserPosition(MARKET_1)
+        .accounts({
+          owner: maker.publicKey,
+          userPosition: await userPosition(maker.publicKey, MARKET_1),
+          orderBook: await address("order_book", MARKET_1),
+        })
+        .signers([maker])
+        .rpc(),
+      "RestingOrdersOpen");
+  });
+};
+
+export const settlesRealizedPnlIntoCollateral = () => {
+  it("[user-009] settles realized pnl into collateral", async () => {
+    await settlePnl(maker);
+    const account = await program.account.userAccount.fetch(await userAccount(maker.publicKey));
+    expect(account.fees.toNumber()).to.equal(0);
+    expect(account.rebates.toNumber()).to.equal(0);
+
+    // the trader's fees are losses that move tokens to the house
+    const collateralBefore = await program.account.userCollateral.fetch(await address("user_collateral", trader.publicKey, usdc));
+    const exchangeBefore = await program.account.exchange.fetch(exchange);
+    await settlePnl(trader);
+    const collateralAfter = await program.account.userCollateral.fetch(await address("user_collateral", trader.publicKey, usdc));
+    const exchangeAfter = await program.account.exchange.fetch(exchange);
+    expect(collateralAfter.tokenAmount.lt(collateralBefore.tokenAmount)).to.be.true;
+    expect(exchangeAfter.settledPnl.gt(exchangeBefore.settledPnl)).to.be.true;
+  });
+};
@@ -0,0 +1,19 @@
+import {
+  MARKET_1,
+  expectError,
+  tokens,
+  trade,
+  trader,
+  updateMarket,
+  victim,
+} from "./harness";
+
+export const capsPositionSizeAndOpenInterest = () => {
+  it("[user-010] caps position size and open interest", async () => {
+    await updateMarket(MARKET_1, { maxPositionSize: tokens(1) });
+    await expectError(trade(trader, MARKET_1, 1), "MaxPositionSizeExceeded");
+    await updateMarket(MARKET_1, { maxLongOpenInterest: tokens(1) });
+    await expectError(trade(victim, MARKET_1, 1), "MaxLongOpenInterestExceeded");
+    await updateMarket(MARKET_1);
+  });
+};
@@ -0,0 +1,106 @@
+import { expect } from 'chai'
+import { getAccount } from "@solana/spl-token"
+import {
+  FEE_DECIMALS,
+  INSURANCE_FEE_SHARE,
+  MARKET_1,
+  USDC_DECIMALS,
+  address,
+  admin,
+  connection,
+  exchange,
+  expectError,
+  insuranceAccounts,
+  market,
+  payer,
+  program,
+  trade,
+  trader,
+  usd,
+  usdc,
+  userAccount,
+} from "./harness";
+
+export const appliesFeeTiersAndSplitsTheInsuranceShare = () => {
+  it("[user-011] [user-018] applies fee tiers and splits the insurance share", async () => {
+    const feeTiers = await address("fee_tiers");
+    await expectError(
+      program.methods.updateFeeTiers([
+        { volumeThreshold: usd(1_000), makerFee: -2, takerFee: 8 },
+        { volumeThreshold: usd(0), makerFee: -2, takerFee: 5 },
+      ]).accounts({ admin, feeTiers, exchange }).rpc(),
+      "InvalidFeeTiers");
+    await program.methods.updateFeeTiers([{ volumeThreshold: usd(0), makerFee: -2, takerFee: 5 }])
+      .accounts({ admin, feeTiers, exchange })
+      .rpc();
+
+    const accountBefore = await program.account.userAccount.fetch(await userAccount(trader.publicKey));
+    const exchangeBefore = await program.account.exchange.fetch(exchange);
+    await trade(trader, MARKET_1, 1, { accounts: { feeTiers } });
+    const accountAfter = await program.account.userAccount.fetch(await userAccount(trader.publicKey));
+    const exchangeAfter = await program.account.exchange.fetch(exchange);
+
+    // $10 at the 5 bps tier is a $0.005 fee, 10% of it goes to the insurance fund
+    const fee = usd(.005).toNumber();
+    const insuranceFee = fee * INSURANCE_FEE_SHARE / FEE_DECIMALS;
+    expect(accountBefore.fees.sub(accountAfter.fees).toNumber()).to.equal(fee);
+    expect(exchangeAfter.insuranceFeesPending.sub(exchangeBefore.insuranceFeesPending).toNumber()).to.equal(insuranceFee);
+    expect(exchangeAfter.fees.sub(exchangeBefore.fees).toNumber()).to.equal(fee - insuranceFee);
+
+    // an override replaces the market and tier fees
+    const feeOverride = await address("fee_override", trader.publicKey);
+    await program.methods.setUserFeeOverride(trader.publicKey, 0, 0)
+      .accounts({ admin, feeOverride, exchange })
+      .rpc();
+    await trade(trader, MARKET_1, -1, { accounts: { feeTiers, feeOverride } });
+    const overridden = await program.account.userAccount.fetch(await userAccount(trader.publicKey));
+    expect(overridden.fees.toString()).to.equal(accountAfter.fees.toString());
+    await program.methods.removeUserFeeOverride(trader.publicKey)
+      .accounts({ admin, feeOverride, exchange })
+      .rpc();
+  });
+};
+
+export const movesCollectedInsuranceFeesIntoTheVault = () => {
+  it("[user-011] moves collected insurance fees into the vault", async () => {
+    await expectError(
+      program.methods.fundInsurance()
+        .accounts({ payer: trader.publicKey, ...await insuranceAccounts() })
+        .signers([trader])
+        .rpc(),
+      "ConstraintRaw");
+
+    const exchangeBefore = await program.account.exchange.fetch(exchange);
+    await program.methods.fundInsurance()
+      .accounts({ payer: admin, ...await insuranceAccounts() })
+      .rpc();
+    const exchangeAfter = await program.account.exchange.fetch(exchange);
+    const vault = await getAccount(connection, await address("insurance_fund", usdc));
+
+    // usdc has 6 decimals and values have 9 at $1
+    const funded = Number(vault.amount) * 10 ** (9 - USDC_DECIMALS);
+    expect(funded).to.be.greaterThan(0);
+    expect(exchangeAfter.insuranceFundValue.sub(exchangeBefore.insuranceFundValue).toNumber()).to.equal(funded);
+    expect(exchangeBefore.insuranceFeesPending.sub(exchangeAfter.insuranceFeesPending).toNumber()).to.equal(funded);
+  });
+};
+
+export const paysInsuranceClaimsFromTheVaultIntoEscrow = () => {
+  it("[user-011] pays insurance claims from the vault into escrow", async () => {
+    const escrow = await address(exchange, usdc);
+    const escrowBefore = await getAccount(connection, escrow);
+    const vaultBefore = await getAccount(connection, await address("insurance_fund", usdc));
+    const exchangeBefore = await program.account.exchange.fetch(exchange);
+
+    await program.methods.payInsuranceClaim().accounts(await insuranceAccounts()).rpc();
+
+    const escrowAfter = await getAccount(connection, escrow);
+    const vaultAfter = await getAccount(connection, await address("insurance_fund", usdc));
+    const exchangeAfter = await program.account.exchange.fetch(exchange);
+    expect(vaultAfter.amount).to.equal(BigInt(0));
+    expect(escrowAfter.amount - escrowBefore.amount).to.equal(vaultBefore.amount);
+    expect(exchangeAfter.insuranceClaimsPending.toNumber()).to.equal(0);
+    expect(exchangeAfter.settledPnl.sub(exchangeBefore.settledPnl).toString())
+      .to.equal(exchangeBefore.insuranceClaimsPending.toString());
+  });
+};
@@ -0,0 +1,22 @@
+import { expect } from 'chai'
+import {
+  MARKET_2,
+  program,
+  tokens,
+  trade,
+  trader,
+  userAccount,
+  userPosition,
+} from "./harness";
+
+export const roundsTradeBasisInTheHousesFavour = () => {
+  it("[user-012] rounds trade basis in the house's favour", async () => {
+    // 1.000000001 tokens at 3.333333333 is worth 3.333333336333..., longs pay up
+    await trade(trader, MARKET_2, 1.000000001);
+    const position = await program.account.userPosition.fetch(await userPosition(trader.publicKey, MARKET_2));
+    expect(position.tokenAmount.toString()).to.equal("1000000001");
+    expect(position.basis.toString()).to.equal("-3333333337");
+    const account = await program.account.userAccount.fetch(await userAccount(trader.publicKey));
+    expect(account.openPositions).to.equal(2);
+  });
+};
@@ -0,0 +1,51 @@
+import { expect } from 'chai'
+import { getAccount } from "@solana/spl-token"
+import {
+  MAX_PRICE_AGE,
+  PRICE_DECIMALS,
+  USDC_DECIMALS,
+  address,
+  admin,
+  bn,
+  collateralAccounts,
+  connection,
+  exchange,
+  expectError,
+  openPositions,
+  program,
+  trader,
+  usd,
+  usdc,
+} from "./harness";
+
+export const withdrawsFromTheBalanceHeldInEachMint = () => {
+  it("[user-013] withdraws from the balance held in each mint", async () => {
+    const accounts = await collateralAccounts(trader.publicKey);
+    await expectError(
+      program.methods.withdraw(usd(5_000))
+        .accounts(accounts)
+        .remainingAccounts(await openPositions(trader.publicKey, undefined, usdc))
+        .signers([trader])
+        .rpc(),
+      "CollateralBalanceInsufficient");
+
+    const before = await getAccount(connection, accounts.userTokenAccount);
+    await program.methods.withdraw(usd(10))
+      .accounts(accounts)
+      .remainingAccounts(await openPositions(trader.publicKey, undefined, usdc))
+      .signers([trader])
+      .rpc();
+    const after = await getAccount(connection, accounts.userTokenAccount);
+    expect(after.amount - before.amount).to.equal(BigInt(10 * 10 ** USDC_DECIMALS));
+  });
+};
+
+export const onlyMigratesTreasuryPositionsStillInTheV0Layout = () => {
+  it("[user-013] only migrates treasury positions still in the v0 layout", async () => {
+    await expectError(
+      program.methods.migrateExchangePosition(usdc, MAX_PRICE_AGE, { fixed: {} }, bn(PRICE_DECIMALS))
+        .accounts({ admin, exchange, exchangeTreasuryPosition: await address("exchange_position", usdc) })
+        .rpc(),
+      "AccountAlreadyMigrated");
+  });
+};
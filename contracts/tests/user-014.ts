@@ -0,0 +1,47 @@
+import { expect } from 'chai'
+import {
+  MARKET_1,
+  MARKET_WEIGHT_DECIMALS,
+  address,
+  admin,
+  deposit,
+  exchange,
+  expectError,
+  openPositions,
+  program,
+  tokens,
+  trade,
+  tradeAccounts,
+  trader,
+  updateCollateral,
+  usdc,
+} from "./harness";
+
+export const appliesTreasuryWeightsAndTheActiveFlag = () => {
+  it("[user-014] applies treasury weights and the active flag", async () => {
+    const updatePosition = (active: boolean, weight: number) =>
+      address("exchange_position", usdc).then(exchangeTreasuryPosition =>
+        program.methods.updateExchangePosition(usdc, active, weight)
+          .accounts({ owner: admin, exchangeTreasuryPosition, exchange })
+          .rpc());
+
+    // health checks mark every mint held, so the trade needs them all and
+    // applies the new weight without a separate update
+    await updatePosition(true, .5 * MARKET_WEIGHT_DECIMALS);
+    await expectError(
+      program.methods.executeTrade(MARKET_1, tokens(.1), null, false)
+        .accounts(await tradeAccounts(trader.publicKey, MARKET_1))
+        .remainingAccounts(await openPositions(trader.publicKey, MARKET_1, usdc))
+        .signers([trader])
+        .rpc(),
+      "MissingOpenPositions");
+    await trade(trader, MARKET_1, .1);
+    const collateral = await program.account.userCollateral.fetch(await address("user_collateral", trader.publicKey, usdc));
+    expect(collateral.weightedCollateralValue.toString()).to.equal(collateral.collateralValue.divn(2).toString());
+
+    await updatePosition(false, MARKET_WEIGHT_DECIMALS);
+    await expectError(deposit(trader, 10), "TreasuryPositionInactive");
+    await updatePosition(true, MARKET_WEIGHT_DECIMALS);
+    await updateCollateral(trader.publicKey);
+  });
+};
@@ -0,0 +1,42 @@
+import { expect } from 'chai'
+import { createMint, getAccount, mintTo, TOKEN_2022_PROGRAM_ID } from "@solana/spl-token"
+import {
+  MARKET_WEIGHT_DECIMALS,
+  MAX_PRICE_AGE,
+  PRICE_DECIMALS,
+  USDC_DECIMALS,
+  address,
+  admin,
+  bn,
+  collateralAccounts,
+  connection,
+  exchange,
+  maker,
+  openPositions,
+  payer,
+  priceFeed,
+  program,
+  tokenAccount,
+  usd,
+} from "./harness";
+
+export const acceptsToken2022Collateral = () => {
+  it("[user-015] accepts token-2022 collateral", async () => {
+    const mint = await createMint(connection, payer, admin, null, USDC_DECIMALS, undefined, undefined, TOKEN_2022_PROGRAM_ID);
+    await program.methods.addExchangePosition(
+      mint, true, MARKET_WEIGHT_DECIMALS, USDC_DECIMALS, mint, MAX_PRICE_AGE, { fixed: {} }, bn(PRICE_DECIMALS),
+    ).accounts({ admin, exchangeTreasuryPosition: await address("exchange_position", mint), exchange }).rpc();
+    const source = await tokenAccount(maker.publicKey, mint, TOKEN_2022_PROGRAM_ID);
+    await mintTo(connection, payer, mint, source, payer, 100 * 10 ** USDC_DECIMALS, [], undefined, TOKEN_2022_PROGRAM_ID);
+
+    await program.methods.deposit(usd(100))
+      .accounts({ ...await collateralAccounts(maker.publicKey, mint, TOKEN_2022_PROGRAM_ID), priceFeed: mint })
+      .remainingAccounts(await openPositions(maker.publicKey, undefined, mint))
+      .signers([maker])
+      .rpc();
+    const collateral = await program.account.userCollateral.fetch(await address("user_collateral", maker.publicKey, mint));
+    expect(collateral.tokenAmount.toNumber()).to.equal(100 * 10 ** USDC_DECIMALS);
+    const escrow = await getAccount(connection, await address(exchange, mint), undefined, TOKEN_2022_PROGRAM_ID);
+    expect(escrow.amount).to.equal(BigInt(100 * 10 ** USDC_DECIMALS));
+  });
+};
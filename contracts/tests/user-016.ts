@@ -0,0 +1,32 @@
+import { expect } from 'chai'
+import {
+  USDC_DECIMALS,
+  admin,
+  collateralAccounts,
+  expectError,
+  program,
+  tokenAccount,
+  trader,
+  usd,
+  userAccount,
+} from "./harness";
+
+export const limitsHouseWithdrawalsToTheHouseDeposits = () => {
+  it("[user-016] limits house withdrawals to the house deposits", async () => {
+    const { owner, userAccount: _, userCollateral: __, ...houseAccounts } = await collateralAccounts(admin);
+    await expectError(
+      program.methods.exchangeDeposit(usd(1))
+        .accounts({ ...houseAccounts, owner: trader.publicKey, userTokenAccount: await tokenAccount(trader.publicKey) })
+        .signers([trader])
+        .rpc(),
+      "ConstraintRaw");
+    await expectError(
+      program.methods.exchangeWithdraw(usd(200_000)).accounts({ owner, ...houseAccounts }).rpc(),
+      "CollateralBalanceInsufficient");
+
+    const before = await program.account.exchangeTreasuryPosition.fetch(houseAccounts.exchangeTreasuryPosition);
+    await program.methods.exchangeWithdraw(usd(1)).accounts({ owner, ...houseAccounts }).rpc();
+    const after = await program.account.exchangeTreasuryPosition.fetch(houseAccounts.exchangeTreasuryPosition);
+    expect(before.houseTokenAmount.sub(after.houseTokenAmount).toNumber()).to.equal(10 ** USDC_DECIMALS);
+  });
+};
@@ -0,0 +1,72 @@
+import { expect } from 'chai'
+import { getAccount, TOKEN_PROGRAM_ID } from "@solana/spl-token"
+import {
+  address,
+  admin,
+  allMarkets,
+  bn,
+  collateralAccounts,
+  connection,
+  deposit,
+  exchange,
+  expectError,
+  liquidator,
+  market,
+  priceFeed,
+  program,
+  tokenAccount,
+  usd,
+  userAccount,
+} from "./harness";
+
+export const pricesLpSharesAgainstEveryMarket = () => {
+  it("[user-017] prices lp shares against every market", async () => {
+    const shareMint = await address("lp_share_mint");
+    const shareEscrow = await address("lp_share_escrow");
+    const lpVault = await address("lp_vault");
+    await program.methods.initializeLpVault(bn(3600))
+      .accounts({ admin, exchange, lpVault, shareMint, shareEscrow, shareTokenProgram: TOKEN_PROGRAM_ID })
+      .rpc();
+
+    const { userAccount: _, userCollateral: __, ...accounts } = await collateralAccounts(liquidator.publicKey);
+    const userShareAccount = await tokenAccount(liquidator.publicKey, shareMint);
+    const lpAccounts = { ...accounts, lpVault, shareMint, userShareAccount, shareEscrow, shareTokenProgram: TOKEN_PROGRAM_ID };
+    await expectError(
+      program.methods.lpDeposit(usd(500))
+        .accounts(lpAccounts)
+        .remainingAccounts((await allMarkets()).slice(0, 2))
+        .signers([liquidator])
+        .rpc(),
+      "MissingMarketAccounts");
+
+    // the first deposit mints shares one for one with its value, the fees the
+    // vault earned before it had lps are locked in the share escrow
+    await program.methods.lpDeposit(usd(500))
+      .accounts(lpAccounts)
+      .remainingAccounts(await allMarkets())
+      .signers([liquidator])
+      .rpc();
+    const shares = await getAccount(connection, userShareAccount);
+    expect(shares.amount.toString()).to.equal(usd(500).toString());
+    const lockedShares = await getAccount(connection, shareEscrow);
+    expect(Number(lockedShares.amount)).to.be.greaterThan(0);
+
+    // shares can only be redeemed once the cooldown has ended
+    const lpPosition = await address("lp_position", liquidator.publicKey);
+    await program.methods.requestLpWithdrawal(usd(100))
+      .accounts({ owner: liquidator.publicKey, lpPosition, shareMint, userShareAccount, shareEscrow, shareTokenProgram: TOKEN_PROGRAM_ID })
+      .signers([liquidator])
+      .rpc();
+    const { userTokenAccount, tokenProgram, mint, escrowAccount, exchangeTreasuryPosition, priceFeed, chainlinkProgram } = accounts;
+    await expectError(
+      program.methods.lpWithdraw()
+        .accounts({
+          owner: liquidator.publicKey, exchange, lpVault, lpPosition, userTokenAccount, tokenProgram, mint, escrowAccount,
+          exchangeTreasuryPosition, priceFeed, chainlinkProgram, shareMint, shareEscrow, shareTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts(await allMarkets())
+        .signers([liquidator])
+        .rpc(),
+      "LpCooldownActive");
+  });
+};
@@ -0,0 +1,72 @@
+import { expect } from 'chai'
+import { mintTo } from "@solana/spl-token"
+import {
+  MARKET_1,
+  USDC_DECIMALS,
+  address,
+  connection,
+  deposit,
+  liquidator,
+  maker,
+  market,
+  payer,
+  placeOrder,
+  program,
+  referee,
+  tokenAccount,
+  trade,
+  usd,
+  usdc,
+  userAccount,
+  userPosition,
+} from "./harness";
+
+export const sharesDiscountedFeesWithTheReferrer = () => {
+  it("[user-019] shares discounted fees with the referrer", async () => {
+    const referrer = await address("referrer", liquidator.publicKey);
+    await program.methods.createReferrer()
+      .accounts({ owner: liquidator.publicKey, referrer })
+      .signers([liquidator])
+      .rpc();
+    await mintTo(connection, payer, usdc, await tokenAccount(referee.publicKey), payer, 1_000 * 10 ** USDC_DECIMALS);
+    await program.methods.createUserAccount()
+      .accounts({ owner: referee.publicKey, userAccount: await userAccount(referee.publicKey), referrer })
+      .signers([referee])
+      .rpc();
+    await program.methods.addUserPosition(MARKET_1)
+      .accounts({
+        owner: referee.publicKey,
+        userPosition: await userPosition(referee.publicKey, MARKET_1),
+        userAccount: await userAccount(referee.publicKey),
+        market: await market(MARKET_1),
+      })
+      .signers([referee])
+      .rpc();
+    await deposit(referee, 100);
+
+    await trade(referee, MARKET_1, 1, { accounts: { referrer } });
+
+    // a $0.01 fee less the 10% discount, 20% of which goes to the referrer
+    const account = await program.account.userAccount.fetch(await userAccount(referee.publicKey));
+    expect(account.fees.toString()).to.equal(usd(-.009).toString());
+    const referrerAccount = await program.account.referrer.fetch(referrer);
+    expect(referrerAccount.refereeCount).to.equal(1);
+    expect(referrerAccount.referralFees.toString()).to.equal(usd(.0018).toString());
+
+    // fills against the order book pay the referrer too
+    await placeOrder(maker, MARKET_1, -1, 10);
+    await placeOrder(referee, MARKET_1, 1, 10, { makers: [maker], referrer });
+    const filledAccount = await program.account.userAccount.fetch(await userAccount(referee.publicKey));
+    expect(filledAccount.fees.toString()).to.equal(usd(-.018).toString());
+    const filledReferrer = await program.account.referrer.fetch(referrer);
+    expect(filledReferrer.referralFees.toString()).to.equal(usd(.0036).toString());
+
+    const before = await program.account.userAccount.fetch(await userAccount(liquidator.publicKey));
+    await program.methods.claimReferralFees()
+      .accounts({ owner: liquidator.publicKey, referrer, userAccount: await userAccount(liquidator.publicKey) })
+      .signers([liquidator])
+      .rpc();
+    const after = await program.account.userAccount.fetch(await userAccount(liquidator.publicKey));
+    expect(after.rebates.sub(before.rebates).toString()).to.equal(usd(.0036).toString());
+  });
+};
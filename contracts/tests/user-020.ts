@@ -0,0 +1,20 @@
+import { expect } from 'chai'
+import {
+  REWARD_FREQUENCY,
+  exchange,
+  program,
+  sleep,
+  trader,
+  updateCollateral,
+} from "./harness";
+
+export const streamsRewardsOverEpochs = () => {
+  it("[user-020] streams rewards over epochs", async () => {
+    const before = await program.account.exchange.fetch(exchange);
+    await sleep(REWARD_FREQUENCY + 1);
+    await updateCollateral(trader.publicKey);
+    const after = await program.account.exchange.fetch(exchange);
+    expect(after.rewardEpoch.gt(before.rewardEpoch)).to.be.true;
+    expect(after.rewardIndex.gte(before.rewardIndex)).to.be.true;
+  });
+};
@@ -0,0 +1,74 @@
+import { expect } from 'chai'
+import { getAccount, TOKEN_PROGRAM_ID } from "@solana/spl-token"
+import {
+  CHAINLINK_PROGRAM,
+  REWARD_FREQUENCY,
+  address,
+  admin,
+  bn,
+  connection,
+  exchange,
+  expectError,
+  openPositions,
+  program,
+  sleep,
+  tokenAccount,
+  tokens,
+  trader,
+  updateCollateral,
+  userAccount,
+} from "./harness";
+
+export const vestsRewardTokensUpToTheEmissionBudget = () => {
+  it("[user-021] vests reward tokens up to the emission budget", async () => {
+    const rewardToken = await address("reward_token");
+    const rewardMint = await address("reward_mint");
+    await program.methods.initializeRewardToken(bn(0), bn(1))
+      .accounts({ admin, exchange, rewardToken, rewardMint, rewardTokenProgram: TOKEN_PROGRAM_ID })
+      .rpc();
+
+    await sleep(REWARD_FREQUENCY * 2 + 1);
+    await updateCollateral(trader.publicKey);
+    const account = await program.account.userAccount.fetch(await userAccount(trader.publicKey));
+    expect(account.rewards.gtn(1)).to.be.true;
+
+    const vestAccounts = {
+      owner: trader.publicKey,
+      exchange,
+      userAccount: await userAccount(trader.publicKey),
+      rewardToken,
+      rewardVesting: await address("reward_vesting", trader.publicKey),
+      chainlinkProgram: CHAINLINK_PROGRAM,
+    };
+    const vest = async () => program.methods.vestRewards()
+      .accounts(vestAccounts)
+      .remainingAccounts(await openPositions(trader.publicKey))
+      .signers([trader])
+      .rpc();
+    await vest();
+    const token = await program.account.rewardToken.fetch(rewardToken);
+    expect(token.emitted.toNumber()).to.equal(1);
+    await expectError(vest(), "RewardBudgetExhausted");
+    await expectError(
+      program.methods.updateRewardToken(bn(0), bn(0)).accounts({ admin, exchange, rewardToken }).rpc(),
+      "RewardBudgetExhausted");
+
+    // vested rewards are no longer owed by the house
+    const vested = await program.account.userAccount.fetch(await userAccount(trader.publicKey));
+    expect(account.rewards.sub(vested.rewards).toNumber()).to.be.at.least(1);
+
+    const userRewardAccount = await tokenAccount(trader.publicKey, rewardMint);
+    await program.methods.claimVested()
+      .accounts({
+        owner: trader.publicKey,
+        exchange,
+        rewardVesting: vestAccounts.rewardVesting,
+        rewardMint,
+        userRewardAccount,
+        rewardTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([trader])
+      .rpc();
+    expect((await getAccount(connection, userRewardAccount)).amount).to.equal(BigInt(1));
+  });
+};
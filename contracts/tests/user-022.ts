@@ -0,0 +1,149 @@
+import { expect } from 'chai'
+import { PublicKey } from '@solana/web3.js';
+import {
+  CHAINLINK_PROGRAM,
+  FUNDING_PERIOD,
+  INITIAL_MARGIN,
+  MAINTENANCE_MARGIN,
+  MARKET_1,
+  MAX_FUNDING_RATE,
+  MAX_PRICE_AGE,
+  TAKER_FEE,
+  YIELD_FEED,
+  YIELD_MARKET,
+  address,
+  admin,
+  bn,
+  collateralAccounts,
+  exchange,
+  expectError,
+  liquidator,
+  mockPrice,
+  openPositions,
+  payer,
+  priceFeed,
+  program,
+  referee,
+  setPrice,
+  tokens,
+  trade,
+  tradeAccounts,
+  trader,
+  updateYield,
+  usd,
+  userAccount,
+} from "./harness";
+
+export const letsUsersTradeAndSettleYield = () => {
+  it("[user-022] [user-023] lets users trade and settle yield", async () => {
+    const yieldMarket = await address("yield_market", YIELD_MARKET);
+    const yieldFeed = await mockPrice(YIELD_FEED);
+    await program.methods.addYieldMarket(
+      YIELD_MARKET, yieldFeed, MAX_PRICE_AGE, { mock: {} }, bn(0), TAKER_FEE, INITIAL_MARGIN, MAINTENANCE_MARGIN,
+      bn(FUNDING_PERIOD), bn(MAX_FUNDING_RATE), { linear: {} }, bn(0), bn(0),
+    ).accounts({ owner: admin, yieldMarket, exchange }).rpc();
+
+    const userYieldPosition = await address("user_yield_position", YIELD_MARKET, trader.publicKey);
+    await program.methods.addYield(YIELD_MARKET)
+      .accounts({ owner: trader.publicKey, userYieldPosition })
+      .signers([trader])
+      .rpc();
+    await updateYield(trader, 10, 0);
+    const position = await program.account.userYieldPosition.fetch(userYieldPosition);
+    expect(position.longTokenAmount.toString()).to.equal(tokens(10).toString());
+    expect(position.longFees.toString()).to.equal(usd(-.01).toString());
+
+    // claiming realizes funding and settles fees into collateral
+    const { userTokenAccount, tokenProgram, ...accounts } = await collateralAccounts(trader.publicKey);
+    await program.methods.claimYield(YIELD_MARKET)
+      .accounts({ ...accounts, userYieldPosition, yieldMarket, yieldPriceFeed: yieldFeed })
+      .signers([trader])
+      .rpc();
+    const account = await program.account.userAccount.fetch(await userAccount(trader.publicKey));
+    expect(account.fees.toNumber()).to.equal(0);
+    const claimed = await program.account.userYieldPosition.fetch(userYieldPosition);
+    expect(claimed.settledFunding.toString()).to.equal(claimed.longFunding.add(claimed.shortFunding).toString());
+  });
+};
+
+export const countsYieldPositionsInHealthAndLiquidatesThem = () => {
+  it("[user-022] counts yield positions in health and liquidates them", async () => {
+    const yieldFeed = await mockPrice(YIELD_FEED);
+    const userYieldPosition = await address("user_yield_position", YIELD_MARKET, referee.publicKey);
+    await program.methods.addYield(YIELD_MARKET)
+      .accounts({ owner: referee.publicKey, userYieldPosition })
+      .signers([referee])
+      .rpc();
+    await updateYield(referee, 0, 200);
+    expect((await program.account.userAccount.fetch(await userAccount(referee.publicKey))).openYieldPositions)
+      .to.equal(1);
+
+    // the open yield position must be passed wherever health is checked
+    await expectError(
+      program.methods.executeTrade(MARKET_1, tokens(1), null, false)
+        .accounts(await tradeAccounts(referee.publicKey, MARKET_1))
+        .remainingAccounts((await openPositions(referee.publicKey, MARKET_1)).filter(meta => !meta.pubkey.equals(userYieldPosition)))
+        .signers([referee])
+        .rpc(),
+      "MissingOpenPositions");
+
+    const liquidateYield = async (owner: PublicKey) => {
+      await program.methods.liquidateYield(YIELD_MARKET)
+        .accounts({
+          liquidator: liquidator.publicKey,
+          liquidatorAccount: await userAccount(liquidator.publicKey),
+          userAccount: await userAccount(owner),
+          userYieldPosition: await address("user_yield_position", YIELD_MARKET, owner),
+          yieldMarket: await address("yield_market", YIELD_MARKET),
+          exchange,
+          priceFeed: yieldFeed,
+          chainlinkProgram: CHAINLINK_PROGRAM,
+        })
+        .remainingAccounts(await openPositions(owner, undefined, undefined, YIELD_MARKET))
+        .signers([liquidator])
+        .rpc();
+    };
+    await expectError(liquidateYield(referee.publicKey), "UserNotLiquidatable");
+
+    // the short loses $100 on a 50% move, more than the account holds
+    await setPrice(YIELD_FEED, 1.5);
+    const before = await program.account.userAccount.fetch(await userAccount(liquidator.publicKey));
+    await liquidateYield(referee.publicKey);
+    await setPrice(YIELD_FEED, 1);
+
+    const position = await program.account.userYieldPosition.fetch(userYieldPosition);
+    expect(position.shortTokenAmount.isZero()).to.be.true;
+    expect(position.maintenanceMarginRequired.isZero()).to.be.true;
+    const account = await program.account.userAccount.fetch(await userAccount(referee.publicKey));
+    expect(account.openYieldPositions).to.equal(0);
+    expect(account.pnl.lt(usd(-90))).to.be.true;
+    const after = await program.account.userAccount.fetch(await userAccount(liquidator.publicKey));
+    expect(after.rebates.gt(before.rebates)).to.be.true;
+  });
+};
+
+export const onlyMigratesYieldAccountsStillInTheV0Layout = () => {
+  it("[user-022] only migrates yield accounts still in the v0 layout", async () => {
+    const yieldMarket = await address("yield_market", YIELD_MARKET);
+    await expectError(
+      program.methods.migrateYieldMarket(YIELD_MARKET, MAX_PRICE_AGE, { mock: {} }, bn(0), TAKER_FEE, INITIAL_MARGIN,
+        MAINTENANCE_MARGIN, bn(FUNDING_PERIOD), bn(MAX_FUNDING_RATE), { linear: {} }, bn(0), bn(0))
+        .accounts({ admin, exchange, yieldMarket })
+        .rpc(),
+      "AccountAlreadyMigrated");
+    await expectError(
+      program.methods.migrateUserYieldPosition(YIELD_MARKET)
+        .accounts({
+          payer: admin,
+          owner: trader.publicKey,
+          userAccount: await userAccount(trader.publicKey),
+          yieldMarket,
+          exchange,
+          userYieldPosition: await address("user_yield_position", YIELD_MARKET, trader.publicKey),
+          priceFeed: await mockPrice(YIELD_FEED),
+          chainlinkProgram: CHAINLINK_PROGRAM,
+        })
+        .rpc(),
+      "AccountAlreadyMigrated");
+  });
+};
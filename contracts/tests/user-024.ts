@@ -0,0 +1,40 @@
+import { expect } from 'chai'
+import {
+  CHAINLINK_PROGRAM,
+  FUNDING_PERIOD,
+  FUNDING_RATE_DECIMALS,
+  MAX_FUNDING_RATE,
+  YIELD_FEED,
+  YIELD_MARKET,
+  address,
+  admin,
+  bn,
+  exchange,
+  expectError,
+  mockPrice,
+  priceFeed,
+  program,
+  sleep,
+} from "./harness";
+
+export const validatesTheYieldFundingCurveAndAccruesBeforeChangingIt = () => {
+  it("[user-024] validates the yield funding curve and accrues before changing it", async () => {
+    const yieldMarket = await address("yield_market", YIELD_MARKET);
+    const update = (curve: any, kinkUtilization: number, kinkFundingRate: number) =>
+      mockPrice(YIELD_FEED).then(priceFeed =>
+        program.methods.updateYieldMarket(YIELD_MARKET, bn(FUNDING_PERIOD), bn(MAX_FUNDING_RATE), curve,
+          bn(kinkUtilization), bn(kinkFundingRate))
+          .accounts({ admin, yieldMarket, exchange, priceFeed, chainlinkProgram: CHAINLINK_PROGRAM })
+          .rpc());
+
+    await expectError(update({ kinked: {} }, .8 * FUNDING_RATE_DECIMALS, 2 * MAX_FUNDING_RATE), "InvalidFundingCurve");
+    await expectError(update({ kinked: {} }, FUNDING_RATE_DECIMALS, MAX_FUNDING_RATE / 2), "InvalidFundingCurve");
+
+    const before = await program.account.yieldMarket.fetch(yieldMarket);
+    await sleep(1);
+    await update({ kinked: {} }, .8 * FUNDING_RATE_DECIMALS, MAX_FUNDING_RATE / 2);
+    const after = await program.account.yieldMarket.fetch(yieldMarket);
+    expect(after.fundingCurve).to.deep.equal({ kinked: {} });
+    expect(after.lastClaimDate.gt(before.lastClaimDate)).to.be.true;
+  });
+};
@@ -0,0 +1,106 @@
+import { expect } from 'chai'
+import { PublicKey } from '@solana/web3.js';
+import { TOKEN_PROGRAM_ID } from "@solana/spl-token"
+import {
+  CHAINLINK_PROGRAM,
+  MARKET_1,
+  PYTH_PROGRAM,
+  address,
+  admin,
+  collateralAccounts,
+  deposit,
+  exchange,
+  expectError,
+  guardian,
+  market,
+  newAdmin,
+  openPositions,
+  payer,
+  priceFeed,
+  program,
+  settlePnl,
+  trade,
+  trader,
+  usd,
+  usdc,
+  userAccount,
+  userPosition,
+} from "./harness";
+
+export const transfersAdminInTwoSteps = () => {
+  it("[user-025] transfers admin in two steps", async () => {
+    await program.methods.proposeAdmin(newAdmin.publicKey).accounts({ admin, exchange }).rpc();
+    await expectError(
+      program.methods.acceptAdmin().accounts({ pendingAdmin: trader.publicKey, exchange }).signers([trader]).rpc(),
+      "ConstraintRaw");
+    await program.methods.acceptAdmin().accounts({ pendingAdmin: newAdmin.publicKey, exchange }).signers([newAdmin]).rpc();
+    expect((await program.account.exchange.fetch(exchange)).admin.equals(newAdmin.publicKey)).to.be.true;
+
+    await program.methods.proposeAdmin(admin).accounts({ admin: newAdmin.publicKey, exchange }).signers([newAdmin]).rpc();
+    await program.methods.acceptAdmin().accounts({ pendingAdmin: admin, exchange }).rpc();
+    const exchangeAccount = await program.account.exchange.fetch(exchange);
+    expect(exchangeAccount.admin.equals(admin)).to.be.true;
+    expect(exchangeAccount.pendingAdmin.equals(PublicKey.default)).to.be.true;
+  });
+};
+
+export const pausesTradingWithdrawalsAndLiquidationsButNotDeposits = () => {
+  it("[user-025] pauses trading, withdrawals and liquidations but not deposits", async () => {
+    await program.methods.updateRoles(admin, admin, admin, guardian.publicKey).accounts({ admin, exchange }).rpc();
+    await expectError(program.methods.setPaused(true).accounts({ pauseGuardian: admin, exchange }).rpc(), "ConstraintRaw");
+    await program.methods.setPaused(true).accounts({ pauseGuardian: guardian.publicKey, exchange }).signers([guardian]).rpc();
+
+    await expectError(trade(trader, MARKET_1, 1), "ExchangePaused");
+    await expectError(
+      program.methods.withdraw(usd(1))
+        .accounts(await collateralAccounts(trader.publicKey))
+        .remainingAccounts(await openPositions(trader.publicKey, undefined, usdc))
+        .signers([trader])
+        .rpc(),
+      "ExchangePaused");
+    await expectError(settlePnl(trader), "ExchangePaused");
+    await deposit(trader, 1);
+
+    await program.methods.setPaused(false).accounts({ pauseGuardian: guardian.publicKey, exchange }).signers([guardian]).rpc();
+    await trade(trader, MARKET_1, -1);
+  });
+};
+
+export const onlyMigratesAccountsStillInTheV0Layout = () => {
+  it("[user-025] only migrates accounts still in the v0 layout", async () => {
+    await expectError(
+      program.methods.migrateExchange(PYTH_PROGRAM, 0, 0, 0, 0, 0).accounts({ admin, exchange }).rpc(),
+      "AccountAlreadyMigrated");
+    await expectError(
+      program.methods.migrateUserAccount()
+        .accounts({
+          payer: admin,
+          owner: trader.publicKey,
+          exchange,
+          userAccount: await userAccount(trader.publicKey),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          mint: usdc,
+          escrowAccount: await address(exchange, usdc),
+          userCollateral: await address("user_collateral", trader.publicKey, usdc),
+          exchangeTreasuryPosition: await address("exchange_position", usdc),
+          priceFeed: usdc,
+          chainlinkProgram: CHAINLINK_PROGRAM,
+        })
+        .rpc(),
+      "AccountAlreadyMigrated");
+    await expectError(
+      program.methods.migrateUserPosition(MARKET_1)
+        .accounts({
+          payer: admin,
+          owner: trader.publicKey,
+          userAccount: await userAccount(trader.publicKey),
+          market: await market(MARKET_1),
+          userPosition: await userPosition(trader.publicKey, MARKET_1),
+          exchange,
+          priceFeed: await priceFeed(MARKET_1),
+          chainlinkProgram: CHAINLINK_PROGRAM,
+        })
+        .rpc(),
+      "AccountAlreadyMigrated");
+  });
+};